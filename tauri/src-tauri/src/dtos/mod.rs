@@ -1,4 +1,6 @@
 pub mod album;
 pub mod device;
+pub mod player;
 pub mod playlist;
+pub mod settings;
 pub mod track;
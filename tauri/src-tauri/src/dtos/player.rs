@@ -0,0 +1,49 @@
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use structural_convert::StructuralConvert;
+use tideperfect::services::player::{ChannelLevel, NowPlaying, OutputFormat, PlayerStats};
+
+use crate::dtos::track::{AudioQualityDTO, TrackDTO};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Type, StructuralConvert)]
+#[convert(from(NowPlaying))]
+#[serde(rename_all = "camelCase")]
+pub struct NowPlayingDTO {
+    pub track: TrackDTO,
+    /// ReplayGain applied to the track in dB, or null if it is played as-is
+    pub replay_gain: Option<f64>,
+    /// The sample rate the track is resampled to, or null if it is played at its own rate
+    pub resampled_to: Option<u32>,
+    /// The quality the track is being played in, which can be lower than the one asked for
+    pub quality: AudioQualityDTO,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Type, StructuralConvert)]
+#[convert(from(OutputFormat))]
+#[serde(rename_all = "camelCase")]
+pub struct OutputFormatDTO {
+    pub device: String,
+    pub sample_rate: u32,
+    pub sample_format: String,
+    pub channels: u16,
+    /// Whether the device is being used exclusively, so samples reach it unaltered
+    pub exclusive: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Type, StructuralConvert)]
+#[convert(from(PlayerStats))]
+#[serde(rename_all = "camelCase")]
+pub struct PlayerStatsDTO {
+    /// Samples that went over full scale and were clipped at the output
+    pub clipped_samples: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Type, StructuralConvert)]
+#[convert(from(ChannelLevel))]
+#[serde(rename_all = "camelCase")]
+pub struct ChannelLevelDTO {
+    /// Peak level since the last event, where 1 is full scale
+    pub peak: f64,
+    /// RMS level since the last event, where 1 is full scale
+    pub rms: f64,
+}
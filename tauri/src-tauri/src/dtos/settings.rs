@@ -0,0 +1,160 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use structural_convert::StructuralConvert;
+use tideperfect::services::{player::{ChannelSettings, CrossfadeCurve, CrossfadeSettings, CrossfeedSettings, DspSettings, DspStageConfig, DspStageSettings, EqBand, EqFilter, EqPreset, GainSettings, ImpulseResponse, LimiterSettings, ReplayGainMode}, track::QualitySettings};
+
+use crate::dtos::track::AudioQualityDTO;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Type, StructuralConvert)]
+#[convert(from(CrossfadeSettings))]
+#[convert(into(CrossfadeSettings))]
+#[serde(rename_all = "camelCase")]
+pub struct CrossfadeSettingsDTO {
+    /// Length of the crossfade in milliseconds. A length of 0 disables crossfading.
+    pub duration_ms: u32,
+    pub curve: CrossfadeCurveDTO,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Type, StructuralConvert)]
+#[convert(from(CrossfadeCurve))]
+#[convert(into(CrossfadeCurve))]
+pub enum CrossfadeCurveDTO {
+    Linear,
+    EqualPower,
+    SCurve,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Type, StructuralConvert)]
+#[convert(from(ReplayGainMode))]
+#[convert(into(ReplayGainMode))]
+pub enum ReplayGainModeDTO {
+    Off,
+    Track,
+    Album,
+    /// Album gain while an album is being played in order, otherwise track gain
+    Auto,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Type, StructuralConvert)]
+#[convert(from(QualitySettings))]
+#[convert(into(QualitySettings))]
+pub struct QualitySettingsDTO {
+    pub streaming: AudioQualityDTO,
+    /// Quality to download tracks in for offline listening.
+    pub offline: AudioQualityDTO,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Type, StructuralConvert)]
+#[convert(from(ChannelSettings))]
+#[convert(into(ChannelSettings))]
+#[serde(rename_all = "camelCase")]
+pub struct ChannelSettingsDTO {
+    /// Balance between the left and right channels, from -1 (left only) to 1 (right only).
+    pub balance: f64,
+    /// Mix every channel together and play the result through both sides.
+    pub mono: bool,
+    /// Swap the left and right channels.
+    pub swap_left_right: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Type, StructuralConvert)]
+#[convert(from(DspSettings))]
+#[convert(into(DspSettings))]
+pub struct DspSettingsDTO {
+    /// The stages, in the order samples pass through them.
+    pub stages: Vec<DspStageSettingsDTO>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Type, StructuralConvert)]
+#[convert(from(DspStageSettings))]
+#[convert(into(DspStageSettings))]
+pub struct DspStageSettingsDTO {
+    pub enabled: bool,
+    pub config: DspStageConfigDTO,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Type, StructuralConvert)]
+#[convert(from(DspStageConfig))]
+#[convert(into(DspStageConfig))]
+pub enum DspStageConfigDTO {
+    /// Convolution with the impulse response loaded for the output device
+    Convolution,
+    /// The parametric EQ, using the preset chosen for the output device
+    Eq,
+    Gain(GainSettingsDTO),
+    /// Headphone crossfeed. Only applies to stereo output
+    Crossfeed(CrossfeedSettingsDTO),
+    /// True-peak limiter. Only runs when another stage is active
+    Limiter(LimiterSettingsDTO),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Type, StructuralConvert)]
+#[convert(from(GainSettings))]
+#[convert(into(GainSettings))]
+#[serde(rename_all = "camelCase")]
+pub struct GainSettingsDTO {
+    pub gain_db: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Type, StructuralConvert)]
+#[convert(from(CrossfeedSettings))]
+#[convert(into(CrossfeedSettings))]
+#[serde(rename_all = "camelCase")]
+pub struct CrossfeedSettingsDTO {
+    /// Cutoff of the low-pass filter on the signal fed to the opposite ear, in Hz.
+    pub cutoff_hz: u32,
+    /// How much quieter the crossfed signal is than the direct one at low frequencies, in dB.
+    pub feed_db: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Type, StructuralConvert)]
+#[convert(from(LimiterSettings))]
+#[convert(into(LimiterSettings))]
+#[serde(rename_all = "camelCase")]
+pub struct LimiterSettingsDTO {
+    /// The highest true peak let through, in dBTP.
+    pub ceiling_db: f64,
+    /// How long the gain takes to recover after a peak, in milliseconds.
+    pub release_ms: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Type, StructuralConvert)]
+#[convert(from(EqPreset))]
+#[convert(into(EqPreset))]
+#[serde(rename_all = "camelCase")]
+pub struct EqPresetDTO {
+    /// Gain applied ahead of the filters in dB. When null, just enough headroom is left that boosts can't clip.
+    pub preamp_db: Option<f64>,
+    pub bands: Vec<EqBandDTO>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Type, StructuralConvert)]
+#[convert(from(EqBand))]
+#[convert(into(EqBand))]
+#[serde(rename_all = "camelCase")]
+pub struct EqBandDTO {
+    pub filter: EqFilterDTO,
+    /// Centre or corner frequency in Hz.
+    pub frequency: f64,
+    pub gain_db: f64,
+    pub q: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Type, StructuralConvert)]
+#[convert(from(EqFilter))]
+#[convert(into(EqFilter))]
+pub enum EqFilterDTO {
+    Peaking,
+    LowShelf,
+    HighShelf,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Type, StructuralConvert)]
+#[convert(from(ImpulseResponse))]
+#[serde(rename_all = "camelCase")]
+pub struct ImpulseResponseDTO {
+    pub path: PathBuf,
+    pub sample_rate: u32,
+}
@@ -3,7 +3,7 @@ use serde_with::{serde_as, DisplayFromStr};
 use specta::Type;
 use structural_convert::StructuralConvert;
 use strum_macros::{AsRefStr, EnumString};
-use tideperfect::services::track::{AudioQuality, MediaMetadata, Track};
+use tideperfect::services::track::{AudioQuality, MediaMetadata, Track, TrackWaveform, WaveformBucket};
 
 use crate::dtos::album::{AlbumSummaryDTO, ArtistSummaryDTO};
 
@@ -62,6 +62,7 @@ pub struct TrackDTO {
 
 #[derive(Debug, Serialize, Deserialize, Clone, Type, EnumString, AsRefStr, StructuralConvert)]
 #[convert(from(AudioQuality))]
+#[convert(into(AudioQuality))]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 #[strum(serialize_all = "SCREAMING_SNAKE_CASE")]
 pub enum AudioQualityDTO {
@@ -84,3 +85,33 @@ pub struct MediaMetadataDTO {
     pub tags: Vec<String>,
 }
 
+
+/// The peaks of a stretch of a track, where 1.0 is full scale.
+#[derive(Debug, Serialize, Deserialize, Clone, Type, StructuralConvert)]
+#[convert(from(WaveformBucket))]
+#[serde(rename_all = "camelCase")]
+pub struct WaveformBucketDTO {
+    pub min: f32,
+    pub max: f32,
+}
+
+/// An overview of a track's waveform.
+#[derive(Debug, Serialize, Deserialize, Clone, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct TrackWaveformDTO {
+    pub buckets: Vec<WaveformBucketDTO>,
+    /// Milliseconds of silence before the track starts
+    pub leading_silence: f64,
+    /// Milliseconds of silence after the track ends
+    pub trailing_silence: f64,
+}
+
+impl From<TrackWaveform> for TrackWaveformDTO {
+    fn from(waveform: TrackWaveform) -> Self {
+        Self {
+            buckets: waveform.buckets.into_iter().map(Into::into).collect(),
+            leading_silence: waveform.leading_silence.as_secs_f64() * 1000.0,
+            trailing_silence: waveform.trailing_silence.as_secs_f64() * 1000.0,
+        }
+    }
+}
@@ -29,14 +29,29 @@ pub fn run() -> Result<(), TidePerfectError> {
             album::favourite_albums, album::album_tracks, 
             album::user_playlists, album::playlist_tracks,
             queue::queue_track, queue::queue_album,
-            player::play, player::pause, player::skip, player::previous,
+            player::play, player::pause, player::skip, player::previous, player::seek,
             player::devices, player::set_device,
+            player::crossfade, player::set_crossfade,
+            player::volume, player::set_volume, player::bit_perfect, player::set_bit_perfect,
+            player::replay_gain, player::set_replay_gain,
+            player::resample_to, player::set_resample_to,
+            player::exclusive_devices, player::exclusive_device, player::set_exclusive_device,
+            player::channels, player::set_channels,
+            player::dsp, player::set_dsp,
+            player::eq_presets, player::save_eq_preset, player::import_eq_preset, player::delete_eq_preset,
+            player::device_eq_presets, player::set_device_eq_preset,
+            player::impulse_responses, player::add_impulse_response, player::remove_impulse_response, player::dsp_latency,
+            player::stats, player::reset_stats, player::set_analysis,
             track::lyrics,
+            track::waveform,
+            track::quality, track::set_quality,
         ])
         .events(collect_events![
             auth::LoggedIn,
             queue::QueueUpdated,
-            player::UpdatedCurrentTrack, player::UpdatedPauseState, player::UpdatedTrackProgress
+            player::UpdatedCurrentTrack, player::UpdatedNowPlaying, player::UpdatedPauseState, player::UpdatedTrackProgress,
+            player::UpdatedVolume, player::UpdatedOutputFormat, player::PlaybackError,
+            player::Spectrum, player::Levels
         ]);
 
     #[cfg(debug_assertions)]
@@ -52,11 +67,14 @@ pub fn run() -> Result<(), TidePerfectError> {
         .setup(move |app| {
             let app_handle = app.handle().clone();
 
-            let (event_emitter, event_reciever) = broadcast::channel(32);
+            // Analysis events come in at around 60 a second, so leave room for receivers to catch up
+            let (event_emitter, event_reciever) = broadcast::channel(256);
             let tideperfect = TidePerfect::init(&app_handle.path().data_dir().unwrap(), event_emitter.clone())?;
 
             let log_event_filter = vec![
                 EventFilter::PlayerEvent(PlayerEventDiscriminants::UpdatedTrackProgress),
+                EventFilter::PlayerEvent(PlayerEventDiscriminants::Spectrum),
+                EventFilter::PlayerEvent(PlayerEventDiscriminants::Levels),
                 EventFilter::QueueEvent(QueueEventDiscriminants::QueueUpdated),
             ];
 
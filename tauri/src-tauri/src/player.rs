@@ -1,3 +1,5 @@
+use std::{collections::{BTreeMap, HashMap}, path::PathBuf, time::Duration};
+
 use serde::{Deserialize, Serialize};
 use specta::Type;
 use tauri::{AppHandle, State};
@@ -6,17 +8,37 @@ use tideperfect::{services::player::PlayerEvent, Event as RecvEvent, TidePerfect
 use tokio::sync::{broadcast, Mutex};
 use tracing::{instrument, trace};
 
-use crate::{dtos::{device::CommandDeviceDTO, track::TrackDTO}, error::ErrorDTO};
+use crate::{dtos::{device::CommandDeviceDTO, player::{ChannelLevelDTO, NowPlayingDTO, OutputFormatDTO, PlayerStatsDTO}, settings::{ChannelSettingsDTO, CrossfadeSettingsDTO, DspSettingsDTO, EqPresetDTO, ImpulseResponseDTO, ReplayGainModeDTO}, track::TrackDTO}, error::ErrorDTO};
 
 #[derive(Serialize, Deserialize, Debug, Clone, Type, Event)]
 pub struct UpdatedCurrentTrack(Option<TrackDTO>);
 
+/// Sent alongside `UpdatedCurrentTrack`, with details of how the track is being played
+#[derive(Serialize, Deserialize, Debug, Clone, Type, Event)]
+pub struct UpdatedNowPlaying(Option<NowPlayingDTO>);
+
 #[derive(Serialize, Deserialize, Debug, Clone, Type, Event)]
 pub struct UpdatedPauseState(bool);
 
 #[derive(Serialize, Deserialize, Debug, Clone, Type, Event)]
 pub struct UpdatedTrackProgress(u32);
 
+#[derive(Serialize, Deserialize, Debug, Clone, Type, Event)]
+pub struct UpdatedVolume(f64);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Type, Event)]
+pub struct UpdatedOutputFormat(OutputFormatDTO);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Type, Event)]
+pub struct PlaybackError(String);
+
+/// Magnitudes in dB of logarithmically spaced bands from 20 Hz to 20 kHz
+#[derive(Serialize, Deserialize, Debug, Clone, Type, Event)]
+pub struct Spectrum(Vec<f64>);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Type, Event)]
+pub struct Levels(Vec<ChannelLevelDTO>);
+
 // TODO: Error handling for event handlers - while the emit should work, I don't like the unwrap.
 // TODO: Also, should we change how event handlers work? Perhaps each services handler should only
 // recieve the corresponding event type, allowing us to remove the default case and give compiler
@@ -26,8 +48,10 @@ pub fn handle_events(mut event_reciever: broadcast::Receiver<RecvEvent>, handle:
     tokio::spawn(async move {
         while let Ok(event) = event_reciever.recv().await {
             match event {
-                RecvEvent::PlayerEvent(PlayerEvent::UpdatedCurrentTrack(track)) => {
-                    UpdatedCurrentTrack(track.map(|t| TrackDTO::from(*t))).emit(&handle).unwrap();
+                RecvEvent::PlayerEvent(PlayerEvent::UpdatedCurrentTrack(now_playing)) => {
+                    let now_playing = now_playing.map(|n| NowPlayingDTO::from(*n));
+                    UpdatedCurrentTrack(now_playing.as_ref().map(|n| n.track.clone())).emit(&handle).unwrap();
+                    UpdatedNowPlaying(now_playing).emit(&handle).unwrap();
                 }
                 RecvEvent::PlayerEvent(PlayerEvent::UpdatedPauseState(paused)) => {
                     UpdatedPauseState(paused).emit(&handle).unwrap();
@@ -35,6 +59,21 @@ pub fn handle_events(mut event_reciever: broadcast::Receiver<RecvEvent>, handle:
                 RecvEvent::PlayerEvent(PlayerEvent::UpdatedTrackProgress(progress)) => {
                     UpdatedTrackProgress(progress).emit(&handle).unwrap();
                 }
+                RecvEvent::PlayerEvent(PlayerEvent::UpdatedVolume(volume)) => {
+                    UpdatedVolume(volume).emit(&handle).unwrap();
+                }
+                RecvEvent::PlayerEvent(PlayerEvent::UpdatedOutputFormat(format)) => {
+                    UpdatedOutputFormat(format.into()).emit(&handle).unwrap();
+                }
+                RecvEvent::PlayerEvent(PlayerEvent::PlaybackError(error)) => {
+                    PlaybackError(error).emit(&handle).unwrap();
+                }
+                RecvEvent::PlayerEvent(PlayerEvent::Spectrum(spectrum)) => {
+                    Spectrum(spectrum).emit(&handle).unwrap();
+                }
+                RecvEvent::PlayerEvent(PlayerEvent::Levels(levels)) => {
+                    Levels(levels.into_iter().map(|x| x.into()).collect()).emit(&handle).unwrap();
+                }
                 _ => continue,
             }
         }
@@ -89,6 +128,18 @@ pub async fn previous(state: State<'_, Mutex<TidePerfect>>) -> Result<(), ErrorD
     Ok(())
 }
 
+#[tauri::command]
+#[specta::specta]
+#[instrument(skip(state))]
+pub async fn seek(state: State<'_, Mutex<TidePerfect>>, position: u32) -> Result<(), ErrorDTO> {
+    trace!("Got command: seek({position})");
+
+    let state = state.lock().await;
+    state.player_service.seek(Duration::from_secs(position as u64)).await?;
+
+    Ok(())
+}
+
 #[tauri::command]
 #[specta::specta]
 #[instrument(skip(state))]
@@ -114,3 +165,367 @@ pub async fn set_device(state: State<'_, Mutex<TidePerfect>>, device: String) ->
     Ok(())
 }
 
+
+#[tauri::command]
+#[specta::specta]
+#[instrument(skip(state))]
+pub async fn crossfade(state: State<'_, Mutex<TidePerfect>>) -> Result<CrossfadeSettingsDTO, ErrorDTO> {
+    trace!("Got command: crossfade");
+
+    let state = state.lock().await;
+    let crossfade = state.player_service.crossfade().await?;
+
+    Ok(crossfade.into())
+}
+
+#[tauri::command]
+#[specta::specta]
+#[instrument(skip(state))]
+pub async fn set_crossfade(state: State<'_, Mutex<TidePerfect>>, crossfade: CrossfadeSettingsDTO) -> Result<(), ErrorDTO> {
+    trace!("Got command: set_crossfade({crossfade:?})");
+
+    let state = state.lock().await;
+    state.player_service.set_crossfade(crossfade.into()).await?;
+
+    Ok(())
+}
+
+#[tauri::command]
+#[specta::specta]
+#[instrument(skip(state))]
+pub async fn volume(state: State<'_, Mutex<TidePerfect>>) -> Result<f64, ErrorDTO> {
+    trace!("Got command: volume");
+
+    let state = state.lock().await;
+    let volume = state.player_service.volume().await?;
+
+    Ok(volume)
+}
+
+#[tauri::command]
+#[specta::specta]
+#[instrument(skip(state))]
+pub async fn set_volume(state: State<'_, Mutex<TidePerfect>>, volume: f64) -> Result<(), ErrorDTO> {
+    trace!("Got command: set_volume({volume})");
+
+    let state = state.lock().await;
+    state.player_service.set_volume(volume).await?;
+
+    Ok(())
+}
+
+#[tauri::command]
+#[specta::specta]
+#[instrument(skip(state))]
+pub async fn bit_perfect(state: State<'_, Mutex<TidePerfect>>) -> Result<bool, ErrorDTO> {
+    trace!("Got command: bit_perfect");
+
+    let state = state.lock().await;
+    let bit_perfect = state.player_service.bit_perfect().await?;
+
+    Ok(bit_perfect)
+}
+
+#[tauri::command]
+#[specta::specta]
+#[instrument(skip(state))]
+pub async fn set_bit_perfect(state: State<'_, Mutex<TidePerfect>>, bit_perfect: bool) -> Result<(), ErrorDTO> {
+    trace!("Got command: set_bit_perfect({bit_perfect})");
+
+    let state = state.lock().await;
+    state.player_service.set_bit_perfect(bit_perfect).await?;
+
+    Ok(())
+}
+
+#[tauri::command]
+#[specta::specta]
+#[instrument(skip(state))]
+pub async fn replay_gain(state: State<'_, Mutex<TidePerfect>>) -> Result<ReplayGainModeDTO, ErrorDTO> {
+    trace!("Got command: replay_gain");
+
+    let state = state.lock().await;
+    let mode = state.player_service.replay_gain().await?;
+
+    Ok(mode.into())
+}
+
+#[tauri::command]
+#[specta::specta]
+#[instrument(skip(state))]
+pub async fn set_replay_gain(state: State<'_, Mutex<TidePerfect>>, mode: ReplayGainModeDTO) -> Result<(), ErrorDTO> {
+    trace!("Got command: set_replay_gain({mode:?})");
+
+    let state = state.lock().await;
+    state.player_service.set_replay_gain(mode.into()).await?;
+
+    Ok(())
+}
+
+#[tauri::command]
+#[specta::specta]
+#[instrument(skip(state))]
+pub async fn resample_to(state: State<'_, Mutex<TidePerfect>>) -> Result<Option<u32>, ErrorDTO> {
+    trace!("Got command: resample_to");
+
+    let state = state.lock().await;
+    let resample_to = state.player_service.resample_to().await?;
+
+    Ok(resample_to)
+}
+
+#[tauri::command]
+#[specta::specta]
+#[instrument(skip(state))]
+pub async fn set_resample_to(state: State<'_, Mutex<TidePerfect>>, resample_to: Option<u32>) -> Result<(), ErrorDTO> {
+    trace!("Got command: set_resample_to({resample_to:?})");
+
+    let state = state.lock().await;
+    state.player_service.set_resample_to(resample_to).await?;
+
+    Ok(())
+}
+
+#[tauri::command]
+#[specta::specta]
+#[instrument(skip(state))]
+pub async fn exclusive_devices(state: State<'_, Mutex<TidePerfect>>) -> Result<Vec<CommandDeviceDTO>, ErrorDTO> {
+    trace!("Got command: exclusive_devices");
+
+    let state = state.lock().await;
+    let devices = state.player_service.exclusive_devices()?;
+    let devices = devices.into_iter().map(|x| x.into()).collect();
+
+    Ok(devices)
+}
+
+#[tauri::command]
+#[specta::specta]
+#[instrument(skip(state))]
+pub async fn exclusive_device(state: State<'_, Mutex<TidePerfect>>) -> Result<Option<String>, ErrorDTO> {
+    trace!("Got command: exclusive_device");
+
+    let state = state.lock().await;
+    let device = state.player_service.exclusive_device().await?;
+
+    Ok(device)
+}
+
+#[tauri::command]
+#[specta::specta]
+#[instrument(skip(state))]
+pub async fn set_exclusive_device(state: State<'_, Mutex<TidePerfect>>, device: Option<String>) -> Result<(), ErrorDTO> {
+    trace!("Got command: set_exclusive_device({device:?})");
+
+    let state = state.lock().await;
+    state.player_service.set_exclusive_device(device).await?;
+
+    Ok(())
+}
+
+#[tauri::command]
+#[specta::specta]
+#[instrument(skip(state))]
+pub async fn channels(state: State<'_, Mutex<TidePerfect>>) -> Result<ChannelSettingsDTO, ErrorDTO> {
+    trace!("Got command: channels");
+
+    let state = state.lock().await;
+    let channels = state.player_service.channels().await?;
+
+    Ok(channels.into())
+}
+
+#[tauri::command]
+#[specta::specta]
+#[instrument(skip(state))]
+pub async fn set_channels(state: State<'_, Mutex<TidePerfect>>, channels: ChannelSettingsDTO) -> Result<(), ErrorDTO> {
+    trace!("Got command: set_channels({channels:?})");
+
+    let state = state.lock().await;
+    state.player_service.set_channels(channels.into()).await?;
+
+    Ok(())
+}
+
+#[tauri::command]
+#[specta::specta]
+#[instrument(skip(state))]
+pub async fn dsp(state: State<'_, Mutex<TidePerfect>>) -> Result<DspSettingsDTO, ErrorDTO> {
+    trace!("Got command: dsp");
+
+    let state = state.lock().await;
+    let dsp = state.player_service.dsp().await?;
+
+    Ok(dsp.into())
+}
+
+#[tauri::command]
+#[specta::specta]
+#[instrument(skip(state))]
+pub async fn set_dsp(state: State<'_, Mutex<TidePerfect>>, dsp: DspSettingsDTO) -> Result<(), ErrorDTO> {
+    trace!("Got command: set_dsp({dsp:?})");
+
+    let state = state.lock().await;
+    state.player_service.set_dsp(dsp.into()).await?;
+
+    Ok(())
+}
+
+#[tauri::command]
+#[specta::specta]
+#[instrument(skip(state))]
+pub async fn eq_presets(state: State<'_, Mutex<TidePerfect>>) -> Result<BTreeMap<String, EqPresetDTO>, ErrorDTO> {
+    trace!("Got command: eq_presets");
+
+    let state = state.lock().await;
+    let presets = state.player_service.eq_presets().await?;
+
+    Ok(presets.into_iter().map(|(name, preset)| (name, preset.into())).collect())
+}
+
+#[tauri::command]
+#[specta::specta]
+#[instrument(skip(state))]
+pub async fn save_eq_preset(state: State<'_, Mutex<TidePerfect>>, name: String, preset: EqPresetDTO) -> Result<(), ErrorDTO> {
+    trace!("Got command: save_eq_preset({name}, {preset:?})");
+
+    let state = state.lock().await;
+    state.player_service.save_eq_preset(name, preset.into()).await?;
+
+    Ok(())
+}
+
+#[tauri::command]
+#[specta::specta]
+#[instrument(skip(state, text))]
+pub async fn import_eq_preset(state: State<'_, Mutex<TidePerfect>>, name: String, text: String) -> Result<(), ErrorDTO> {
+    trace!("Got command: import_eq_preset({name})");
+
+    let state = state.lock().await;
+    state.player_service.import_eq_preset(name, &text).await?;
+
+    Ok(())
+}
+
+#[tauri::command]
+#[specta::specta]
+#[instrument(skip(state))]
+pub async fn delete_eq_preset(state: State<'_, Mutex<TidePerfect>>, name: String) -> Result<(), ErrorDTO> {
+    trace!("Got command: delete_eq_preset({name})");
+
+    let state = state.lock().await;
+    state.player_service.delete_eq_preset(name).await?;
+
+    Ok(())
+}
+
+#[tauri::command]
+#[specta::specta]
+#[instrument(skip(state))]
+pub async fn device_eq_presets(state: State<'_, Mutex<TidePerfect>>) -> Result<HashMap<String, String>, ErrorDTO> {
+    trace!("Got command: device_eq_presets");
+
+    let state = state.lock().await;
+    let presets = state.player_service.device_eq_presets().await?;
+
+    Ok(presets)
+}
+
+#[tauri::command]
+#[specta::specta]
+#[instrument(skip(state))]
+pub async fn set_device_eq_preset(state: State<'_, Mutex<TidePerfect>>, device: String, preset: Option<String>) -> Result<(), ErrorDTO> {
+    trace!("Got command: set_device_eq_preset({device}, {preset:?})");
+
+    let state = state.lock().await;
+    state.player_service.set_device_eq_preset(device, preset).await?;
+
+    Ok(())
+}
+
+#[tauri::command]
+#[specta::specta]
+#[instrument(skip(state))]
+pub async fn impulse_responses(state: State<'_, Mutex<TidePerfect>>) -> Result<HashMap<String, Vec<ImpulseResponseDTO>>, ErrorDTO> {
+    trace!("Got command: impulse_responses");
+
+    let state = state.lock().await;
+    let impulses = state.player_service.impulse_responses().await?;
+
+    Ok(impulses.into_iter()
+        .map(|(device, impulses)| (device, impulses.into_iter().map(Into::into).collect()))
+        .collect())
+}
+
+#[tauri::command]
+#[specta::specta]
+#[instrument(skip(state))]
+pub async fn add_impulse_response(state: State<'_, Mutex<TidePerfect>>, device: String, path: PathBuf) -> Result<(), ErrorDTO> {
+    trace!("Got command: add_impulse_response({device}, {})", path.display());
+
+    let state = state.lock().await;
+    state.player_service.add_impulse_response(device, &path).await?;
+
+    Ok(())
+}
+
+#[tauri::command]
+#[specta::specta]
+#[instrument(skip(state))]
+pub async fn remove_impulse_response(state: State<'_, Mutex<TidePerfect>>, device: String, sample_rate: u32) -> Result<(), ErrorDTO> {
+    trace!("Got command: remove_impulse_response({device}, {sample_rate})");
+
+    let state = state.lock().await;
+    state.player_service.remove_impulse_response(device, sample_rate).await?;
+
+    Ok(())
+}
+
+/// Latency of the DSP chain in milliseconds.
+#[tauri::command]
+#[specta::specta]
+#[instrument(skip(state))]
+pub async fn dsp_latency(state: State<'_, Mutex<TidePerfect>>) -> Result<f64, ErrorDTO> {
+    trace!("Got command: dsp_latency");
+
+    let state = state.lock().await;
+    let latency = state.player_service.dsp_latency().await?;
+
+    Ok(latency.as_secs_f64() * 1000.0)
+}
+
+#[tauri::command]
+#[specta::specta]
+#[instrument(skip(state))]
+pub async fn stats(state: State<'_, Mutex<TidePerfect>>) -> Result<PlayerStatsDTO, ErrorDTO> {
+    trace!("Got command: stats");
+
+    let state = state.lock().await;
+
+    Ok(state.player_service.stats().into())
+}
+
+/// Start or stop the `Spectrum` and `Levels` events.
+#[tauri::command]
+#[specta::specta]
+#[instrument(skip(state))]
+pub async fn set_analysis(state: State<'_, Mutex<TidePerfect>>, enabled: bool) -> Result<(), ErrorDTO> {
+    trace!("Got command: set_analysis({enabled})");
+
+    let state = state.lock().await;
+    state.player_service.set_analysis(enabled);
+
+    Ok(())
+}
+
+#[tauri::command]
+#[specta::specta]
+#[instrument(skip(state))]
+pub async fn reset_stats(state: State<'_, Mutex<TidePerfect>>) -> Result<(), ErrorDTO> {
+    trace!("Got command: reset_stats");
+
+    let state = state.lock().await;
+    state.player_service.reset_stats();
+
+    Ok(())
+}
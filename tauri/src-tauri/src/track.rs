@@ -1,9 +1,9 @@
 use tauri::State;
 use tideperfect::TidePerfect;
 use tokio::sync::Mutex;
-use tracing::{error, info, instrument};
+use tracing::{error, info, instrument, trace};
 
-use crate::error::ErrorDTO;
+use crate::{dtos::{settings::QualitySettingsDTO, track::TrackWaveformDTO}, error::ErrorDTO};
 
 #[tauri::command]
 #[specta::specta]
@@ -16,3 +16,38 @@ pub async fn lyrics(state: State<'_, Mutex<TidePerfect>>, id: &str) -> Result<Op
     info!("resp: {resp:?}");
     resp
 }
+
+#[tauri::command]
+#[specta::specta]
+#[instrument(skip(state))]
+pub async fn waveform(state: State<'_, Mutex<TidePerfect>>, id: &str, buckets: u32) -> Result<TrackWaveformDTO, ErrorDTO> {
+    trace!("Got command: waveform");
+    let id = id.parse()?;
+    // Released before decoding, which can take a while
+    let waveform = state.lock().await.track_service.waveform(id, buckets as usize);
+    Ok(waveform.await?.into())
+}
+
+#[tauri::command]
+#[specta::specta]
+#[instrument(skip(state))]
+pub async fn quality(state: State<'_, Mutex<TidePerfect>>) -> Result<QualitySettingsDTO, ErrorDTO> {
+    trace!("Got command: quality");
+
+    let state = state.lock().await;
+    let quality = state.track_service.quality().await;
+
+    Ok(quality.into())
+}
+
+#[tauri::command]
+#[specta::specta]
+#[instrument(skip(state))]
+pub async fn set_quality(state: State<'_, Mutex<TidePerfect>>, quality: QualitySettingsDTO) -> Result<(), ErrorDTO> {
+    trace!("Got command: set_quality({quality:?})");
+
+    let state = state.lock().await;
+    state.track_service.set_quality(quality.into()).await?;
+
+    Ok(())
+}
@@ -101,6 +101,14 @@ async previous() : Promise<Result<null, ErrorDTO>> {
     else return { status: "error", error: e  as any };
 }
 },
+async seek(position: number) : Promise<Result<null, ErrorDTO>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("seek", { position }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
 async devices() : Promise<Result<CommandDeviceDTO[], ErrorDTO>> {
     try {
     return { status: "ok", data: await TAURI_INVOKE("devices") };
@@ -117,6 +125,249 @@ async setDevice(device: string) : Promise<Result<null, ErrorDTO>> {
     else return { status: "error", error: e  as any };
 }
 },
+async crossfade() : Promise<Result<CrossfadeSettingsDTO, ErrorDTO>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("crossfade") };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async setCrossfade(crossfade: CrossfadeSettingsDTO) : Promise<Result<null, ErrorDTO>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("set_crossfade", { crossfade }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async volume() : Promise<Result<number, ErrorDTO>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("volume") };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async setVolume(volume: number) : Promise<Result<null, ErrorDTO>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("set_volume", { volume }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async bitPerfect() : Promise<Result<boolean, ErrorDTO>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("bit_perfect") };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async setBitPerfect(bitPerfect: boolean) : Promise<Result<null, ErrorDTO>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("set_bit_perfect", { bitPerfect }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async replayGain() : Promise<Result<ReplayGainModeDTO, ErrorDTO>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("replay_gain") };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async setReplayGain(mode: ReplayGainModeDTO) : Promise<Result<null, ErrorDTO>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("set_replay_gain", { mode }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async resampleTo() : Promise<Result<number | null, ErrorDTO>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("resample_to") };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async setResampleTo(resampleTo: number | null) : Promise<Result<null, ErrorDTO>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("set_resample_to", { resampleTo }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async exclusiveDevices() : Promise<Result<CommandDeviceDTO[], ErrorDTO>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("exclusive_devices") };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async exclusiveDevice() : Promise<Result<string | null, ErrorDTO>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("exclusive_device") };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async setExclusiveDevice(device: string | null) : Promise<Result<null, ErrorDTO>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("set_exclusive_device", { device }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async channels() : Promise<Result<ChannelSettingsDTO, ErrorDTO>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("channels") };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async setChannels(channels: ChannelSettingsDTO) : Promise<Result<null, ErrorDTO>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("set_channels", { channels }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async dsp() : Promise<Result<DspSettingsDTO, ErrorDTO>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("dsp") };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async setDsp(dsp: DspSettingsDTO) : Promise<Result<null, ErrorDTO>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("set_dsp", { dsp }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async eqPresets() : Promise<Result<{ [key in string]: EqPresetDTO }, ErrorDTO>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("eq_presets") };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async saveEqPreset(name: string, preset: EqPresetDTO) : Promise<Result<null, ErrorDTO>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("save_eq_preset", { name, preset }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async importEqPreset(name: string, text: string) : Promise<Result<null, ErrorDTO>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("import_eq_preset", { name, text }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async deleteEqPreset(name: string) : Promise<Result<null, ErrorDTO>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("delete_eq_preset", { name }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async deviceEqPresets() : Promise<Result<{ [key in string]: string }, ErrorDTO>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("device_eq_presets") };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async setDeviceEqPreset(device: string, preset: string | null) : Promise<Result<null, ErrorDTO>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("set_device_eq_preset", { device, preset }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async impulseResponses() : Promise<Result<{ [key in string]: ImpulseResponseDTO[] }, ErrorDTO>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("impulse_responses") };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async addImpulseResponse(device: string, path: string) : Promise<Result<null, ErrorDTO>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("add_impulse_response", { device, path }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async removeImpulseResponse(device: string, sampleRate: number) : Promise<Result<null, ErrorDTO>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("remove_impulse_response", { device, sampleRate }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+/**
+ * Latency of the DSP chain in milliseconds.
+ */
+async dspLatency() : Promise<Result<number, ErrorDTO>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("dsp_latency") };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async stats() : Promise<Result<PlayerStatsDTO, ErrorDTO>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("stats") };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async resetStats() : Promise<Result<null, ErrorDTO>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("reset_stats") };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async setAnalysis(enabled: boolean) : Promise<Result<null, ErrorDTO>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("set_analysis", { enabled }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
 async lyrics(id: string) : Promise<Result<string | null, ErrorDTO>> {
     try {
     return { status: "ok", data: await TAURI_INVOKE("lyrics", { id }) };
@@ -124,6 +375,30 @@ async lyrics(id: string) : Promise<Result<string | null, ErrorDTO>> {
     if(e instanceof Error) throw e;
     else return { status: "error", error: e  as any };
 }
+},
+async waveform(id: string, buckets: number) : Promise<Result<TrackWaveformDTO, ErrorDTO>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("waveform", { id, buckets }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async quality() : Promise<Result<QualitySettingsDTO, ErrorDTO>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("quality") };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async setQuality(quality: QualitySettingsDTO) : Promise<Result<null, ErrorDTO>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("set_quality", { quality }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
 }
 }
 
@@ -131,17 +406,29 @@ async lyrics(id: string) : Promise<Result<string | null, ErrorDTO>> {
 
 
 export const events = __makeEvents__<{
+levels: Levels,
 loggedIn: LoggedIn,
+playbackError: PlaybackError,
 queueUpdated: QueueUpdated,
+spectrum: Spectrum,
 updatedCurrentTrack: UpdatedCurrentTrack,
+updatedNowPlaying: UpdatedNowPlaying,
+updatedOutputFormat: UpdatedOutputFormat,
 updatedPauseState: UpdatedPauseState,
-updatedTrackProgress: UpdatedTrackProgress
+updatedTrackProgress: UpdatedTrackProgress,
+updatedVolume: UpdatedVolume
 }>({
+levels: "levels",
 loggedIn: "logged-in",
+playbackError: "playback-error",
 queueUpdated: "queue-updated",
+spectrum: "spectrum",
 updatedCurrentTrack: "updated-current-track",
+updatedNowPlaying: "updated-now-playing",
+updatedOutputFormat: "updated-output-format",
 updatedPauseState: "updated-pause-state",
-updatedTrackProgress: "updated-track-progress"
+updatedTrackProgress: "updated-track-progress",
+updatedVolume: "updated-volume"
 })
 
 /** user-defined constants **/
@@ -371,15 +658,122 @@ export type AudioQualityDTO =
  * Hi-Res Lossless quality (FLAC, up to 192 kHz / 24-bit)
  */
 "HI_RES_LOSSLESS"
+export type ChannelLevelDTO = { 
+/**
+ * Peak level since the last event, where 1 is full scale
+ */
+peak: number; 
+/**
+ * RMS level since the last event, where 1 is full scale
+ */
+rms: number }
+export type ChannelSettingsDTO = { 
+/**
+ * Balance between the left and right channels, from -1 (left only) to 1 (right only).
+ */
+balance: number; 
+/**
+ * Mix every channel together and play the result through both sides.
+ */
+mono: boolean; 
+/**
+ * Swap the left and right channels.
+ */
+swapLeftRight: boolean }
 export type CommandDeviceDTO = { name: string; id: string }
+export type CrossfadeCurveDTO = "Linear" | "EqualPower" | "SCurve"
+export type CrossfadeSettingsDTO = { 
+/**
+ * Length of the crossfade in milliseconds. A length of 0 disables crossfading.
+ */
+durationMs: number; curve: CrossfadeCurveDTO }
+export type CrossfeedSettingsDTO = { 
+/**
+ * Cutoff of the low-pass filter on the signal fed to the opposite ear, in Hz.
+ */
+cutoffHz: number; 
+/**
+ * How much quieter the crossfed signal is than the direct one at low frequencies, in dB.
+ */
+feedDb: number }
+export type DspSettingsDTO = { 
+/**
+ * The stages, in the order samples pass through them.
+ */
+stages: DspStageSettingsDTO[] }
+export type DspStageConfigDTO = 
+/**
+ * Convolution with the impulse response loaded for the output device
+ */
+"Convolution" | 
+/**
+ * The parametric EQ, using the preset chosen for the output device
+ */
+"Eq" | { Gain: GainSettingsDTO } | 
+/**
+ * Headphone crossfeed. Only applies to stereo output
+ */
+{ Crossfeed: CrossfeedSettingsDTO } | 
+/**
+ * True-peak limiter. Only runs when another stage is active
+ */
+{ Limiter: LimiterSettingsDTO }
+export type DspStageSettingsDTO = { enabled: boolean; config: DspStageConfigDTO }
+export type EqBandDTO = { filter: EqFilterDTO; 
+/**
+ * Centre or corner frequency in Hz.
+ */
+frequency: number; gainDb: number; q: number }
+export type EqFilterDTO = "Peaking" | "LowShelf" | "HighShelf"
+export type EqPresetDTO = { 
+/**
+ * Gain applied ahead of the filters in dB. When null, just enough headroom is left that boosts can't clip.
+ */
+preampDb: number | null; bands: EqBandDTO[] }
 export type ErrorDTO = { error: string }
 export type FavouriteAlbumDTO = { created: string; item: AlbumDTO }
+export type GainSettingsDTO = { gainDb: number }
+export type ImpulseResponseDTO = { path: string; sampleRate: number }
+export type Levels = ChannelLevelDTO[]
+export type LimiterSettingsDTO = { 
+/**
+ * The highest true peak let through, in dBTP.
+ */
+ceilingDb: number; 
+/**
+ * How long the gain takes to recover after a peak, in milliseconds.
+ */
+releaseMs: number }
 export type LoggedIn = null
 export type MediaMetadataDTO = { 
 /**
  * Tags associated with the media
  */
 tags?: string[] }
+export type NowPlayingDTO = { track: TrackDTO; 
+/**
+ * ReplayGain applied to the track in dB, or null if it is played as-is
+ */
+replayGain: number | null; 
+/**
+ * The sample rate the track is resampled to, or null if it is played at its own rate
+ */
+resampledTo: number | null; 
+/**
+ * The quality the track is being played in, which can be lower than the one asked for
+ */
+quality: AudioQualityDTO }
+export type OutputFormatDTO = { device: string; sampleRate: number; sampleFormat: string; channels: number; 
+/**
+ * Whether the device is being used exclusively, so samples reach it unaltered
+ */
+exclusive: boolean }
+export type PlaybackError = string
+export type PlayerStatsDTO = { 
+/**
+ * Samples that went over full scale and were clipped at the output
+ */
+clippedSamples: string }
 /**
  * Information about the creator of a playlist.
  * 
@@ -481,6 +875,11 @@ promotedArtists: ArtistSummaryDTO[] | null;
  * This is needed for adding or removing tracks from the playlist
  */
 etag: string | null }
+export type QualitySettingsDTO = { streaming: AudioQualityDTO; 
+/**
+ * Quality to download tracks in for offline listening.
+ */
+offline: AudioQualityDTO }
 export type QueueUpdated = TrackDTO[]
 /**
  * Represents a track from the Tidal catalog.
@@ -488,6 +887,15 @@ export type QueueUpdated = TrackDTO[]
  * This structure contains all available information about a track,
  * including metadata, audio quality, and associated album/artist data.
  */
+export type ReplayGainModeDTO = "Off" | "Track" | "Album" | 
+/**
+ * Album gain while an album is being played in order, otherwise track gain
+ */
+"Auto"
+/**
+ * Magnitudes in dB of logarithmically spaced bands from 20 Hz to 20 kHz
+ */
+export type Spectrum = number[]
 export type TrackDTO = { 
 /**
  * Unique track identifier
@@ -547,9 +955,31 @@ url: string | null;
  * Beats per minute (BPM) of the track
  */
 bpm: number | null; upload: boolean | null }
+/**
+ * An overview of a track's waveform.
+ */
+export type TrackWaveformDTO = { buckets: WaveformBucketDTO[]; 
+/**
+ * Milliseconds of silence before the track starts
+ */
+leadingSilence: number; 
+/**
+ * Milliseconds of silence after the track ends
+ */
+trailingSilence: number }
 export type UpdatedCurrentTrack = TrackDTO | null
+/**
+ * Sent alongside `UpdatedCurrentTrack`, with details of how the track is being played
+ */
+export type UpdatedNowPlaying = NowPlayingDTO | null
+export type UpdatedOutputFormat = OutputFormatDTO
 export type UpdatedPauseState = boolean
 export type UpdatedTrackProgress = number
+export type UpdatedVolume = number
+/**
+ * The peaks of a stretch of a track, where 1.0 is full scale.
+ */
+export type WaveformBucketDTO = { min: number; max: number }
 
 /** tauri-specta globals **/
 
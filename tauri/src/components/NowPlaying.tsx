@@ -0,0 +1,27 @@
+import { events, NowPlayingDTO } from "@/bindings";
+import { useEffect, useState } from "react";
+import QualityBadge from "@/components/QualityBadge";
+
+function NowPlaying() {
+  const [nowPlaying, setNowPlaying] = useState<NowPlayingDTO | null>(null);
+
+  useEffect(() => {
+    const unlisten = events.updatedNowPlaying.listen((event) => setNowPlaying(event.payload));
+
+    return () => {
+      unlisten.then((fn) => fn());
+    };
+  }, []);
+
+  if (!nowPlaying) return;
+
+  // The quality is what TIDAL is actually serving, which can be lower than the one asked for
+  return (
+    <div className="flex items-center gap-2">
+      <span>{nowPlaying.track.title}</span>
+      <QualityBadge quality={nowPlaying.quality} />
+    </div>
+  )
+}
+
+export default NowPlaying;
@@ -0,0 +1,19 @@
+import { AudioQualityDTO } from "@/bindings";
+import { Badge } from "@/components/ui/badge";
+
+interface QualityBadgeProps {
+  quality: AudioQualityDTO,
+}
+
+export default function QualityBadge({ quality }: QualityBadgeProps) {
+  switch (quality) {
+    case "LOW":
+      return <Badge variant="secondary">LOW</Badge>
+    case "HIGH":
+      return <Badge>HIGH</Badge>
+    case "LOSSLESS":
+      return <Badge className="bg-teal-500">LOSSLESS</Badge>
+    case "HI_RES_LOSSLESS":
+      return <Badge className="bg-amber-300">MAX</Badge>
+  }
+}
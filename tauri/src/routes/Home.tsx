@@ -1,7 +1,10 @@
+import NowPlaying from "@/components/NowPlaying";
+
 function Home() {
   return (
     <div className="flex flex-col justify-center items-center">
       <h1>Home</h1>
+      <NowPlaying />
     </div>
   )
 }
@@ -0,0 +1,228 @@
+use std::{f64::consts::PI, sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Arc, Mutex}, time::Duration};
+
+use realfft::{num_complex::Complex, RealFftPlanner, RealToComplex};
+use ringbuf::{traits::{Consumer, Observer, Producer, Split}, CachingCons, CachingProd, HeapRb};
+use tokio::{sync::{broadcast, Notify}, time::MissedTickBehavior};
+use tracing::{info, instrument};
+
+use crate::{audio::player::PlayerEvent, Event};
+
+/// How often spectrum and level events are emitted.
+const ANALYSIS_INTERVAL: Duration = Duration::from_millis(33);
+
+/// Samples the tap can hold while waiting for the analyser, enough for a few intervals at any rate.
+const TAP_CAPACITY: usize = 1 << 19;
+
+/// Frames in each FFT.
+const FFT_FRAMES: usize = 2048;
+
+/// Number of bands in each spectrum, spaced logarithmically.
+pub const SPECTRUM_BANDS: usize = 32;
+
+const SPECTRUM_LOWEST_HZ: f64 = 20.0;
+const SPECTRUM_HIGHEST_HZ: f64 = 20_000.0;
+
+/// The lowest magnitude reported in a spectrum in dB, so silence still has a level.
+pub const SPECTRUM_FLOOR_DB: f64 = -120.0;
+
+/// Peak and RMS levels of a channel, where 1.0 is full scale.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChannelLevel {
+    pub peak: f64,
+    pub rms: f64,
+}
+
+/// Passes samples from the output callback to the analyser. Nothing is copied until analysis has
+/// been enabled, so the tap costs the callback a single atomic load while it is off.
+pub struct AnalysisTap {
+    enabled: AtomicBool,
+    enabled_changed: Notify,
+    /// Sample rate and channel count of the tapped samples, packed as `rate << 16 | channels`.
+    format: AtomicU64,
+    producer: Mutex<CachingProd<Arc<HeapRb<f32>>>>,
+}
+
+impl AnalysisTap {
+    /// Make a tap, along with the analyser that reads from it.
+    pub fn new() -> (Arc<Self>, Analyser) {
+        let (producer, consumer) = HeapRb::new(TAP_CAPACITY).split();
+
+        let tap = Arc::new(Self {
+            enabled: AtomicBool::new(false),
+            enabled_changed: Notify::new(),
+            format: AtomicU64::new(0),
+            producer: Mutex::new(producer),
+        });
+
+        let analyser = Analyser::new(tap.clone(), consumer);
+
+        (tap, analyser)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+        self.enabled_changed.notify_one();
+    }
+
+    pub fn set_format(&self, sample_rate: u32, channels: u16) {
+        self.format.store(((sample_rate as u64) << 16) | channels as u64, Ordering::Relaxed);
+    }
+
+    fn format(&self) -> (u32, usize) {
+        let format = self.format.load(Ordering::Relaxed);
+        ((format >> 16) as u32, (format & 0xffff) as usize)
+    }
+
+    /// Pass interleaved samples to the analyser, if it is enabled. Whole frames are dropped if the
+    /// analyser has fallen behind.
+    pub fn push(&self, samples: &[f64]) {
+        if !self.enabled.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let (_, channels) = self.format();
+        if channels == 0 {
+            return;
+        }
+
+        if let Ok(mut producer) = self.producer.try_lock() {
+            let room = producer.vacant_len() / channels * channels;
+            producer.push_iter(samples.iter().take(room).map(|sample| *sample as f32));
+        }
+    }
+}
+
+/// Works out the spectrum and levels of tapped samples, emitting them as player events.
+pub struct Analyser {
+    tap: Arc<AnalysisTap>,
+    consumer: CachingCons<Arc<HeapRb<f32>>>,
+    format: (u32, usize),
+    fft: Arc<dyn RealToComplex<f64>>,
+    window: Vec<f64>,
+    /// The most recent frames, mixed to mono, oldest first.
+    history: Vec<f64>,
+    incoming: Vec<f32>,
+    input: Vec<f64>,
+    spectrum: Vec<Complex<f64>>,
+}
+
+impl Analyser {
+    fn new(tap: Arc<AnalysisTap>, consumer: CachingCons<Arc<HeapRb<f32>>>) -> Self {
+        let fft = RealFftPlanner::<f64>::new().plan_fft_forward(FFT_FRAMES);
+        let window = (0..FFT_FRAMES)
+            .map(|i| 0.5 * (1.0 - (2.0 * PI * i as f64 / FFT_FRAMES as f64).cos()))
+            .collect();
+
+        Self {
+            tap,
+            consumer,
+            format: (0, 0),
+            input: fft.make_input_vec(),
+            spectrum: fft.make_output_vec(),
+            fft,
+            window,
+            history: Vec::with_capacity(FFT_FRAMES * 2),
+            incoming: vec![0.0; TAP_CAPACITY],
+        }
+    }
+
+    /// Emit analysis events while the tap is enabled, waiting for it to be enabled otherwise.
+    /// Nothing is emitted while no samples are being played.
+    #[instrument(skip_all)]
+    pub async fn run(mut self, event_emitter: broadcast::Sender<Event>) {
+        let mut interval = tokio::time::interval(ANALYSIS_INTERVAL);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+        loop {
+            if !self.tap.enabled.load(Ordering::Relaxed) {
+                self.tap.enabled_changed.notified().await;
+                info!("Analysis enabled: {}", self.tap.enabled.load(Ordering::Relaxed));
+                // Anything left in the tap is from before it was disabled
+                self.consumer.clear();
+                self.history.clear();
+                continue;
+            }
+
+            interval.tick().await;
+
+            let Some((spectrum, levels)) = self.analyse() else {
+                continue;
+            };
+
+            if let Some(spectrum) = spectrum {
+                let _ = event_emitter.send(Event::PlayerEvent(PlayerEvent::Spectrum(spectrum)));
+            }
+            let _ = event_emitter.send(Event::PlayerEvent(PlayerEvent::Levels(levels)));
+        }
+    }
+
+    /// Analyse the samples tapped since the last call. Returns `None` if there weren't any, and
+    /// no spectrum until a whole FFT's worth of frames has been seen.
+    fn analyse(&mut self) -> Option<(Option<Vec<f64>>, Vec<ChannelLevel>)> {
+        let format = self.tap.format();
+        if format != self.format {
+            self.format = format;
+            self.consumer.clear();
+            self.history.clear();
+            return None;
+        }
+
+        let (sample_rate, channels) = format;
+        let available = self.consumer.occupied_len() / channels.max(1) * channels;
+        if available == 0 {
+            return None;
+        }
+        let read = self.consumer.pop_slice(&mut self.incoming[..available]);
+        let incoming = &self.incoming[..read];
+
+        let mut levels = vec![ChannelLevel { peak: 0.0, rms: 0.0 }; channels];
+        for frame in incoming.chunks_exact(channels) {
+            for (level, sample) in levels.iter_mut().zip(frame) {
+                let sample = *sample as f64;
+                level.peak = level.peak.max(sample.abs());
+                level.rms += sample * sample;
+            }
+
+            self.history.push(frame.iter().map(|sample| *sample as f64).sum::<f64>() / channels as f64);
+        }
+
+        let frames = (read / channels) as f64;
+        levels.iter_mut().for_each(|level| level.rms = (level.rms / frames).sqrt());
+
+        if self.history.len() > FFT_FRAMES {
+            self.history.drain(..self.history.len() - FFT_FRAMES);
+        }
+
+        let spectrum = (self.history.len() == FFT_FRAMES).then(|| self.spectrum(sample_rate));
+
+        Some((spectrum, levels))
+    }
+
+    /// The spectrum of the frames in `history`, as the peak magnitude in dB of each band.
+    fn spectrum(&mut self, sample_rate: u32) -> Vec<f64> {
+        for ((input, sample), window) in self.input.iter_mut().zip(&self.history).zip(&self.window) {
+            *input = sample * window;
+        }
+        let _ = self.fft.process(&mut self.input, &mut self.spectrum);
+
+        // Scaled so a full scale sine reads 0 dB
+        let scale = 2.0 / self.window.iter().sum::<f64>();
+        let bin_hz = sample_rate as f64 / FFT_FRAMES as f64;
+        let highest = SPECTRUM_HIGHEST_HZ.min(sample_rate as f64 / 2.0);
+        let edge = |band: usize| SPECTRUM_LOWEST_HZ * (highest / SPECTRUM_LOWEST_HZ).powf(band as f64 / SPECTRUM_BANDS as f64);
+
+        (0..SPECTRUM_BANDS)
+            .map(|band| {
+                let low = (edge(band) / bin_hz).round() as usize;
+                // Low bands can be narrower than a bin, so each band covers at least one
+                let high = ((edge(band + 1) / bin_hz).round() as usize).max(low + 1).min(self.spectrum.len());
+
+                let magnitude = self.spectrum[low.min(high - 1)..high].iter()
+                    .map(|bin| bin.norm() * scale)
+                    .fold(0.0, f64::max);
+
+                (20.0 * magnitude.log10()).max(SPECTRUM_FLOOR_DB)
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,238 @@
+use std::f64::consts::FRAC_1_SQRT_2;
+
+use serde::{Deserialize, Serialize};
+
+use crate::audio::settings::PlayerSettings;
+
+/// How tracks' channels are mapped to the output's. These only apply to stereo output.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChannelSettings {
+    /// Balance between the left and right channels, from -1 (left only) to 1 (right only). The
+    /// channel being turned away from is attenuated, and the other is left as it is.
+    pub balance: f64,
+    /// Mix every channel together and play the result through both sides.
+    pub mono: bool,
+    /// Swap the left and right channels.
+    pub swap_left_right: bool,
+}
+
+/// A matrix mapping a track's channels to the output's. Tracks with a different number of
+/// channels to the output are downmixed or upmixed to it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelMap {
+    source_channels: usize,
+    output_channels: usize,
+    /// The gain from each source channel into each output channel, indexed by
+    /// `output * source_channels + source`.
+    gains: Vec<f64>,
+    /// The source channel copied to each output channel, if that's all the map does.
+    routing: Option<Vec<usize>>,
+}
+
+impl ChannelMap {
+    /// Build the map from `source_channels` to `output_channels`. When playback is bit-perfect, only
+    /// the conversion between channel counts is done, and the settings are ignored.
+    pub fn new(settings: &PlayerSettings, source_channels: u16, output_channels: u16) -> Self {
+        let (source_channels, output_channels) = (source_channels as usize, output_channels as usize);
+        let mut rows = base_matrix(source_channels, output_channels);
+
+        let options = settings.channels;
+        if output_channels == 2 && !settings.is_bit_perfect() {
+            if options.mono {
+                let mixed: Vec<f64> = (0..source_channels).map(|source| (rows[0][source] + rows[1][source]) / 2.0).collect();
+                rows = vec![mixed.clone(), mixed];
+            }
+
+            if options.swap_left_right {
+                rows.swap(0, 1);
+            }
+
+            let balance = options.balance.clamp(-1.0, 1.0);
+            rows[0].iter_mut().for_each(|gain| *gain *= (1.0 - balance).min(1.0));
+            rows[1].iter_mut().for_each(|gain| *gain *= (1.0 + balance).min(1.0));
+        }
+
+        let routing = rows.iter()
+            .map(|row| {
+                let mut sources = row.iter().enumerate().filter(|(_, gain)| **gain != 0.0);
+                match (sources.next(), sources.next()) {
+                    (Some((source, gain)), None) if *gain == 1.0 => Some(source),
+                    _ => None,
+                }
+            })
+            .collect();
+
+        Self {
+            source_channels,
+            output_channels,
+            gains: rows.concat(),
+            routing,
+        }
+    }
+
+    pub fn source_channels(&self) -> usize {
+        self.source_channels
+    }
+
+    pub fn output_channels(&self) -> usize {
+        self.output_channels
+    }
+
+    /// The source channel to copy to each output channel, if the map only moves samples around
+    /// and doesn't change them.
+    pub fn routing(&self) -> Option<&[usize]> {
+        self.routing.as_deref()
+    }
+
+    /// Map interleaved `samples` to the output's channels, replacing the contents of `output`.
+    pub fn apply(&self, samples: &[f64], output: &mut Vec<f64>) {
+        output.clear();
+
+        for frame in samples.chunks_exact(self.source_channels) {
+            for row in self.gains.chunks_exact(self.source_channels) {
+                output.push(row.iter().zip(frame).map(|(gain, sample)| gain * sample).sum());
+            }
+        }
+    }
+}
+
+/// The matrix converting between channel counts, as a row of source gains for each output channel.
+fn base_matrix(source_channels: usize, output_channels: usize) -> Vec<Vec<f64>> {
+    if source_channels == output_channels || output_channels != 2 {
+        return (0..output_channels)
+            .map(|output| (0..source_channels).map(|source| if source == output { 1.0 } else { 0.0 }).collect())
+            .collect();
+    }
+
+    let (mut left, mut right): (Vec<f64>, Vec<f64>) = stereo_downmix(source_channels).into_iter().unzip();
+
+    // Scale each side down so the mix can't go over full scale
+    for row in [&mut left, &mut right] {
+        let total: f64 = row.iter().sum();
+        if total > 1.0 {
+            row.iter_mut().for_each(|gain| *gain /= total);
+        }
+    }
+
+    vec![left, right]
+}
+
+/// The (left, right) gains for each channel when mixing `channels` down to stereo, following the
+/// channel order used by FLAC and WAV.
+fn stereo_downmix(channels: usize) -> Vec<(f64, f64)> {
+    const LEFT: (f64, f64) = (1.0, 0.0);
+    const RIGHT: (f64, f64) = (0.0, 1.0);
+    const CENTRE: (f64, f64) = (FRAC_1_SQRT_2, FRAC_1_SQRT_2);
+    const LFE: (f64, f64) = (0.0, 0.0);
+    const SURROUND_LEFT: (f64, f64) = (FRAC_1_SQRT_2, 0.0);
+    const SURROUND_RIGHT: (f64, f64) = (0.0, FRAC_1_SQRT_2);
+
+    match channels {
+        1 => vec![(1.0, 1.0)],
+        3 => vec![LEFT, RIGHT, CENTRE],
+        4 => vec![LEFT, RIGHT, SURROUND_LEFT, SURROUND_RIGHT],
+        5 => vec![LEFT, RIGHT, CENTRE, SURROUND_LEFT, SURROUND_RIGHT],
+        6 => vec![LEFT, RIGHT, CENTRE, LFE, SURROUND_LEFT, SURROUND_RIGHT],
+        7 => vec![LEFT, RIGHT, CENTRE, LFE, CENTRE, SURROUND_LEFT, SURROUND_RIGHT],
+        8 => vec![LEFT, RIGHT, CENTRE, LFE, SURROUND_LEFT, SURROUND_RIGHT, SURROUND_LEFT, SURROUND_RIGHT],
+        // Unknown layouts alternate between the two sides
+        _ => (0..channels).map(|channel| if channel % 2 == 0 { LEFT } else { RIGHT }).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(channels: ChannelSettings) -> PlayerSettings {
+        PlayerSettings { channels, ..Default::default() }
+    }
+
+    fn assert_gains(map: &ChannelMap, expected: &[f64]) {
+        assert_eq!(map.gains.len(), expected.len());
+        for (gain, expected) in map.gains.iter().zip(expected) {
+            assert!((gain - expected).abs() < 1e-9, "gains were {:?}, expected {expected:?}", map.gains);
+        }
+    }
+
+    #[test]
+    fn stereo_is_routed_unchanged() {
+        let map = ChannelMap::new(&PlayerSettings::default(), 2, 2);
+
+        assert_eq!(map.routing(), Some([0, 1].as_slice()));
+        assert_gains(&map, &[1.0, 0.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn mono_track_plays_through_both_sides() {
+        let map = ChannelMap::new(&PlayerSettings::default(), 1, 2);
+
+        assert_eq!(map.routing(), Some([0, 0].as_slice()));
+        let mut output = Vec::new();
+        map.apply(&[0.5, -0.25], &mut output);
+        assert_eq!(output, [0.5, 0.5, -0.25, -0.25]);
+    }
+
+    #[test]
+    fn mono_mixes_both_sides() {
+        let map = ChannelMap::new(&settings(ChannelSettings { mono: true, ..Default::default() }), 2, 2);
+
+        assert_eq!(map.routing(), None);
+        assert_gains(&map, &[0.5, 0.5, 0.5, 0.5]);
+    }
+
+    #[test]
+    fn swaps_left_and_right() {
+        let map = ChannelMap::new(&settings(ChannelSettings { swap_left_right: true, ..Default::default() }), 2, 2);
+
+        assert_eq!(map.routing(), Some([1, 0].as_slice()));
+        let mut output = Vec::new();
+        map.apply(&[0.1, 0.2], &mut output);
+        assert_eq!(output, [0.2, 0.1]);
+    }
+
+    #[test]
+    fn balance_attenuates_the_other_side() {
+        let map = ChannelMap::new(&settings(ChannelSettings { balance: 0.5, ..Default::default() }), 2, 2);
+
+        assert_eq!(map.routing(), None);
+        assert_gains(&map, &[0.5, 0.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn bit_perfect_ignores_settings() {
+        let settings = PlayerSettings {
+            bit_perfect: true,
+            channels: ChannelSettings { balance: -1.0, mono: true, swap_left_right: true },
+            ..Default::default()
+        };
+        let map = ChannelMap::new(&settings, 2, 2);
+
+        assert_eq!(map.routing(), Some([0, 1].as_slice()));
+    }
+
+    #[test]
+    fn downmixes_surround_without_clipping() {
+        let map = ChannelMap::new(&PlayerSettings::default(), 6, 2);
+
+        // L, R, C, LFE, Ls, Rs, with each side scaled by its total gain of 1 + 2 * -3 dB
+        let total = 1.0 + 2.0 * FRAC_1_SQRT_2;
+        let (side, mixed) = (1.0 / total, FRAC_1_SQRT_2 / total);
+        assert_gains(&map, &[
+            side, 0.0, mixed, 0.0, mixed, 0.0,
+            0.0, side, mixed, 0.0, 0.0, mixed,
+        ]);
+
+        let mut output = Vec::new();
+        map.apply(&[1.0; 6], &mut output);
+        assert!(output.iter().all(|sample| (sample - 1.0).abs() < 1e-9), "full scale input gave {output:?}");
+    }
+
+    #[test]
+    fn other_outputs_copy_matching_channels() {
+        let map = ChannelMap::new(&PlayerSettings::default(), 2, 4);
+
+        assert_gains(&map, &[1.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0]);
+    }
+}
@@ -0,0 +1,598 @@
+use std::{fmt::Write, time::Duration};
+
+use bytes::Bytes;
+use dash_mpd::{AdaptationSet, BaseURL, Period, Representation, SegmentTemplate, SegmentTimeline, MPD};
+use snafu::{OptionExt, ResultExt, Snafu};
+use tidalrs::AudioQuality;
+use tracing::{info, instrument, trace, warn};
+use url::Url;
+
+/// A representation of a track offered by a DASH manifest.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DashRepresentation {
+    pub id: Option<String>,
+    pub codecs: String,
+    /// Bits per second needed to stream the representation.
+    pub bandwidth: u64,
+    pub sample_rate: u32,
+    /// Bit depth of the representation. Manifests don't give it, so it's read from the init segment
+    /// once the stream is probed.
+    pub bits_per_sample: Option<u32>,
+}
+
+impl DashRepresentation {
+    fn is_flac(&self) -> bool {
+        self.codecs.eq_ignore_ascii_case("flac")
+    }
+
+    /// Whether the representation can be decoded. Of the AAC profiles, only AAC-LC is supported.
+    pub fn is_supported(&self) -> bool {
+        self.is_flac() || self.codecs.eq_ignore_ascii_case("mp4a.40.2")
+    }
+
+    /// The quality tier the representation is in. FLAC is hi-res if it's above 16 bits or 48 kHz,
+    /// so until its bit depth is known, FLAC at up to 48 kHz counts as lossless.
+    pub fn quality(&self) -> AudioQuality {
+        match (self.is_flac(), self.sample_rate, self.bits_per_sample, self.bandwidth) {
+            (true, rate, bits, _) if rate > 48_000 || bits.is_some_and(|bits| bits > 16) => AudioQuality::HiResLossless,
+            (true, _, _, _) => AudioQuality::Lossless,
+            (false, _, _, bandwidth) if bandwidth > 160_000 => AudioQuality::High,
+            _ => AudioQuality::Low,
+        }
+    }
+
+    /// Whether the representation decodes to the same format as `other`, so the two can follow on
+    /// from each other without reopening the output.
+    fn same_format(&self, other: &Self) -> bool {
+        self.codecs.eq_ignore_ascii_case(&other.codecs)
+            && self.sample_rate == other.sample_rate
+            && self.bits_per_sample == other.bits_per_sample
+    }
+}
+
+/// A media segment of the chosen representation.
+#[derive(Debug, Clone)]
+pub struct DashSegment {
+    pub url: String,
+    /// Where the segment starts in the track.
+    pub start: Duration,
+    /// The period the segment is in, which decides the init segment it is decoded with.
+    pub period: usize,
+}
+
+/// A DASH stream ready to be played, with a representation chosen from each period of the manifest.
+/// Every period is played in the same format as the first.
+#[derive(Debug, Clone)]
+pub struct DashStream {
+    /// The representation chosen from the first period.
+    pub representation: DashRepresentation,
+    /// Every audio representation in the first period.
+    pub representations: Vec<DashRepresentation>,
+    /// The init segment of each period.
+    pub init_urls: Vec<String>,
+    /// The first period's init segment, once it has been downloaded to probe the stream.
+    pub init_data: Option<Bytes>,
+    pub segments: Vec<DashSegment>,
+    pub duration: Duration,
+}
+
+impl DashStream {
+    /// Choose the representation to play from each period of `mpd`. From the first period, the best
+    /// supported representation no better than `quality` is picked, or the lowest supported one if
+    /// they're all better. The later periods are played with their representation in the same
+    /// format.
+    #[instrument(skip(mpd), err)]
+    pub fn select(mpd: &MPD, quality: AudioQuality) -> Result<Self, DashError> {
+        let mpd_base = resolve_base(None, &mpd.base_url)?;
+
+        let mut stream: Option<Self> = None;
+        let mut offset = Duration::ZERO;
+
+        for (index, period) in mpd.periods.iter().enumerate() {
+            let period_base = resolve_base(mpd_base.clone(), &period.BaseURL)?;
+            let candidates = candidates(period, period_base)?;
+            if candidates.is_empty() {
+                return Err(DashError::NoAudio);
+            }
+            let representations: Vec<_> = candidates.iter().map(|candidate| candidate.representation.clone()).collect();
+            trace!("Period {index} representations: {representations:#?}");
+
+            let chosen = match stream.as_ref() {
+                Some(stream) => matching(&candidates, &stream.representation).context(NoMatchingRepresentationSnafu { period: index })?,
+                None => choose(&candidates, quality).context(NoSupportedRepresentationSnafu {
+                    codecs: representations.iter().map(|representation| representation.codecs.clone()).collect::<Vec<_>>(),
+                })?,
+            };
+
+            let period_duration = period.duration.or(mpd.mediaPresentationDuration.filter(|_| mpd.periods.len() == 1));
+            let (init_url, segments) = chosen.segments(index, offset, period_duration)?;
+            offset = segments.last().map_or(offset, |(segment, duration)| segment.start + *duration);
+
+            match stream.as_mut() {
+                Some(stream) => {
+                    stream.init_urls.push(init_url);
+                    stream.segments.extend(segments.into_iter().map(|(segment, _)| segment));
+                }
+                None => {
+                    info!("Playing representation {:?} ({}, {} bps, {} Hz)", chosen.representation.id, chosen.representation.codecs, chosen.representation.bandwidth, chosen.representation.sample_rate);
+                    stream = Some(Self {
+                        representation: chosen.representation.clone(),
+                        representations,
+                        init_urls: vec![init_url],
+                        init_data: None,
+                        segments: segments.into_iter().map(|(segment, _)| segment).collect(),
+                        duration: Duration::ZERO,
+                    });
+                }
+            }
+        }
+
+        let mut stream = stream.context(NoSegmentsSnafu)?;
+        if stream.segments.is_empty() {
+            return Err(DashError::NoSegments);
+        }
+        stream.duration = offset;
+
+        Ok(stream)
+    }
+
+    /// The index of the segment containing `position`.
+    pub fn segment_at(&self, position: Duration) -> usize {
+        self.segments.iter().rposition(|segment| segment.start <= position).unwrap_or(0)
+    }
+}
+
+/// A representation along with what's needed to find its segments.
+struct Candidate {
+    representation: DashRepresentation,
+    template: Template,
+    base: Option<Url>,
+}
+
+impl Candidate {
+    /// The init segment URL, and each media segment with its duration.
+    fn segments(&self, period: usize, offset: Duration, period_duration: Option<Duration>) -> Result<(String, Vec<(DashSegment, Duration)>), DashError> {
+        let template = &self.template;
+        let timescale = template.timescale as f64;
+        let fill = |media: &str, number: u64, time: u64| {
+            let path = fill_template(media, &self.representation, number, time)?;
+            join(self.base.as_ref(), &path).map(String::from)
+        };
+
+        let init_url = fill(&template.initialization, template.start_number, 0)?;
+
+        let times = match (&template.timeline, template.duration) {
+            (Some(timeline), _) => {
+                // Where an open-ended repeat at the end of the timeline stops
+                let end = period_duration.map(|duration| (duration.as_secs_f64() * timescale).round() as u64 + template.presentation_time_offset);
+                segment_times(timeline, end)
+            }
+            (None, Some(duration)) => {
+                let period_duration = period_duration.context(UnknownDurationSnafu)?;
+                let count = (period_duration.as_secs_f64() * timescale / duration).ceil() as u64;
+                (0..count).map(|index| ((index as f64 * duration) as u64, duration as u64)).collect()
+            }
+            (None, None) => return Err(DashError::NoSegmentTimes),
+        };
+
+        let segments = times.into_iter()
+            .enumerate()
+            .map(|(index, (time, duration))| {
+                let start = time.saturating_sub(template.presentation_time_offset) as f64 / timescale;
+                let segment = DashSegment {
+                    url: fill(&template.media, template.start_number + index as u64, time)?,
+                    start: offset + Duration::from_secs_f64(start),
+                    period,
+                };
+                Ok((segment, Duration::from_secs_f64(duration as f64 / timescale)))
+            })
+            .collect::<Result<_, DashError>>()?;
+
+        Ok((init_url, segments))
+    }
+}
+
+/// A SegmentTemplate, with anything missing from the representation's taken from its adaptation set.
+struct Template {
+    initialization: String,
+    media: String,
+    timeline: Option<SegmentTimeline>,
+    start_number: u64,
+    /// Length of every segment, for templates without a timeline.
+    duration: Option<f64>,
+    timescale: u64,
+    presentation_time_offset: u64,
+}
+
+impl Template {
+    fn new(adaptation: Option<&SegmentTemplate>, representation: Option<&SegmentTemplate>) -> Result<Self, DashError> {
+        macro_rules! inherit {
+            ($field:ident) => {
+                representation.and_then(|template| template.$field.clone())
+                    .or_else(|| adaptation.and_then(|template| template.$field.clone()))
+            };
+        }
+
+        Ok(Self {
+            initialization: inherit!(initialization).context(NoInitializationSnafu)?,
+            media: inherit!(media).context(NoMediaSnafu)?,
+            timeline: inherit!(SegmentTimeline),
+            start_number: inherit!(startNumber).unwrap_or(1),
+            duration: inherit!(duration),
+            timescale: inherit!(timescale).unwrap_or(1).max(1),
+            presentation_time_offset: inherit!(presentationTimeOffset).unwrap_or(0),
+        })
+    }
+}
+
+/// Every audio representation in `period` that has a usable sample rate.
+fn candidates(period: &Period, base: Option<Url>) -> Result<Vec<Candidate>, DashError> {
+    let mut candidates = Vec::new();
+    let mut skipped = None;
+
+    for adaptation in period.adaptations.iter().filter(|adaptation| is_audio(adaptation)) {
+        let adaptation_base = resolve_base(base.clone(), &adaptation.BaseURL)?;
+
+        for representation in &adaptation.representations {
+            // Without a sample rate a representation can't be ranked, but the others may still be playable
+            let described = match describe(adaptation, representation) {
+                Ok(described) => described,
+                Err(e) => {
+                    warn!("Skipping representation: {e}");
+                    skipped.get_or_insert(e);
+                    continue;
+                }
+            };
+
+            candidates.push(Candidate {
+                representation: described,
+                template: Template::new(adaptation.SegmentTemplate.as_ref(), representation.SegmentTemplate.as_ref())?,
+                base: resolve_base(adaptation_base.clone(), &representation.BaseURL)?,
+            });
+        }
+    }
+
+    match skipped {
+        Some(e) if candidates.is_empty() => Err(e),
+        _ => Ok(candidates),
+    }
+}
+
+fn is_audio(adaptation: &AdaptationSet) -> bool {
+    let content_type = adaptation.contentType.as_deref()
+        .or(adaptation.mimeType.as_deref())
+        .or_else(|| adaptation.representations.first().and_then(|representation| representation.mimeType.as_deref()));
+
+    // Tidal's manifests only have audio, and don't always say so
+    content_type.is_none_or(|content_type| content_type.starts_with("audio"))
+}
+
+fn describe(adaptation: &AdaptationSet, representation: &Representation) -> Result<DashRepresentation, DashError> {
+    let id = representation.id.clone();
+    let sample_rate = representation.audioSamplingRate.as_ref().context(MissingSampleRateSnafu { id: id.clone() })?;
+    let sample_rate = sample_rate.parse().ok().context(InvalidSampleRateSnafu { id: id.clone(), value: sample_rate.clone() })?;
+
+    Ok(DashRepresentation {
+        codecs: representation.codecs.clone().or_else(|| adaptation.codecs.clone()).unwrap_or_default(),
+        bandwidth: representation.bandwidth.unwrap_or(0),
+        sample_rate,
+        bits_per_sample: None,
+        id,
+    })
+}
+
+/// Orders the quality tiers from lowest to highest.
+pub fn quality_rank(quality: AudioQuality) -> u8 {
+    match quality {
+        AudioQuality::Low => 0,
+        AudioQuality::High => 1,
+        AudioQuality::Lossless => 2,
+        AudioQuality::HiResLossless => 3,
+    }
+}
+
+fn choose(candidates: &[Candidate], quality: AudioQuality) -> Option<&Candidate> {
+    let wanted = quality_rank(quality);
+    let rank = |candidate: &&Candidate| (quality_rank(candidate.representation.quality()), candidate.representation.bandwidth);
+    let supported = || candidates.iter().filter(|candidate| candidate.representation.is_supported());
+
+    supported()
+        .filter(|candidate| quality_rank(candidate.representation.quality()) <= wanted)
+        .max_by_key(rank)
+        .or_else(|| supported().min_by_key(rank))
+}
+
+/// The candidate in the same format as `representation`, preferring the one with the same id, then
+/// the closest bandwidth.
+fn matching<'a>(candidates: &'a [Candidate], representation: &DashRepresentation) -> Option<&'a Candidate> {
+    let same_format = || candidates.iter().filter(|candidate| candidate.representation.same_format(representation));
+
+    same_format()
+        .find(|candidate| candidate.representation.id == representation.id)
+        .or_else(|| same_format().min_by_key(|candidate| candidate.representation.bandwidth.abs_diff(representation.bandwidth)))
+}
+
+/// Resolve the first of `urls` against `base`. Further BaseURLs are alternative locations, which
+/// aren't used.
+fn resolve_base(base: Option<Url>, urls: &[BaseURL]) -> Result<Option<Url>, DashError> {
+    match urls.first() {
+        Some(url) => join(base.as_ref(), &url.base).map(Some),
+        None => Ok(base),
+    }
+}
+
+fn join(base: Option<&Url>, url: &str) -> Result<Url, DashError> {
+    match base {
+        Some(base) => base.join(url),
+        None => Url::parse(url),
+    }
+    .context(UrlSnafu { url })
+}
+
+/// Substitute the identifiers in a SegmentTemplate URL, including any `%0<width>d` format tags.
+fn fill_template(template: &str, representation: &DashRepresentation, number: u64, time: u64) -> Result<String, DashError> {
+    let mut url = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find('$') {
+        url.push_str(&rest[..start]);
+        let (identifier, after) = rest[start + 1..].split_once('$').context(TemplateSnafu { template })?;
+        rest = after;
+
+        let (name, format) = match identifier.split_once('%') {
+            Some((name, format)) => (name, Some(format)),
+            None => (identifier, None),
+        };
+
+        let value = match name {
+            // `$$` is an escaped dollar sign
+            "" => {
+                url.push('$');
+                continue;
+            }
+            "RepresentationID" => {
+                url.push_str(representation.id.as_deref().context(TemplateSnafu { template })?);
+                continue;
+            }
+            "Number" => number,
+            "Time" => time,
+            "Bandwidth" => representation.bandwidth,
+            _ => return Err(DashError::Template { template: template.to_owned() }),
+        };
+
+        let width = match format {
+            Some(format) => format.strip_prefix('0')
+                .and_then(|format| format.strip_suffix('d'))
+                .and_then(|width| width.parse().ok())
+                .context(TemplateSnafu { template })?,
+            None => 0,
+        };
+        let _ = write!(url, "{value:0width$}");
+    }
+
+    url.push_str(rest);
+    Ok(url)
+}
+
+/// Expands a SegmentTimeline into the start time and duration of each segment, in timescale units.
+/// A negative repeat count repeats the segment up to the next entry's start, or up to `end` for
+/// the last entry.
+fn segment_times(timeline: &SegmentTimeline, end: Option<u64>) -> Vec<(u64, u64)> {
+    let mut segments = Vec::new();
+    let mut time = 0u64;
+    for (index, s) in timeline.segments.iter().enumerate() {
+        if let Some(t) = s.t {
+            time = t;
+        }
+        let repeats = match s.r.unwrap_or(0) {
+            r if r >= 0 => r as u64,
+            _ => {
+                let until = match timeline.segments.get(index + 1) {
+                    Some(next) => next.t,
+                    None => end,
+                };
+                match until {
+                    Some(until) if s.d > 0 => until.saturating_sub(time).div_ceil(s.d).saturating_sub(1),
+                    _ => 0,
+                }
+            }
+        };
+        for _ in 0..=repeats {
+            segments.push((time, s.d));
+            time += s.d;
+        }
+    }
+    segments
+}
+
+#[derive(Debug, Snafu)]
+pub enum DashError {
+    #[snafu(display("Manifest has a period without audio"))]
+    NoAudio,
+    #[snafu(display("None of the representations can be played (codecs: {codecs:?})"))]
+    NoSupportedRepresentation {
+        codecs: Vec<String>,
+    },
+    #[snafu(display("Period {period} has no representation in the same format as the first"))]
+    NoMatchingRepresentation {
+        period: usize,
+    },
+    #[snafu(display("Representation {id:?} has no audioSamplingRate"))]
+    MissingSampleRate {
+        id: Option<String>,
+    },
+    #[snafu(display("Representation {id:?} has an invalid audioSamplingRate '{value}'"))]
+    InvalidSampleRate {
+        id: Option<String>,
+        value: String,
+    },
+    #[snafu(display("SegmentTemplate has no initialization URL"))]
+    NoInitialization,
+    #[snafu(display("SegmentTemplate has no media URL"))]
+    NoMedia,
+    #[snafu(display("SegmentTemplate has neither a SegmentTimeline nor a duration"))]
+    NoSegmentTimes,
+    #[snafu(display("Period has no duration to divide into segments"))]
+    UnknownDuration,
+    #[snafu(display("Manifest has no segments"))]
+    NoSegments,
+    #[snafu(display("Unsupported SegmentTemplate URL '{template}'"))]
+    Template {
+        template: String,
+    },
+    #[snafu(display("Invalid URL '{url}'"))]
+    Url {
+        url: String,
+        source: url::ParseError,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn representation() -> DashRepresentation {
+        DashRepresentation { id: Some("flac".to_owned()), codecs: "flac".to_owned(), bandwidth: 1_000_000, sample_rate: 44_100, bits_per_sample: None }
+    }
+
+    /// A single period manifest with a FLAC representation, and an AAC one without a sample rate.
+    fn manifest(template: &str, timeline: &str) -> MPD {
+        dash_mpd::parse(&format!(r#"<?xml version="1.0" encoding="UTF-8"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="static" mediaPresentationDuration="PT10S">
+  <Period>
+    <AdaptationSet contentType="audio" mimeType="audio/mp4">
+      <Representation id="aac" codecs="mp4a.40.2" bandwidth="320000">
+        <SegmentTemplate initialization="https://example.com/aac/init.mp4" media="https://example.com/aac/$Number$.mp4"/>
+      </Representation>
+      <Representation id="flac" codecs="flac" bandwidth="1000000" audioSamplingRate="44100">
+        <SegmentTemplate initialization="https://example.com/$RepresentationID$/init.mp4" {template}>
+          <SegmentTimeline>{timeline}</SegmentTimeline>
+        </SegmentTemplate>
+      </Representation>
+    </AdaptationSet>
+  </Period>
+</MPD>"#)).unwrap()
+    }
+
+    fn segment_urls(stream: &DashStream) -> Vec<&str> {
+        stream.segments.iter().map(|segment| segment.url.as_str()).collect()
+    }
+
+    #[test]
+    fn fills_template_identifiers() {
+        let url = fill_template("$RepresentationID$/$Number%05d$-$Time$-$Bandwidth$.mp4", &representation(), 42, 176_128).unwrap();
+
+        assert_eq!(url, "flac/00042-176128-1000000.mp4");
+    }
+
+    #[test]
+    fn fills_escaped_dollar() {
+        assert_eq!(fill_template("a$$b/$Number$", &representation(), 3, 0).unwrap(), "a$b/3");
+    }
+
+    #[test]
+    fn rejects_unknown_identifiers() {
+        assert!(fill_template("$Unknown$.mp4", &representation(), 1, 0).is_err());
+        assert!(fill_template("$Number%5x$.mp4", &representation(), 1, 0).is_err());
+        assert!(fill_template("$Number.mp4", &representation(), 1, 0).is_err());
+    }
+
+    #[test]
+    fn numbers_segments_from_start_number() {
+        let mpd = manifest(
+            r#"timescale="2" startNumber="7" media="https://example.com/$RepresentationID$/$Number%05d$.mp4""#,
+            r#"<S d="4" r="2"/>"#,
+        );
+        let stream = DashStream::select(&mpd, AudioQuality::Lossless).unwrap();
+
+        assert_eq!(stream.representation, representation());
+        assert_eq!(stream.init_urls, ["https://example.com/flac/init.mp4"]);
+        assert_eq!(segment_urls(&stream), [
+            "https://example.com/flac/00007.mp4",
+            "https://example.com/flac/00008.mp4",
+            "https://example.com/flac/00009.mp4",
+        ]);
+        let starts: Vec<_> = stream.segments.iter().map(|segment| segment.start).collect();
+        assert_eq!(starts, [Duration::ZERO, Duration::from_secs(2), Duration::from_secs(4)]);
+        assert_eq!(stream.duration, Duration::from_secs(6));
+    }
+
+    #[test]
+    fn open_repeat_runs_until_next_entry() {
+        let mpd = manifest(
+            r#"timescale="10" media="https://example.com/$Time$.mp4""#,
+            r#"<S t="0" d="10" r="-1"/><S t="30" d="5"/>"#,
+        );
+        let stream = DashStream::select(&mpd, AudioQuality::Lossless).unwrap();
+
+        assert_eq!(segment_urls(&stream), [
+            "https://example.com/0.mp4",
+            "https://example.com/10.mp4",
+            "https://example.com/20.mp4",
+            "https://example.com/30.mp4",
+        ]);
+    }
+
+    #[test]
+    fn open_repeat_runs_until_period_end() {
+        let mpd = manifest(
+            r#"timescale="1000" media="https://example.com/$Time$.mp4""#,
+            r#"<S t="0" d="4000" r="-1"/>"#,
+        );
+        let stream = DashStream::select(&mpd, AudioQuality::Lossless).unwrap();
+
+        assert_eq!(segment_urls(&stream), [
+            "https://example.com/0.mp4",
+            "https://example.com/4000.mp4",
+            "https://example.com/8000.mp4",
+        ]);
+    }
+
+    #[test]
+    fn skips_representations_without_sample_rate() {
+        let mpd = manifest(r#"media="https://example.com/$Number$.mp4""#, r#"<S d="1"/>"#);
+        let stream = DashStream::select(&mpd, AudioQuality::High).unwrap();
+
+        // The FLAC representation is the only candidate, even though it's better than what was asked for
+        assert_eq!(stream.representations, [representation()]);
+        assert_eq!(stream.representation, representation());
+    }
+
+    #[test]
+    fn classifies_flac_by_depth_and_rate() {
+        let flac = |sample_rate, bits_per_sample| DashRepresentation { sample_rate, bits_per_sample, ..representation() };
+
+        assert_eq!(flac(44_100, Some(16)).quality(), AudioQuality::Lossless);
+        assert_eq!(flac(44_100, Some(24)).quality(), AudioQuality::HiResLossless);
+        assert_eq!(flac(48_000, Some(24)).quality(), AudioQuality::HiResLossless);
+        assert_eq!(flac(96_000, Some(16)).quality(), AudioQuality::HiResLossless);
+        // Until the init segment is read, only the rate is known
+        assert_eq!(flac(48_000, None).quality(), AudioQuality::Lossless);
+        assert_eq!(flac(192_000, None).quality(), AudioQuality::HiResLossless);
+    }
+
+    #[test]
+    fn plays_every_period_in_the_same_format() {
+        let period = |representations: &str| format!(r#"
+  <Period duration="PT4S">
+    <AdaptationSet contentType="audio" mimeType="audio/mp4">{representations}</AdaptationSet>
+  </Period>"#);
+        let representation = |id: &str, rate: u32, bandwidth: u32| format!(r#"
+      <Representation id="{id}" codecs="flac" bandwidth="{bandwidth}" audioSamplingRate="{rate}">
+        <SegmentTemplate initialization="https://example.com/$RepresentationID$/init.mp4" media="https://example.com/$RepresentationID$/$Number$.mp4" timescale="1">
+          <SegmentTimeline><S d="4"/></SegmentTimeline>
+        </SegmentTemplate>
+      </Representation>"#);
+
+        let mpd = dash_mpd::parse(&format!(r#"<?xml version="1.0" encoding="UTF-8"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="static">{}{}</MPD>"#,
+            period(&(representation("a-44", 44_100, 1_000_000) + &representation("a-96", 96_000, 3_000_000))),
+            period(&(representation("b-96", 96_000, 3_000_000) + &representation("b-44", 44_100, 900_000))),
+        )).unwrap();
+        let stream = DashStream::select(&mpd, AudioQuality::Lossless).unwrap();
+
+        assert_eq!(stream.representation.id.as_deref(), Some("a-44"));
+        assert_eq!(stream.init_urls, ["https://example.com/a-44/init.mp4", "https://example.com/b-44/init.mp4"]);
+        assert_eq!(segment_urls(&stream), ["https://example.com/a-44/1.mp4", "https://example.com/b-44/1.mp4"]);
+    }
+}
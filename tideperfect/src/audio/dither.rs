@@ -0,0 +1,49 @@
+/// Triangular (TPDF) dither, added before reducing samples to a lower bit depth so the rounding
+/// error is decorrelated from the signal instead of showing up as distortion.
+pub struct Dither {
+    state: u64,
+}
+
+impl Dither {
+    pub fn new() -> Self {
+        Self { state: 0x9E37_79B9_7F4A_7C15 }
+    }
+
+    /// Dither noise in LSBs, with a triangular distribution over (-1, 1).
+    pub fn sample(&mut self) -> f64 {
+        self.uniform() - self.uniform()
+    }
+
+    /// A uniformly distributed value in [0, 1), from a xorshift generator. This runs in the output
+    /// callback, so it needs to be cheap rather than cryptographically sound.
+    fn uniform(&mut self) -> f64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        (self.state >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+impl Default for Dither {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn noise_is_triangular() {
+        let mut dither = Dither::new();
+        let samples: Vec<f64> = (0..100_000).map(|_| dither.sample()).collect();
+
+        assert!(samples.iter().all(|sample| sample.abs() < 1.0));
+        let mean = samples.iter().sum::<f64>() / samples.len() as f64;
+        assert!(mean.abs() < 0.01, "mean was {mean}");
+        // Three quarters of a triangular distribution over (-1, 1) is within half of the centre
+        let central = samples.iter().filter(|sample| sample.abs() < 0.5).count() as f64 / samples.len() as f64;
+        assert!((central - 0.75).abs() < 0.01, "{central} within 0.5");
+    }
+}
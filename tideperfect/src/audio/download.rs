@@ -0,0 +1,455 @@
+use std::{fs::File, future::Future, io::{Read, Seek, SeekFrom, Write}, ops::Range, pin::Pin, sync::{Arc, Condvar, Mutex}, time::Duration};
+
+use reqwest::{header::RANGE, Response, StatusCode};
+use snafu::Snafu;
+use symphonia::core::io::MediaSource;
+use tokio::{sync::Notify, time::{sleep, timeout}};
+use tokio_util::sync::{CancellationToken, DropGuard};
+use tracing::{info, instrument, trace, warn};
+
+use crate::audio::fetch::http_client;
+
+/// How long the connection can go without sending anything before it's treated as dropped.
+const STALL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Attempts at connecting before the download fails.
+const CONNECT_ATTEMPTS: u32 = 8;
+
+/// Wait before the first reconnection after a failed attempt, doubled for each one after it.
+const CONNECT_BACKOFF: Duration = Duration::from_millis(250);
+
+/// Reads this far ahead of the download wait for it to get there, rather than restarting the
+/// download where the read is.
+const READ_AHEAD_BYTES: u64 = 512 * 1024;
+
+/// Returned from reads once streaming has been cancelled. Its error kind isn't one that readers
+/// retry, so a cancelled decoder gives up instead of waiting on the download forever.
+#[derive(Debug, Snafu)]
+#[snafu(display("Streaming was cancelled"))]
+pub struct Cancelled;
+
+/// Gets a new link to the file, for when the one being downloaded has expired.
+pub type UrlRefresh = Arc<dyn Fn() -> Pin<Box<dyn Future<Output = Result<String, String>> + Send>> + Send + Sync>;
+
+/// A file downloaded into a temporary file as it is read. A dropped or stalled connection is
+/// picked up again with a Range request from the last byte received, and reads that seek past
+/// the download restart it from there.
+pub struct ResumableDownload {
+    shared: Arc<Shared>,
+    position: u64,
+    cancel: CancellationToken,
+    /// Stops the download once the reader is dropped.
+    _stop: DropGuard,
+}
+
+impl ResumableDownload {
+    /// Connect to `url` and start downloading in the background.
+    #[instrument(skip(refresh, cancel), err)]
+    pub async fn start(mut url: String, refresh: Option<UrlRefresh>, cancel: CancellationToken) -> Result<Self, String> {
+        let response = connect(&mut url, 0, refresh.as_ref()).await?;
+        let len = response.content_length().ok_or("Server didn't send the length of the file")?;
+        trace!("Downloading {len} bytes");
+
+        let shared = Arc::new(Shared {
+            file: Mutex::new(tempfile::tempfile().map_err(|e| e.to_string())?),
+            len,
+            state: Mutex::new(DownloadState::default()),
+            changed: Condvar::new(),
+            restart: Notify::new(),
+        });
+
+        let stop = cancel.child_token();
+        tokio::spawn(stop.clone().run_until_cancelled_owned(download(shared.clone(), url, refresh, response)));
+        // Wakes a read waiting for the download, so it sees that streaming has been cancelled
+        tokio::spawn({
+            let (shared, stop) = (shared.clone(), stop.clone());
+            async move {
+                stop.cancelled().await;
+                shared.wake();
+            }
+        });
+
+        Ok(Self {
+            shared,
+            position: 0,
+            cancel,
+            _stop: stop.drop_guard(),
+        })
+    }
+
+    /// Wait until the byte at `position` has been downloaded, returning how many bytes can be read
+    /// from there.
+    fn wait_for(&self, position: u64) -> std::io::Result<u64> {
+        let mut state = self.shared.state.lock().unwrap();
+
+        loop {
+            if let Some(range) = state.range_containing(position) {
+                return Ok(range.end - position);
+            }
+            if let Some(error) = &state.error {
+                return Err(std::io::Error::other(error.clone()));
+            }
+            if self.cancel.is_cancelled() {
+                return Err(std::io::Error::other(Cancelled));
+            }
+
+            let behind = position < state.download_position;
+            let too_far_ahead = position > state.download_position + READ_AHEAD_BYTES;
+            if (behind || too_far_ahead) && state.restart_at != Some(position) {
+                trace!("Restarting download at byte {position}");
+                state.restart_at = Some(position);
+                self.shared.restart.notify_one();
+            }
+
+            state = self.shared.changed.wait(state).unwrap();
+        }
+    }
+}
+
+impl Read for ResumableDownload {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() || self.position >= self.shared.len {
+            return Ok(0);
+        }
+
+        let available = self.wait_for(self.position)?;
+        let len = buf.len().min(available as usize);
+
+        let mut file = self.shared.file.lock().unwrap();
+        file.seek(SeekFrom::Start(self.position))?;
+        file.read_exact(&mut buf[..len])?;
+
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl Seek for ResumableDownload {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.shared.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+
+        self.position = position.ok_or(std::io::ErrorKind::InvalidInput)?;
+        Ok(self.position)
+    }
+}
+
+impl MediaSource for ResumableDownload {
+    fn is_seekable(&self) -> bool {
+        true
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        Some(self.shared.len)
+    }
+}
+
+/// State shared between the reader and the download task.
+struct Shared {
+    file: Mutex<File>,
+    len: u64,
+    state: Mutex<DownloadState>,
+    /// Signalled whenever more of the file is downloaded, the download fails, or it is stopped.
+    changed: Condvar,
+    /// Signalled when the reader wants the download to restart somewhere else.
+    restart: Notify,
+}
+
+#[derive(Default)]
+struct DownloadState {
+    /// Byte ranges that have been downloaded, in order and not touching.
+    downloaded: Vec<Range<u64>>,
+    /// Where the download is writing to.
+    download_position: u64,
+    /// Where the reader wants the download to continue from.
+    restart_at: Option<u64>,
+    error: Option<String>,
+}
+
+impl DownloadState {
+    fn range_containing(&self, position: u64) -> Option<&Range<u64>> {
+        self.downloaded.iter().find(|range| range.contains(&position))
+    }
+
+    fn add(&mut self, added: Range<u64>) {
+        self.downloaded.push(added);
+        self.downloaded.sort_by_key(|range| range.start);
+
+        let mut merged: Vec<Range<u64>> = Vec::with_capacity(self.downloaded.len());
+        for range in self.downloaded.drain(..) {
+            match merged.last_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => merged.push(range),
+            }
+        }
+        self.downloaded = merged;
+    }
+}
+
+impl Shared {
+    /// Where the download should carry on from: where the reader asked for, or `position`, moved
+    /// past anything already downloaded.
+    fn next_position(&self, position: u64) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        let mut position = state.restart_at.take().unwrap_or(position);
+        while let Some(range) = state.range_containing(position) {
+            position = range.end;
+        }
+        state.download_position = position;
+        position
+    }
+
+    /// Whether the download should move, because the reader has asked it to or it has reached
+    /// bytes it already has.
+    fn should_move(&self, position: u64) -> bool {
+        let state = self.state.lock().unwrap();
+        state.restart_at.is_some() || state.range_containing(position).is_some()
+    }
+
+    fn write(&self, position: u64, data: &[u8]) -> std::io::Result<()> {
+        {
+            let mut file = self.file.lock().unwrap();
+            file.seek(SeekFrom::Start(position))?;
+            file.write_all(data)?;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.add(position..position + data.len() as u64);
+        state.download_position = position + data.len() as u64;
+        self.changed.notify_all();
+        Ok(())
+    }
+
+    fn fail(&self, error: String) {
+        self.state.lock().unwrap().error = Some(error);
+        self.changed.notify_all();
+    }
+
+    /// Wake any read waiting for the download. The state is locked so a read that is about to wait
+    /// doesn't miss it.
+    fn wake(&self) {
+        let _state = self.state.lock().unwrap();
+        self.changed.notify_all();
+    }
+}
+
+/// Download the file into `shared`, reconnecting as needed, until the reader is dropped.
+async fn download(shared: Arc<Shared>, mut url: String, refresh: Option<UrlRefresh>, response: Response) {
+    let mut response = Some(response);
+    let mut position = 0;
+
+    loop {
+        position = shared.next_position(position);
+
+        // Everything after the read position is downloaded, but the reader may still seek back
+        if position >= shared.len {
+            trace!("Download caught up");
+            response = None;
+            shared.restart.notified().await;
+            continue;
+        }
+
+        let mut current = match response.take() {
+            Some(current) => current,
+            None => match connect(&mut url, position, refresh.as_ref()).await {
+                Ok(current) => current,
+                Err(e) => {
+                    shared.fail(e);
+                    return;
+                }
+            },
+        };
+
+        // A server that ignores the range sends the whole file again, so skip to where we were
+        let mut skip = match current.status() {
+            StatusCode::PARTIAL_CONTENT => 0,
+            _ => position,
+        };
+        if skip > 0 {
+            warn!("Server ignored the range request, skipping {skip} bytes");
+        }
+
+        loop {
+            let chunk = match timeout(STALL_TIMEOUT, current.chunk()).await {
+                Ok(Ok(Some(chunk))) => chunk,
+                Ok(Ok(None)) => break,
+                Ok(Err(e)) => {
+                    warn!("Download interrupted at byte {position}: {e}");
+                    break;
+                }
+                Err(_) => {
+                    warn!("Download stalled at byte {position}");
+                    break;
+                }
+            };
+
+            let skipped = skip.min(chunk.len() as u64);
+            skip -= skipped;
+            let chunk = chunk.slice(skipped as usize..);
+            if chunk.is_empty() {
+                continue;
+            }
+
+            if let Err(e) = shared.write(position, &chunk) {
+                shared.fail(e.to_string());
+                return;
+            }
+            position += chunk.len() as u64;
+
+            if shared.should_move(position) {
+                break;
+            }
+        }
+    }
+}
+
+/// Request the file from `position` onwards, retrying with backoff, and refreshing `url` if it
+/// has expired. The response is for the whole file rather than the range if it isn't a 206.
+async fn connect(url: &mut String, position: u64, refresh: Option<&UrlRefresh>) -> Result<Response, String> {
+    let mut backoff = CONNECT_BACKOFF;
+
+    for attempt in 1..=CONNECT_ATTEMPTS {
+        let result = http_client().get(url.as_str())
+            .header(RANGE, format!("bytes={position}-"))
+            .send().await;
+
+        let error = match result {
+            Ok(response) if response.status().is_success() => return Ok(response),
+            // The link has expired, which retrying won't fix
+            Ok(response) if matches!(response.status(), StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN | StatusCode::GONE) => {
+                let Some(refresh) = refresh else {
+                    return Err(format!("Link expired ({})", response.status()));
+                };
+
+                info!("Link expired ({}), getting a new one", response.status());
+                *url = refresh().await.map_err(|e| format!("Could not refresh link: {e}"))?;
+                continue;
+            }
+            Ok(response) => format!("Server returned {}", response.status()),
+            Err(e) => e.to_string(),
+        };
+
+        warn!("Connection attempt {attempt}/{CONNECT_ATTEMPTS} failed, retrying in {backoff:?}: {error}");
+        sleep(backoff).await;
+        backoff *= 2;
+    }
+
+    Err(format!("Could not connect after {CONNECT_ATTEMPTS} attempts"))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpListener};
+
+    use super::*;
+
+    const FILE_LEN: usize = 64 * 1024;
+    /// Where the first connection to the test server is dropped.
+    const CUT_AT: usize = 20_000;
+
+    fn file() -> Vec<u8> {
+        (0..FILE_LEN).map(|index| (index * 7 % 251) as u8).collect()
+    }
+
+    /// Serve `file()` over HTTP, dropping the first connection part way through. Later requests are
+    /// answered with the range they ask for, or the whole file if `ranges` is false. Returns the
+    /// URL, and the start of the range each request asked for.
+    async fn serve(ranges: bool) -> (String, Arc<Mutex<Vec<Option<usize>>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/file", listener.local_addr().unwrap());
+        let requested = Arc::new(Mutex::new(Vec::new()));
+
+        tokio::spawn({
+            let requested = requested.clone();
+            async move {
+                let file = file();
+                loop {
+                    let (mut socket, _) = listener.accept().await.unwrap();
+                    let mut request = Vec::new();
+                    while !request.ends_with(b"\r\n\r\n") {
+                        let mut byte = [0];
+                        if socket.read(&mut byte).await.unwrap() == 0 {
+                            break;
+                        }
+                        request.push(byte[0]);
+                    }
+
+                    let request = String::from_utf8_lossy(&request).to_lowercase();
+                    let start = request.lines()
+                        .find_map(|line| line.strip_prefix("range: bytes="))
+                        .and_then(|range| range.trim_end_matches('-').parse::<usize>().ok());
+                    let first = {
+                        let mut requested = requested.lock().unwrap();
+                        requested.push(start);
+                        requested.len() == 1
+                    };
+
+                    let (status, start) = match start {
+                        Some(start) if ranges && !first => (format!("206 Partial Content\r\nContent-Range: bytes {start}-{}/{FILE_LEN}", FILE_LEN - 1), start),
+                        _ => ("200 OK".to_owned(), 0),
+                    };
+                    let end = if first { CUT_AT } else { FILE_LEN };
+                    let header = format!("HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", FILE_LEN - start);
+
+                    socket.write_all(header.as_bytes()).await.unwrap();
+                    socket.write_all(&file[start..end]).await.unwrap();
+                }
+            }
+        });
+
+        (url, requested)
+    }
+
+    async fn download_all(url: String) -> Vec<u8> {
+        let mut download = ResumableDownload::start(url, None, CancellationToken::new()).await.unwrap();
+        tokio::task::spawn_blocking(move || {
+            let mut data = Vec::new();
+            download.read_to_end(&mut data).unwrap();
+            data
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn resumes_with_a_range_request() {
+        let (url, requested) = serve(true).await;
+
+        assert!(download_all(url).await == file());
+        assert_eq!(*requested.lock().unwrap(), [Some(0), Some(CUT_AT)]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn skips_to_the_offset_when_the_range_is_ignored() {
+        let (url, requested) = serve(false).await;
+
+        assert!(download_all(url).await == file());
+        assert_eq!(*requested.lock().unwrap(), [Some(0), Some(CUT_AT)]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn cancelling_wakes_a_waiting_read() {
+        // Sends the headers, then nothing
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/file", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let _ = socket.read(&mut [0; 1024]).await;
+            socket.write_all(format!("HTTP/1.1 200 OK\r\nContent-Length: {FILE_LEN}\r\n\r\n").as_bytes()).await.unwrap();
+            sleep(Duration::from_secs(60)).await;
+        });
+
+        let cancel = CancellationToken::new();
+        let mut download = ResumableDownload::start(url, None, cancel.clone()).await.unwrap();
+        let read = tokio::task::spawn_blocking(move || download.read(&mut [0; 16]));
+
+        sleep(Duration::from_millis(50)).await;
+        cancel.cancel();
+
+        let error = timeout(Duration::from_secs(1), read).await.expect("read didn't wake").unwrap().unwrap_err();
+        assert!(error.get_ref().is_some_and(|error| error.is::<Cancelled>()));
+    }
+}
@@ -0,0 +1,504 @@
+use std::{collections::HashMap, fs::File, path::{Path, PathBuf}, sync::Arc};
+
+use realfft::{num_complex::Complex, ComplexToReal, RealFftPlanner, RealToComplex};
+use rubato::{Resampler as _, SincFixedIn};
+use serde::{Deserialize, Serialize};
+use snafu::{ResultExt, Snafu};
+use symphonia::core::{audio::SampleBuffer, codecs::{DecoderOptions, CODEC_TYPE_NULL}, formats::FormatOptions, io::MediaSourceStream, meta::MetadataOptions, probe::Hint};
+use tracing::{info, instrument};
+
+use crate::audio::{dsp::DspFormat, output::SOURCE_SCALE, resample::sinc_parameters};
+
+/// Frames in each partition of the impulse response. This is also the latency of the convolution.
+const PARTITION_FRAMES: usize = 1024;
+
+/// Impulse responses for each output device. Tracks are convolved as they are decoded, so changes
+/// take effect from the next track that starts streaming.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConvolutionSettings {
+    /// Impulse responses by the id of the device they correct, with at most one for each rate.
+    pub devices: HashMap<String, Vec<ImpulseResponse>>,
+}
+
+impl ConvolutionSettings {
+    /// The impulse response to use with a device at `sample_rate`. One made for that rate is
+    /// preferred, otherwise the one with the closest rate is used and resampled.
+    pub fn impulse_for(&self, device_id: &str, sample_rate: u32) -> Option<&ImpulseResponse> {
+        self.devices.get(device_id)?
+            .iter()
+            .min_by_key(|impulse| impulse.sample_rate.abs_diff(sample_rate))
+    }
+}
+
+/// A WAV impulse response. It has either one channel, used for every output channel, or one
+/// channel for each output channel.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ImpulseResponse {
+    pub path: PathBuf,
+    pub sample_rate: u32,
+}
+
+impl ImpulseResponse {
+    /// Read the sample rate of the WAV file at `path`, without decoding it.
+    #[instrument(err)]
+    pub fn probe(path: &Path) -> Result<Self, ConvolutionError> {
+        let format = open_wav(path)?;
+        let track = format.default_track().ok_or(ConvolutionError::NoAudioTrack)?;
+        let sample_rate = track.codec_params.sample_rate.ok_or(ConvolutionError::NoAudioTrack)?;
+
+        Ok(Self {
+            path: path.to_owned(),
+            sample_rate,
+        })
+    }
+
+    /// Decode the impulse response, with each channel given separately.
+    fn read(&self) -> Result<Vec<Vec<f64>>, ConvolutionError> {
+        let mut format = open_wav(&self.path)?;
+        let track = format.tracks()
+            .iter()
+            .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+            .ok_or(ConvolutionError::NoAudioTrack)?;
+        let track_id = track.id;
+
+        let mut decoder = symphonia::default::get_codecs()
+            .make(&track.codec_params, &DecoderOptions::default())
+            .context(DecodeSnafu)?;
+
+        let mut channels: Vec<Vec<f64>> = Vec::new();
+        let mut sample_buf: Option<SampleBuffer<f64>> = None;
+
+        while let Ok(packet) = format.next_packet() {
+            if packet.track_id() != track_id {
+                continue;
+            }
+
+            let decoded = decoder.decode(&packet).context(DecodeSnafu)?;
+            let spec = *decoded.spec();
+            let buf = sample_buf.get_or_insert_with(|| SampleBuffer::new(decoded.capacity() as u64, spec));
+            buf.copy_interleaved_ref(decoded);
+
+            let count = spec.channels.count();
+            channels.resize_with(count, Vec::new);
+            for frame in buf.samples().chunks_exact(count) {
+                for (channel, sample) in channels.iter_mut().zip(frame) {
+                    channel.push(*sample);
+                }
+            }
+        }
+
+        if channels.first().is_none_or(Vec::is_empty) {
+            return Err(ConvolutionError::NoAudioTrack);
+        }
+
+        Ok(channels)
+    }
+}
+
+fn open_wav(path: &Path) -> Result<Box<dyn symphonia::core::formats::FormatReader>, ConvolutionError> {
+    let file = File::open(path).context(OpenSnafu { path })?;
+    let mss = MediaSourceStream::new(Box::new(file), Default::default());
+
+    let mut hint = Hint::new();
+    hint.with_extension("wav");
+
+    let probed = symphonia::default::get_probe()
+        .format(&hint, mss, &FormatOptions::default(), &MetadataOptions::default())
+        .context(DecodeSnafu)?;
+
+    Ok(probed.format)
+}
+
+/// Resample an impulse response, keeping its gain the same.
+fn resample(channels: &[Vec<f64>], from_rate: u32, to_rate: u32) -> Result<Vec<Vec<f64>>, ConvolutionError> {
+    let ratio = to_rate as f64 / from_rate as f64;
+    let frames = channels[0].len();
+
+    let mut resampler = SincFixedIn::<f64>::new(ratio, 1.0, sinc_parameters(), frames, channels.len())
+        .context(ResamplerConstructionSnafu)?;
+    let length = (frames as f64 * ratio).ceil() as usize;
+
+    let mut resampled = resampler.process(channels, None).context(ResampleSnafu)?;
+    // The filter needs input beyond the end of the response to finish it, so flush until it's all out
+    while resampled[0].len() < length {
+        let tail = resampler.process_partial::<Vec<f64>>(None, None).context(ResampleSnafu)?;
+        for (channel, tail) in resampled.iter_mut().zip(tail) {
+            channel.extend(tail);
+        }
+    }
+
+    // A higher rate spreads the same response over more taps, so each tap is scaled down to match
+    for channel in &mut resampled {
+        channel.truncate(length);
+        channel.iter_mut().for_each(|sample| *sample /= ratio);
+    }
+
+    Ok(resampled)
+}
+
+/// Convolves a track as it is decoded, before it reaches the ring buffer, so the FFTs are never
+/// run in the output callback. The partition delay is cut from the start of the track and flushed
+/// out at the end, so it stays in time and plays gaplessly. Anything the impulse response boosts
+/// over full scale is clipped.
+pub struct StreamConvolution {
+    convolution: Convolution,
+    channels: usize,
+    /// Output frames still to be dropped to make up for the partition delay.
+    delay: usize,
+    /// Frames taken in and given out since the last reset.
+    frames_in: u64,
+    frames_out: u64,
+    buffer: Vec<f64>,
+}
+
+impl StreamConvolution {
+    pub fn new(impulse: &ImpulseResponse, format: DspFormat) -> Result<Self, ConvolutionError> {
+        Ok(Self {
+            convolution: Convolution::new(impulse, format)?,
+            channels: format.channels as usize,
+            delay: PARTITION_FRAMES,
+            frames_in: 0,
+            frames_out: 0,
+            buffer: Vec::new(),
+        })
+    }
+
+    /// Convolve interleaved `samples`. The output is delayed, so it can be shorter than the input.
+    pub fn process(&mut self, samples: &[i32]) -> Vec<i32> {
+        self.frames_in += (samples.len() / self.channels) as u64;
+
+        self.buffer.clear();
+        self.buffer.extend(samples.iter().map(|sample| *sample as f64 / SOURCE_SCALE));
+        self.convolution.process(&mut self.buffer);
+
+        self.output()
+    }
+
+    /// The end of the track, which the partition delay is still holding back.
+    pub fn finish(&mut self) -> Vec<i32> {
+        let pending = self.frames_in.saturating_sub(self.frames_out) as usize;
+
+        self.buffer.clear();
+        self.buffer.resize(PARTITION_FRAMES * self.channels, 0.0);
+        self.convolution.process(&mut self.buffer);
+
+        let mut output = self.output();
+        output.truncate(pending * self.channels);
+        self.frames_out = self.frames_in;
+        output
+    }
+
+    /// Drop the input held by the convolution, after a seek.
+    pub fn reset(&mut self) {
+        self.convolution.reset();
+        self.delay = PARTITION_FRAMES;
+        self.frames_in = 0;
+        self.frames_out = 0;
+    }
+
+    fn output(&mut self) -> Vec<i32> {
+        let delayed = (self.delay * self.channels).min(self.buffer.len());
+        self.delay -= delayed / self.channels;
+
+        let output: Vec<i32> = self.buffer[delayed..].iter()
+            .map(|sample| (sample * SOURCE_SCALE).round().clamp(i32::MIN as f64, i32::MAX as f64) as i32)
+            .collect();
+        self.frames_out += (output.len() / self.channels) as u64;
+        output
+    }
+}
+
+/// Convolves each channel with an impulse response, using uniformly partitioned overlap-save FFT
+/// convolution. Input is gathered into partitions of `PARTITION_FRAMES`, so output is delayed by one
+/// partition.
+struct Convolution {
+    forward: Arc<dyn RealToComplex<f64>>,
+    inverse: Arc<dyn ComplexToReal<f64>>,
+    channels: Vec<ChannelConvolver>,
+    /// Position within the current partition.
+    position: usize,
+    time: Vec<f64>,
+    spectrum: Vec<Complex<f64>>,
+    scratch: Vec<Complex<f64>>,
+}
+
+/// The filter and running state for a single channel.
+struct ChannelConvolver {
+    /// Spectrum of each partition of the impulse response.
+    filter: Arc<Vec<Vec<Complex<f64>>>>,
+    /// Spectra of recent input partitions, newest at `newest`.
+    history: Vec<Vec<Complex<f64>>>,
+    newest: usize,
+    /// The last two partitions of input, for overlap-save.
+    input: Vec<f64>,
+    /// Output for the partition currently being gathered.
+    output: Vec<f64>,
+}
+
+impl Convolution {
+    #[instrument(skip(format), err)]
+    fn new(impulse: &ImpulseResponse, format: DspFormat) -> Result<Self, ConvolutionError> {
+        let mut ir = impulse.read()?;
+        if impulse.sample_rate != format.sample_rate {
+            info!("Resampling impulse response from {} Hz to {} Hz", impulse.sample_rate, format.sample_rate);
+            ir = resample(&ir, impulse.sample_rate, format.sample_rate)?;
+        }
+
+        let output_channels = format.channels as usize;
+        if ir.len() != 1 && ir.len() != output_channels {
+            return Err(ConvolutionError::ChannelMismatch { impulse: ir.len(), track: output_channels });
+        }
+
+        let fft_len = PARTITION_FRAMES * 2;
+        let mut planner = RealFftPlanner::<f64>::new();
+        let forward = planner.plan_fft_forward(fft_len);
+        let inverse = planner.plan_fft_inverse(fft_len);
+
+        let mut time = forward.make_input_vec();
+        let mut scratch = forward.make_scratch_vec();
+        // Fold the inverse FFT's scaling into the filter
+        let scale = 1.0 / fft_len as f64;
+
+        let filters: Vec<_> = ir.iter()
+            .map(|channel| {
+                let partitions: Vec<_> = channel.chunks(PARTITION_FRAMES)
+                    .map(|partition| {
+                        time.fill(0.0);
+                        time.iter_mut().zip(partition).for_each(|(t, sample)| *t = sample * scale);
+                        let mut spectrum = forward.make_output_vec();
+                        forward.process_with_scratch(&mut time, &mut spectrum, &mut scratch)
+                            .expect("FFT buffers are made by the planner");
+                        spectrum
+                    })
+                    .collect();
+                Arc::new(partitions)
+            })
+            .collect();
+
+        let channels = (0..output_channels)
+            .map(|channel| {
+                let filter = filters[channel.min(filters.len() - 1)].clone();
+                ChannelConvolver {
+                    history: vec![forward.make_output_vec(); filter.len()],
+                    newest: 0,
+                    input: vec![0.0; fft_len],
+                    output: vec![0.0; PARTITION_FRAMES],
+                    filter,
+                }
+            })
+            .collect();
+
+        info!("Convolving with {} partitions of {PARTITION_FRAMES} frames", filters[0].len());
+
+        let spectrum = forward.make_output_vec();
+        let scratch_len = forward.get_scratch_len().max(inverse.get_scratch_len());
+
+        Ok(Self {
+            forward,
+            inverse,
+            channels,
+            position: 0,
+            time,
+            spectrum,
+            scratch: vec![Complex::default(); scratch_len],
+        })
+    }
+
+    /// Clear the input and output, as if nothing had been convolved yet.
+    fn reset(&mut self) {
+        self.position = 0;
+        for channel in &mut self.channels {
+            channel.history.iter_mut().for_each(|spectrum| spectrum.fill(Complex::default()));
+            channel.newest = 0;
+            channel.input.fill(0.0);
+            channel.output.fill(0.0);
+        }
+    }
+
+    /// Convolve the partition that has just been gathered, for every channel.
+    fn process_partition(&mut self) {
+        for channel in &mut self.channels {
+            channel.newest = (channel.newest + 1) % channel.history.len();
+            self.time.copy_from_slice(&channel.input);
+            let _ = self.forward.process_with_scratch(&mut self.time, &mut channel.history[channel.newest], &mut self.scratch);
+
+            // Slide the input along by a partition, keeping this one for the next overlap
+            channel.input.copy_within(PARTITION_FRAMES.., 0);
+
+            self.spectrum.fill(Complex::default());
+            for (age, filter) in channel.filter.iter().enumerate() {
+                let index = (channel.newest + channel.history.len() - age) % channel.history.len();
+                for ((out, x), h) in self.spectrum.iter_mut().zip(&channel.history[index]).zip(filter) {
+                    *out += x * h;
+                }
+            }
+
+            if let Some(first) = self.spectrum.first_mut() {
+                first.im = 0.0;
+            }
+            if let Some(last) = self.spectrum.last_mut() {
+                last.im = 0.0;
+            }
+            let _ = self.inverse.process_with_scratch(&mut self.spectrum, &mut self.time, &mut self.scratch);
+
+            // The first half is wrapped around by the circular convolution, so only the second is kept
+            channel.output.copy_from_slice(&self.time[PARTITION_FRAMES..]);
+        }
+    }
+
+    /// Convolve interleaved samples in place, normalised so full scale is 1.0.
+    fn process(&mut self, samples: &mut [f64]) {
+        let channels = self.channels.len();
+
+        for frame in samples.chunks_exact_mut(channels) {
+            for (sample, channel) in frame.iter_mut().zip(&mut self.channels) {
+                channel.input[PARTITION_FRAMES + self.position] = *sample;
+                *sample = channel.output[self.position];
+            }
+
+            self.position += 1;
+            if self.position == PARTITION_FRAMES {
+                self.position = 0;
+                self.process_partition();
+            }
+        }
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum ConvolutionError {
+    #[snafu(display("Failed to open impulse response {}", path.display()))]
+    Open {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+    #[snafu(display("Failed to decode impulse response"))]
+    Decode {
+        source: symphonia::core::errors::Error,
+    },
+    #[snafu(display("Impulse response has no audio"))]
+    NoAudioTrack,
+    #[snafu(display("Impulse response has {impulse} channels, but the track has {track}"))]
+    ChannelMismatch {
+        impulse: usize,
+        track: usize,
+    },
+    #[snafu(display("Failed to create resampler for impulse response"))]
+    ResamplerConstruction {
+        source: rubato::ResamplerConstructionError,
+    },
+    #[snafu(display("Failed to resample impulse response"))]
+    Resample {
+        source: rubato::ResampleError,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    /// Write a mono 16-bit WAV impulse response.
+    fn impulse_file(samples: &[f64], sample_rate: u32) -> tempfile::NamedTempFile {
+        let data: Vec<u8> = samples.iter().flat_map(|sample| ((sample * 32_768.0) as i16).to_le_bytes()).collect();
+
+        let mut wav = Vec::new();
+        wav.extend(b"RIFF");
+        wav.extend((36 + data.len() as u32).to_le_bytes());
+        wav.extend(b"WAVEfmt ");
+        wav.extend(16u32.to_le_bytes());
+        wav.extend(1u16.to_le_bytes());
+        wav.extend(1u16.to_le_bytes());
+        wav.extend(sample_rate.to_le_bytes());
+        wav.extend((sample_rate * 2).to_le_bytes());
+        wav.extend(2u16.to_le_bytes());
+        wav.extend(16u16.to_le_bytes());
+        wav.extend(b"data");
+        wav.extend((data.len() as u32).to_le_bytes());
+        wav.extend(data);
+
+        let mut file = tempfile::Builder::new().suffix(".wav").tempfile().unwrap();
+        file.write_all(&wav).unwrap();
+        file
+    }
+
+    fn impulse(file: &tempfile::NamedTempFile) -> ImpulseResponse {
+        ImpulseResponse::probe(file.path()).unwrap()
+    }
+
+    /// Convolve `samples` in uneven chunks, and flush the end of the stream.
+    fn convolve(convolution: &mut StreamConvolution, samples: &[i32], channels: usize) -> Vec<f64> {
+        let mut output = Vec::new();
+        for chunk in samples.chunks(700 * channels) {
+            output.extend(convolution.process(chunk));
+        }
+        output.extend(convolution.finish());
+        output.into_iter().map(|sample| sample as f64 / SOURCE_SCALE).collect()
+    }
+
+    #[test]
+    fn impulse_gives_back_the_response() {
+        let mut response = vec![0.0; 2_500];
+        response[0] = 0.5;
+        response[2] = -0.25;
+        // In a later partition, to check the partitions line up
+        response[2_100] = 0.125;
+        let file = impulse_file(&response, 48_000);
+
+        let format = DspFormat { sample_rate: 48_000, channels: 2 };
+        let mut convolution = StreamConvolution::new(&impulse(&file), format).unwrap();
+        let mut input = vec![0; 3_000 * 2];
+        // An impulse on the left, and half of one on the right
+        input[0] = (0.5 * SOURCE_SCALE) as i32;
+        input[1] = (0.25 * SOURCE_SCALE) as i32;
+        let output = convolve(&mut convolution, &input, 2);
+
+        assert_eq!(output.len(), input.len());
+        for (frame, samples) in output.chunks_exact(2).enumerate() {
+            let expected = response.get(frame).copied().unwrap_or(0.0);
+            assert!((samples[0] - expected * 0.5).abs() < 1e-6, "left frame {frame} was {}", samples[0]);
+            assert!((samples[1] - expected * 0.25).abs() < 1e-6, "right frame {frame} was {}", samples[1]);
+        }
+    }
+
+    #[test]
+    fn reset_starts_afresh() {
+        let file = impulse_file(&[0.5, 0.25, 0.125], 44_100);
+        let format = DspFormat { sample_rate: 44_100, channels: 1 };
+        let mut convolution = StreamConvolution::new(&impulse(&file), format).unwrap();
+        let input: Vec<i32> = (0..5_000).map(|index| ((index as f64 * 0.01).sin() * 0.5 * SOURCE_SCALE) as i32).collect();
+
+        let first = convolve(&mut convolution, &input, 1);
+        convolution.reset();
+        let second = convolve(&mut convolution, &input, 1);
+
+        assert_eq!(first.len(), input.len());
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn resampled_response_keeps_its_timing_and_gain() {
+        let mut response = vec![0.0; 1_000];
+        response[100] = 1.0;
+
+        let resampled = resample(&[response], 44_100, 48_000).unwrap();
+
+        let expected_len = (1_000.0 * 48_000.0 / 44_100.0_f64).ceil() as usize;
+        assert_eq!(resampled[0].len(), expected_len);
+        let peak = (0..expected_len).max_by(|a, b| resampled[0][*a].total_cmp(&resampled[0][*b])).unwrap();
+        assert!(peak.abs_diff(109) <= 1, "impulse moved to {peak}");
+        // The response's gain at low frequencies stays the same
+        let sum: f64 = resampled[0].iter().sum();
+        assert!((sum - 1.0).abs() < 0.01, "gain was {sum}");
+    }
+
+    #[test]
+    fn mono_response_is_used_for_every_channel() {
+        let file = impulse_file(&[1.0], 48_000);
+        let impulse = impulse(&file);
+
+        assert!(StreamConvolution::new(&impulse, DspFormat { sample_rate: 48_000, channels: 6 }).is_ok());
+    }
+}
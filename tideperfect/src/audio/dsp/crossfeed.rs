@@ -0,0 +1,95 @@
+use std::f64::consts::PI;
+
+use serde::{Deserialize, Serialize};
+
+use crate::audio::{dsp::{DspFormat, DspStage}, replay_gain::db_to_gain};
+
+pub const MIN_CROSSFEED_CUTOFF_HZ: u32 = 300;
+pub const MAX_CROSSFEED_CUTOFF_HZ: u32 = 2000;
+pub const MIN_CROSSFEED_FEED_DB: f64 = 1.0;
+pub const MAX_CROSSFEED_FEED_DB: f64 = 15.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CrossfeedSettings {
+    /// Cutoff of the low-pass filter on the signal fed to the opposite ear, in Hz.
+    pub cutoff_hz: u32,
+    /// How much quieter the crossfed signal is than the direct one at low frequencies, in dB.
+    /// Lower values give a stronger effect.
+    pub feed_db: f64,
+}
+
+impl Default for CrossfeedSettings {
+    /// The bs2b default, 700 Hz with 4.5 dB of feed.
+    fn default() -> Self {
+        Self {
+            cutoff_hz: 700,
+            feed_db: 4.5,
+        }
+    }
+}
+
+/// Headphone crossfeed after Boris Mikhaylov's bs2b. Each ear gets a low-passed copy of
+/// the other channel, as it would from speakers, while the direct signal is given a gentle high
+/// shelf so the overall tonal balance stays the same.
+pub struct Crossfeed {
+    lo_a0: f64,
+    lo_b1: f64,
+    hi_a0: f64,
+    hi_a1: f64,
+    hi_b1: f64,
+    gain: f64,
+    /// Last input, low-passed and high-boosted samples, for the left and right channels.
+    last: [f64; 2],
+    lo: [f64; 2],
+    hi: [f64; 2],
+}
+
+impl Crossfeed {
+    /// Build the crossfeed for `format`, or `None` if it isn't stereo.
+    pub fn new(settings: &CrossfeedSettings, format: DspFormat) -> Option<Self> {
+        if format.channels != 2 {
+            return None;
+        }
+
+        let cutoff = settings.cutoff_hz.clamp(MIN_CROSSFEED_CUTOFF_HZ, MAX_CROSSFEED_CUTOFF_HZ) as f64;
+        let feed_db = settings.feed_db.clamp(MIN_CROSSFEED_FEED_DB, MAX_CROSSFEED_FEED_DB);
+        let sample_rate = format.sample_rate as f64;
+
+        let lo_gain_db = feed_db * -5.0 / 6.0 - 3.0;
+        let hi_gain_db = feed_db / 6.0 - 3.0;
+        let lo_gain = db_to_gain(lo_gain_db);
+        let hi_gain = 1.0 - db_to_gain(hi_gain_db);
+        let hi_cutoff = cutoff * 2f64.powf((lo_gain_db - 20.0 * hi_gain.log10()) / 12.0);
+
+        let lo_x = (-2.0 * PI * cutoff / sample_rate).exp();
+        let hi_x = (-2.0 * PI * hi_cutoff / sample_rate).exp();
+
+        Some(Self {
+            lo_a0: lo_gain * (1.0 - lo_x),
+            lo_b1: lo_x,
+            hi_a0: 1.0 - hi_gain * (1.0 - hi_x),
+            hi_a1: -hi_x,
+            hi_b1: hi_x,
+            gain: 1.0 / (1.0 - hi_gain + lo_gain),
+            last: [0.0; 2],
+            lo: [0.0; 2],
+            hi: [0.0; 2],
+        })
+    }
+}
+
+impl DspStage for Crossfeed {
+    fn process(&mut self, samples: &mut [f64]) {
+        for frame in samples.chunks_exact_mut(2) {
+            for (channel, input) in frame.iter().enumerate() {
+                self.lo[channel] = self.lo_a0 * input + self.lo_b1 * self.lo[channel];
+                self.hi[channel] = self.hi_a0 * input + self.hi_a1 * self.last[channel] + self.hi_b1 * self.hi[channel];
+                self.last[channel] = *input;
+            }
+
+            frame[0] = (self.hi[0] + self.lo[1]) * self.gain;
+            frame[1] = (self.hi[1] + self.lo[0]) * self.gain;
+        }
+    }
+}
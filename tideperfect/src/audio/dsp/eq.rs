@@ -0,0 +1,346 @@
+use std::{collections::{BTreeMap, HashMap}, f64::consts::PI};
+
+use serde::{Deserialize, Serialize};
+use snafu::Snafu;
+
+use crate::audio::{dsp::{DspFormat, DspStage}, replay_gain::db_to_gain};
+
+/// Q used for shelves that don't give one, for a slope without overshoot.
+const DEFAULT_SHELF_Q: f64 = std::f64::consts::FRAC_1_SQRT_2;
+
+/// Number of points the response is measured at when working out the headroom a preset needs.
+const RESPONSE_POINTS: usize = 512;
+
+/// Named EQ presets, and which preset each output device uses.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EqSettings {
+    pub presets: BTreeMap<String, EqPreset>,
+    /// Preset names, by the id of the device they are used with.
+    pub devices: HashMap<String, String>,
+}
+
+impl EqSettings {
+    /// The preset used with the device with `device_id`, if it has one.
+    pub fn preset_for(&self, device_id: &str) -> Option<&EqPreset> {
+        self.devices.get(device_id).and_then(|name| self.presets.get(name))
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EqPreset {
+    /// Gain applied ahead of the filters in dB. When `None`, the preset is given just enough
+    /// headroom that its boosts can't clip.
+    pub preamp_db: Option<f64>,
+    pub bands: Vec<EqBand>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct EqBand {
+    pub filter: EqFilter,
+    /// Centre or corner frequency in Hz.
+    pub frequency: f64,
+    pub gain_db: f64,
+    pub q: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EqFilter {
+    Peaking,
+    LowShelf,
+    HighShelf,
+}
+
+impl EqPreset {
+    /// Parse a preset in the EqualizerAPO format used by AutoEQ's `ParametricEQ.txt` files, such as:
+    ///
+    /// ```text
+    /// Preamp: -6.2 dB
+    /// Filter 1: ON LSC Fc 105 Hz Gain 5.5 dB Q 0.70
+    /// Filter 2: ON PK Fc 2470 Hz Gain -3.1 dB Q 1.84
+    /// ```
+    pub fn parse_parametric_eq(text: &str) -> Result<Self, EqParseError> {
+        let mut preset = Self::default();
+
+        for (index, line) in text.lines().enumerate() {
+            let line_number = index + 1;
+            let line = line.trim();
+
+            if let Some(preamp) = line.strip_prefix("Preamp:") {
+                let preamp = preamp.trim().trim_end_matches("dB").trim();
+                preset.preamp_db = Some(preamp.parse().map_err(|_| EqParseError::InvalidValue { line: line_number, value: preamp.to_owned() })?);
+            } else if line.starts_with("Filter")
+                && let Some((_, filter)) = line.split_once(':')
+                && let Some(band) = parse_filter(filter, line_number)?
+            {
+                preset.bands.push(band);
+            }
+        }
+
+        Ok(preset)
+    }
+
+    /// The preamp in dB, working out the headroom needed if the preset doesn't give one.
+    fn preamp_db(&self, sample_rate: u32) -> f64 {
+        if let Some(preamp_db) = self.preamp_db {
+            return preamp_db;
+        }
+
+        let coefficients: Vec<_> = self.bands.iter().map(|band| Coefficients::new(band, sample_rate)).collect();
+        let nyquist = sample_rate as f64 / 2.0;
+        let top = nyquist.min(20_000.0);
+
+        let peak_db = (0..RESPONSE_POINTS)
+            .map(|i| 20.0 * (top / 20.0).powf(i as f64 / (RESPONSE_POINTS - 1) as f64))
+            .map(|frequency| coefficients.iter().map(|c| c.response_db(frequency, sample_rate)).sum::<f64>())
+            .fold(0.0, f64::max);
+
+        -peak_db
+    }
+}
+
+/// Parse the part of a `Filter` line after the colon. Returns `None` for filters that are off.
+fn parse_filter(filter: &str, line: usize) -> Result<Option<EqBand>, EqParseError> {
+    let mut tokens = filter.split_whitespace();
+
+    match tokens.next() {
+        Some("ON") => {}
+        Some("OFF") => return Ok(None),
+        _ => return Err(EqParseError::InvalidFilter { line }),
+    }
+
+    let filter = match tokens.next() {
+        Some("PK" | "PEQ") => EqFilter::Peaking,
+        Some("LS" | "LSC") => EqFilter::LowShelf,
+        Some("HS" | "HSC") => EqFilter::HighShelf,
+        Some(filter) => return Err(EqParseError::UnsupportedFilter { line, filter: filter.to_owned() }),
+        None => return Err(EqParseError::InvalidFilter { line }),
+    };
+
+    let (mut frequency, mut gain_db, mut q) = (None, None, None);
+    while let Some(key) = tokens.next() {
+        let field = match key {
+            "Fc" => &mut frequency,
+            "Gain" => &mut gain_db,
+            "Q" => &mut q,
+            // Units, and parameters we don't use
+            _ => continue,
+        };
+
+        let value = tokens.next().ok_or(EqParseError::InvalidFilter { line })?;
+        *field = Some(value.parse().map_err(|_| EqParseError::InvalidValue { line, value: value.to_owned() })?);
+    }
+
+    let q = match (filter, q) {
+        (_, Some(q)) => q,
+        (EqFilter::LowShelf | EqFilter::HighShelf, None) => DEFAULT_SHELF_Q,
+        (EqFilter::Peaking, None) => return Err(EqParseError::MissingValue { line, value: "Q" }),
+    };
+
+    Ok(Some(EqBand {
+        filter,
+        frequency: frequency.ok_or(EqParseError::MissingValue { line, value: "Fc" })?,
+        gain_db: gain_db.ok_or(EqParseError::MissingValue { line, value: "Gain" })?,
+        q,
+    }))
+}
+
+/// Normalised biquad coefficients, from the Audio EQ Cookbook.
+#[derive(Debug, Clone, Copy)]
+struct Coefficients {
+    b0: f64,
+    b1: f64,
+    b2: f64,
+    a1: f64,
+    a2: f64,
+}
+
+impl Coefficients {
+    fn new(band: &EqBand, sample_rate: u32) -> Self {
+        // Keep the frequency below Nyquist, so bands meant for higher rates still give a stable filter
+        let frequency = band.frequency.clamp(1.0, sample_rate as f64 * 0.49);
+        let a = 10f64.powf(band.gain_db / 40.0);
+        let w0 = 2.0 * PI * frequency / sample_rate as f64;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * band.q.max(0.01));
+        let shelf = 2.0 * a.sqrt() * alpha;
+
+        let (b0, b1, b2, a0, a1, a2) = match band.filter {
+            EqFilter::Peaking => (
+                1.0 + alpha * a,
+                -2.0 * cos,
+                1.0 - alpha * a,
+                1.0 + alpha / a,
+                -2.0 * cos,
+                1.0 - alpha / a,
+            ),
+            EqFilter::LowShelf => (
+                a * ((a + 1.0) - (a - 1.0) * cos + shelf),
+                2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                a * ((a + 1.0) - (a - 1.0) * cos - shelf),
+                (a + 1.0) + (a - 1.0) * cos + shelf,
+                -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                (a + 1.0) + (a - 1.0) * cos - shelf,
+            ),
+            EqFilter::HighShelf => (
+                a * ((a + 1.0) + (a - 1.0) * cos + shelf),
+                -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                a * ((a + 1.0) + (a - 1.0) * cos - shelf),
+                (a + 1.0) - (a - 1.0) * cos + shelf,
+                2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                (a + 1.0) - (a - 1.0) * cos - shelf,
+            ),
+        };
+
+        Self {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+        }
+    }
+
+    /// The filter's gain in dB at `frequency`.
+    fn response_db(&self, frequency: f64, sample_rate: u32) -> f64 {
+        let w = 2.0 * PI * frequency / sample_rate as f64;
+        let (sin1, cos1) = w.sin_cos();
+        let (sin2, cos2) = (2.0 * w).sin_cos();
+
+        let numerator = (self.b0 + self.b1 * cos1 + self.b2 * cos2).powi(2) + (self.b1 * sin1 + self.b2 * sin2).powi(2);
+        let denominator = (1.0 + self.a1 * cos1 + self.a2 * cos2).powi(2) + (self.a1 * sin1 + self.a2 * sin2).powi(2);
+
+        10.0 * (numerator / denominator).log10()
+    }
+}
+
+/// A parametric EQ, built from a preset. Each band is a biquad, run in transposed direct form II.
+pub struct Eq {
+    preamp: f64,
+    bands: Vec<Coefficients>,
+    channels: usize,
+    /// Filter state for each band and channel, indexed by `band * channels + channel`.
+    state: Vec<[f64; 2]>,
+}
+
+impl Eq {
+    pub fn new(preset: &EqPreset, format: DspFormat) -> Self {
+        let channels = format.channels as usize;
+        let bands: Vec<_> = preset.bands.iter().map(|band| Coefficients::new(band, format.sample_rate)).collect();
+
+        Self {
+            preamp: db_to_gain(preset.preamp_db(format.sample_rate)),
+            state: vec![[0.0; 2]; bands.len() * channels],
+            bands,
+            channels,
+        }
+    }
+}
+
+impl DspStage for Eq {
+    fn process(&mut self, samples: &mut [f64]) {
+        for frame in samples.chunks_exact_mut(self.channels) {
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                let mut x = *sample * self.preamp;
+
+                for (band, c) in self.bands.iter().enumerate() {
+                    let state = &mut self.state[band * self.channels + channel];
+                    let y = c.b0 * x + state[0];
+                    state[0] = c.b1 * x - c.a1 * y + state[1];
+                    state[1] = c.b2 * x - c.a2 * y;
+                    x = y;
+                }
+
+                *sample = x;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum EqParseError {
+    #[snafu(display("Line {line}: expected a filter like 'ON PK Fc 100 Hz Gain -3 dB Q 1.0'"))]
+    InvalidFilter {
+        line: usize,
+    },
+    #[snafu(display("Line {line}: unsupported filter type '{filter}'"))]
+    UnsupportedFilter {
+        line: usize,
+        filter: String,
+    },
+    #[snafu(display("Line {line}: '{value}' is not a number"))]
+    InvalidValue {
+        line: usize,
+        value: String,
+    },
+    #[snafu(display("Line {line}: filter has no {value}"))]
+    MissingValue {
+        line: usize,
+        value: &'static str,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_autoeq_preset() {
+        let preset = EqPreset::parse_parametric_eq("\
+Preamp: -6.2 dB
+Filter 1: ON LSC Fc 105 Hz Gain 5.5 dB Q 0.70
+Filter 2: ON PK Fc 2470 Hz Gain -3.1 dB Q 1.84
+Filter 3: ON HSC Fc 10000 Hz Gain 2.0 dB Q 0.70
+").unwrap();
+
+        assert_eq!(preset, EqPreset {
+            preamp_db: Some(-6.2),
+            bands: vec![
+                EqBand { filter: EqFilter::LowShelf, frequency: 105.0, gain_db: 5.5, q: 0.7 },
+                EqBand { filter: EqFilter::Peaking, frequency: 2470.0, gain_db: -3.1, q: 1.84 },
+                EqBand { filter: EqFilter::HighShelf, frequency: 10000.0, gain_db: 2.0, q: 0.7 },
+            ],
+        });
+    }
+
+    #[test]
+    fn shelf_without_q_uses_default() {
+        let preset = EqPreset::parse_parametric_eq("Filter: ON LS Fc 80 Hz Gain 4 dB").unwrap();
+
+        assert_eq!(preset.preamp_db, None);
+        assert_eq!(preset.bands, [EqBand { filter: EqFilter::LowShelf, frequency: 80.0, gain_db: 4.0, q: DEFAULT_SHELF_Q }]);
+    }
+
+    #[test]
+    fn skips_disabled_filters() {
+        let preset = EqPreset::parse_parametric_eq("\
+Filter 1: OFF PK Fc 100 Hz Gain 3 dB Q 1.0
+Filter 2: ON PK Fc 200 Hz Gain -2 dB Q 1.5
+").unwrap();
+
+        assert_eq!(preset.bands, [EqBand { filter: EqFilter::Peaking, frequency: 200.0, gain_db: -2.0, q: 1.5 }]);
+    }
+
+    #[test]
+    fn peaking_filter_needs_q() {
+        let error = EqPreset::parse_parametric_eq("Filter 1: ON PK Fc 100 Hz Gain 3 dB").unwrap_err();
+
+        assert!(matches!(error, EqParseError::MissingValue { line: 1, value: "Q" }));
+    }
+
+    #[test]
+    fn rejects_unsupported_filter() {
+        let error = EqPreset::parse_parametric_eq("Preamp: -1 dB\nFilter 1: ON BP Fc 100 Hz Q 1.0").unwrap_err();
+
+        assert!(matches!(error, EqParseError::UnsupportedFilter { line: 2, ref filter } if filter == "BP"));
+    }
+
+    #[test]
+    fn derived_preamp_leaves_headroom_for_boosts() {
+        let preset = EqPreset::parse_parametric_eq("Filter 1: ON PK Fc 1000 Hz Gain 6 dB Q 1.0").unwrap();
+
+        let preamp_db = preset.preamp_db(48_000);
+        assert!((preamp_db + 6.0).abs() < 0.1, "preamp was {preamp_db}");
+    }
+}
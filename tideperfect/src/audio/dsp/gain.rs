@@ -0,0 +1,28 @@
+use serde::{Deserialize, Serialize};
+
+use crate::audio::{dsp::DspStage, replay_gain::db_to_gain};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GainSettings {
+    pub gain_db: f64,
+}
+
+/// Applies a fixed gain, such as a pre-amp ahead of an EQ.
+pub struct Gain {
+    gain: f64,
+}
+
+impl Gain {
+    pub fn new(settings: &GainSettings) -> Self {
+        Self { gain: db_to_gain(settings.gain_db) }
+    }
+}
+
+impl DspStage for Gain {
+    fn process(&mut self, samples: &mut [f64]) {
+        for sample in samples {
+            *sample *= self.gain;
+        }
+    }
+}
@@ -0,0 +1,233 @@
+use std::{collections::VecDeque, f64::consts::PI};
+
+use serde::{Deserialize, Serialize};
+
+use crate::audio::{dsp::{DspFormat, DspStage}, replay_gain::db_to_gain};
+
+/// How far ahead the limiter looks for peaks, so it can turn the gain down smoothly before them.
+const LOOKAHEAD_MS: f64 = 5.0;
+
+/// Taps of the interpolator used to find peaks between samples.
+const TAPS: usize = 12;
+
+/// Oversampling factor for true-peak detection.
+const OVERSAMPLING: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LimiterSettings {
+    /// The highest true peak let through, in dBTP.
+    pub ceiling_db: f64,
+    /// How long the gain takes to recover after a peak, in milliseconds.
+    pub release_ms: f64,
+}
+
+impl Default for LimiterSettings {
+    fn default() -> Self {
+        Self {
+            ceiling_db: -1.0,
+            release_ms: 100.0,
+        }
+    }
+}
+
+/// A look-ahead true-peak limiter. Peaks between samples are found by oversampling, and the gain is
+/// brought down over the look-ahead window so it reaches the level needed just as the peak arrives.
+pub struct Limiter {
+    ceiling: f64,
+    release: f64,
+    channels: usize,
+    lookahead: usize,
+    /// Interpolator coefficients for each oversampled phase between two samples.
+    phases: [[f64; TAPS]; OVERSAMPLING - 1],
+    /// The last `TAPS` input samples for each channel, oldest first.
+    history: Vec<[f64; TAPS]>,
+    /// Peak of the previous interval between samples.
+    last_interval_peak: f64,
+    /// Indices and values of the lowest required gains in the look-ahead window, in increasing order
+    /// of value from the front.
+    window: VecDeque<(u64, f64)>,
+    envelope: f64,
+    /// The last `lookahead` envelope values, averaged to smooth the gain.
+    smoothing: Vec<f64>,
+    smoothing_sum: f64,
+    /// Input waiting to have the gain applied, in frames.
+    delay: Vec<f64>,
+    /// Index of the frame being processed.
+    frame: u64,
+}
+
+impl Limiter {
+    pub fn new(settings: &LimiterSettings, format: DspFormat) -> Self {
+        let channels = format.channels as usize;
+        let sample_rate = format.sample_rate as f64;
+        let lookahead = ((LOOKAHEAD_MS / 1000.0 * sample_rate) as usize).max(1);
+        let release_frames = (settings.release_ms.max(1.0) / 1000.0 * sample_rate).max(1.0);
+
+        let mut phases = [[0.0; TAPS]; OVERSAMPLING - 1];
+        for (phase, coefficients) in phases.iter_mut().enumerate() {
+            let offset = (phase + 1) as f64 / OVERSAMPLING as f64;
+            for (tap, coefficient) in coefficients.iter_mut().enumerate() {
+                let x = (TAPS / 2 - 1) as f64 - tap as f64 + offset;
+                let window = 0.5 * (1.0 + (PI * x / (TAPS / 2) as f64).cos());
+                *coefficient = sinc(x) * window;
+            }
+        }
+
+        Self {
+            ceiling: db_to_gain(settings.ceiling_db.min(0.0)),
+            release: 1.0 - (-1.0 / release_frames).exp(),
+            channels,
+            lookahead,
+            phases,
+            history: vec![[0.0; TAPS]; channels],
+            last_interval_peak: 0.0,
+            window: VecDeque::with_capacity(lookahead + 1),
+            envelope: 1.0,
+            smoothing: vec![1.0; lookahead],
+            smoothing_sum: lookahead as f64,
+            delay: vec![0.0; (TAPS / 2 + lookahead - 1).max(1) * channels],
+            frame: 0,
+        }
+    }
+
+    /// Add a frame to the interpolator, returning the true peak of the interval between the middle
+    /// two samples in its history.
+    fn interval_peak(&mut self, frame: &[f64]) -> f64 {
+        let mut peak: f64 = 0.0;
+
+        for (history, sample) in self.history.iter_mut().zip(frame) {
+            history.copy_within(1.., 0);
+            history[TAPS - 1] = *sample;
+
+            peak = peak.max(history[TAPS / 2 - 1].abs());
+            for coefficients in &self.phases {
+                let interpolated: f64 = coefficients.iter().zip(history.iter()).map(|(c, x)| c * x).sum();
+                peak = peak.max(interpolated.abs());
+            }
+        }
+
+        peak
+    }
+}
+
+impl DspStage for Limiter {
+    fn process(&mut self, samples: &mut [f64]) {
+        let channels = self.channels;
+        let delay_frames = self.delay.len() / channels;
+
+        for frame in samples.chunks_exact_mut(channels) {
+            // A sample is affected by the overshoot on either side of it
+            let interval_peak = self.interval_peak(frame);
+            let peak = interval_peak.max(self.last_interval_peak);
+            self.last_interval_peak = interval_peak;
+            let required = if peak > self.ceiling { self.ceiling / peak } else { 1.0 };
+
+            // Hold the lowest gain needed anywhere in the look-ahead window
+            while self.window.back().is_some_and(|(_, gain)| *gain >= required) {
+                self.window.pop_back();
+            }
+            self.window.push_back((self.frame, required));
+            while self.window.front().is_some_and(|(index, _)| *index + (self.lookahead as u64) <= self.frame) {
+                self.window.pop_front();
+            }
+            let held = self.window.front().map_or(1.0, |(_, gain)| *gain);
+
+            self.envelope = held.min(self.envelope + (1.0 - self.envelope) * self.release);
+
+            // Averaging over the window ramps the gain down smoothly, and still reaches the held
+            // level by the time the peak comes out of the delay
+            let slot = (self.frame % self.lookahead as u64) as usize;
+            self.smoothing_sum += self.envelope - self.smoothing[slot];
+            self.smoothing[slot] = self.envelope;
+            let gain = (self.smoothing_sum / self.lookahead as f64).min(1.0);
+
+            let offset = (self.frame % delay_frames as u64) as usize * channels;
+            for (sample, delayed) in frame.iter_mut().zip(&mut self.delay[offset..offset + channels]) {
+                let input = *sample;
+                *sample = *delayed * gain;
+                *delayed = input;
+            }
+
+            self.frame += 1;
+        }
+    }
+
+    fn latency(&self) -> usize {
+        self.delay.len() / self.channels
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORMAT: DspFormat = DspFormat { sample_rate: 48_000, channels: 2 };
+
+    /// Interleaved stereo sine wave with a peak of `amplitude`.
+    fn sine(frames: usize, frequency: f64, amplitude: f64) -> Vec<f64> {
+        (0..frames)
+            .flat_map(|frame| {
+                let sample = (2.0 * PI * frequency * frame as f64 / FORMAT.sample_rate as f64).sin() * amplitude;
+                [sample, -sample]
+            })
+            .collect()
+    }
+
+    /// Run `samples` through the limiter in blocks the size of an output callback.
+    fn limit(limiter: &mut Limiter, mut samples: Vec<f64>) -> Vec<f64> {
+        for block in samples.chunks_mut(512 * 2) {
+            limiter.process(block);
+        }
+        samples
+    }
+
+    #[test]
+    fn never_exceeds_ceiling() {
+        let settings = LimiterSettings::default();
+        let ceiling = db_to_gain(settings.ceiling_db);
+
+        for (frequency, amplitude) in [(997.0, 2.0), (100.0, 4.0), (11_025.0, 1.5)] {
+            let mut limiter = Limiter::new(&settings, FORMAT);
+            let output = limit(&mut limiter, sine(48_000, frequency, amplitude));
+
+            let peak = output.iter().fold(0.0, |peak: f64, sample| peak.max(sample.abs()));
+            assert!(peak <= ceiling + 1e-9, "{frequency} Hz at {amplitude} peaked at {peak}, over {ceiling}");
+        }
+    }
+
+    #[test]
+    fn sudden_peak_is_caught() {
+        let settings = LimiterSettings { ceiling_db: -3.0, ..Default::default() };
+        let ceiling = db_to_gain(settings.ceiling_db);
+        let mut input = sine(4_800, 440.0, 0.1);
+        input.extend(sine(4_800, 440.0, 8.0));
+
+        let mut limiter = Limiter::new(&settings, FORMAT);
+        let output = limit(&mut limiter, input);
+
+        let peak = output.iter().fold(0.0, |peak: f64, sample| peak.max(sample.abs()));
+        assert!(peak <= ceiling + 1e-9, "peaked at {peak}, over {ceiling}");
+    }
+
+    #[test]
+    fn quiet_signal_is_only_delayed() {
+        let input = sine(9_600, 440.0, 0.25);
+        let mut limiter = Limiter::new(&LimiterSettings::default(), FORMAT);
+        let latency = limiter.latency() * FORMAT.channels as usize;
+        let output = limit(&mut limiter, input.clone());
+
+        assert!(output[..latency].iter().all(|sample| *sample == 0.0));
+        for (out, input) in output[latency..].iter().zip(&input) {
+            assert!((out - input).abs() < 1e-12);
+        }
+    }
+}
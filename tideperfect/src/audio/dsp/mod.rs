@@ -0,0 +1,144 @@
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::audio::{dsp::{crossfeed::{Crossfeed, CrossfeedSettings}, eq::Eq, gain::{Gain, GainSettings}, limiter::{Limiter, LimiterSettings}}, settings::PlayerSettings};
+
+pub mod convolution;
+pub mod crossfeed;
+pub mod eq;
+pub mod gain;
+pub mod limiter;
+
+/// The format of the samples passed through the DSP chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DspFormat {
+    pub sample_rate: u32,
+    pub channels: u16,
+}
+
+/// A stage in the DSP chain. Stages are built for a particular format, and rebuilt if it changes.
+pub trait DspStage: Send {
+    /// Process interleaved samples in place. Samples are normalised so full scale is 1.0, and may
+    /// go over it until they are quantised for the output. Buffers always hold whole frames.
+    fn process(&mut self, samples: &mut [f64]);
+
+    /// How many frames the stage delays its output by.
+    fn latency(&self) -> usize {
+        0
+    }
+}
+
+/// DSP settings, persisted as part of the player settings.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DspSettings {
+    /// The stages, in the order samples pass through them.
+    pub stages: Vec<DspStageSettings>,
+}
+
+impl Default for DspSettings {
+    fn default() -> Self {
+        Self {
+            stages: vec![
+                DspStageSettings { enabled: true, config: DspStageConfig::Convolution },
+                DspStageSettings { enabled: true, config: DspStageConfig::Eq },
+                DspStageSettings { enabled: false, config: DspStageConfig::Crossfeed(CrossfeedSettings::default()) },
+                DspStageSettings { enabled: true, config: DspStageConfig::Limiter(LimiterSettings::default()) },
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DspStageSettings {
+    pub enabled: bool,
+    pub config: DspStageConfig,
+}
+
+/// The parameters of a stage, for each kind of stage.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum DspStageConfig {
+    /// Convolution with the impulse response loaded for the output device. Tracks are convolved
+    /// as they are decoded rather than in the output callback, so this always comes first.
+    Convolution,
+    /// The parametric EQ, using the preset chosen for the output device.
+    Eq,
+    Gain(GainSettings),
+    /// Headphone crossfeed. Only applies to stereo output.
+    Crossfeed(CrossfeedSettings),
+    /// True-peak limiter, to stop boosts from earlier stages clipping. It is skipped if no other
+    /// stage is active, so unprocessed playback is left untouched.
+    Limiter(LimiterSettings),
+}
+
+impl DspStageConfig {
+    /// Build the stage for the device with `device_id`, or `None` if it would have nothing to do.
+    fn build(&self, settings: &PlayerSettings, format: DspFormat, device_id: &str) -> Option<Box<dyn DspStage>> {
+        match self {
+            // Applied by the streaming task, before samples reach the output
+            Self::Convolution => None,
+            Self::Eq => settings.eq.preset_for(device_id).map(|preset| Box::new(Eq::new(preset, format)) as Box<dyn DspStage>),
+            Self::Gain(settings) => Some(Box::new(Gain::new(settings))),
+            Self::Crossfeed(settings) => Crossfeed::new(settings, format).map(|stage| Box::new(stage) as Box<dyn DspStage>),
+            Self::Limiter(settings) => Some(Box::new(Limiter::new(settings, format))),
+        }
+    }
+}
+
+/// The stages samples are passed through between the sources and the output, built from the
+/// settings for the output's format.
+pub struct DspChain {
+    stages: Vec<Box<dyn DspStage>>,
+}
+
+impl DspChain {
+    /// Build the enabled stages in `settings`, for the device with `device_id`. When playback is
+    /// bit-perfect, the chain is left empty so it can never alter samples.
+    pub fn new(settings: &PlayerSettings, format: DspFormat, device_id: &str) -> Self {
+        if settings.is_bit_perfect() {
+            return Self::empty();
+        }
+
+        let stages: Vec<_> = settings.dsp.stages.iter()
+            .filter(|stage| stage.enabled)
+            .filter_map(|stage| Some((&stage.config, stage.config.build(settings, format, device_id)?)))
+            .collect();
+
+        // There's nothing for a limiter to protect against on its own
+        if stages.iter().all(|(config, _)| matches!(config, DspStageConfig::Limiter(_))) {
+            return Self::empty();
+        }
+
+        let stages: Vec<_> = stages.into_iter().map(|(_, stage)| stage).collect();
+        info!("Built DSP chain with {} stages for {format:?}", stages.len());
+
+        Self { stages }
+    }
+
+    pub fn empty() -> Self {
+        Self { stages: Vec::new() }
+    }
+
+    /// Whether a chain built from `old` for the device with `device_id` needs rebuilding for `new`.
+    pub fn needs_rebuild(old: &PlayerSettings, new: &PlayerSettings, device_id: &str) -> bool {
+        old.dsp != new.dsp
+            || old.is_bit_perfect() != new.is_bit_perfect()
+            || old.eq.preset_for(device_id) != new.eq.preset_for(device_id)
+    }
+
+    /// Whether the chain has any stages, and so may alter samples.
+    pub fn is_active(&self) -> bool {
+        !self.stages.is_empty()
+    }
+
+    /// How many frames the chain delays samples by.
+    pub fn latency(&self) -> usize {
+        self.stages.iter().map(|stage| stage.latency()).sum()
+    }
+
+    pub fn process(&mut self, samples: &mut [f64]) {
+        for stage in &mut self.stages {
+            stage.process(samples);
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use std::{collections::VecDeque, sync::LazyLock, time::Duration};
+
+use bytes::Bytes;
+use reqwest::{Client, StatusCode};
+use snafu::{ResultExt, Snafu};
+use tokio::{task::JoinHandle, time::sleep};
+use tracing::{instrument, warn};
+
+/// Segments downloaded at once, ahead of the one being decoded.
+const FETCH_WINDOW: usize = 4;
+
+/// Attempts at each download before giving up, so a short dropout doesn't end the track.
+const FETCH_ATTEMPTS: u32 = 6;
+
+/// Wait before the first retry, doubled for each retry after it.
+const RETRY_BACKOFF: Duration = Duration::from_millis(250);
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest a single attempt can take, so a stalled connection is retried.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+static HTTP_CLIENT: LazyLock<Client> = LazyLock::new(|| {
+    Client::builder()
+        .connect_timeout(CONNECT_TIMEOUT)
+        .build()
+        .unwrap_or_default()
+});
+
+/// The HTTP client shared by every stream, so connections to the CDN are pooled between them.
+pub fn http_client() -> Client {
+    HTTP_CLIENT.clone()
+}
+
+/// Download `url`, retrying with backoff if the connection fails or the server has a problem.
+#[instrument(err)]
+pub async fn fetch(url: String) -> Result<Bytes, FetchError> {
+    let mut backoff = RETRY_BACKOFF;
+    let mut attempt = 1;
+
+    loop {
+        match try_fetch(&url).await {
+            Ok(data) => return Ok(data),
+            Err(e) if attempt < FETCH_ATTEMPTS && e.is_transient() => {
+                warn!("Fetch attempt {attempt}/{FETCH_ATTEMPTS} failed, retrying in {backoff:?}: {e}");
+                sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+async fn try_fetch(url: &str) -> Result<Bytes, FetchError> {
+    let response = HTTP_CLIENT.get(url)
+        .timeout(REQUEST_TIMEOUT)
+        .send().await
+        .context(RequestSnafu { url })?;
+
+    let status = response.status();
+    if !status.is_success() {
+        return Err(FetchError::Status { url: url.to_owned(), status });
+    }
+
+    response.bytes().await.context(RequestSnafu { url })
+}
+
+/// Downloads a track's segments a few at a time, handing them back in order.
+pub struct SegmentFetcher {
+    urls: VecDeque<String>,
+    in_flight: VecDeque<JoinHandle<Result<Bytes, FetchError>>>,
+}
+
+impl SegmentFetcher {
+    pub fn new(urls: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            urls: urls.into_iter().collect(),
+            in_flight: VecDeque::with_capacity(FETCH_WINDOW),
+        }
+    }
+
+    /// The next segment, or `None` once every segment has been returned.
+    pub async fn next(&mut self) -> Option<Result<Bytes, FetchError>> {
+        while self.in_flight.len() < FETCH_WINDOW && let Some(url) = self.urls.pop_front() {
+            self.in_flight.push_back(tokio::spawn(fetch(url)));
+        }
+
+        let download = self.in_flight.pop_front()?;
+        Some(download.await.unwrap_or_else(|e| Err(FetchError::Task { message: e.to_string() })))
+    }
+}
+
+impl Drop for SegmentFetcher {
+    fn drop(&mut self) {
+        for download in &self.in_flight {
+            download.abort();
+        }
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum FetchError {
+    #[snafu(display("Request to '{url}' failed: {source}"))]
+    Request {
+        url: String,
+        source: reqwest::Error,
+    },
+    #[snafu(display("Request to '{url}' returned {status}"))]
+    Status {
+        url: String,
+        status: StatusCode,
+    },
+    #[snafu(display("Download task failed: {message}"))]
+    Task {
+        message: String,
+    },
+}
+
+impl FetchError {
+    /// Whether trying again might work. Client errors, like an expired link, won't go away.
+    fn is_transient(&self) -> bool {
+        match self {
+            FetchError::Request { .. } => true,
+            FetchError::Status { status, .. } => {
+                status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS || *status == StatusCode::REQUEST_TIMEOUT
+            }
+            FetchError::Task { .. } => false,
+        }
+    }
+}
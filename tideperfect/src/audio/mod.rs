@@ -1,4 +1,16 @@
+pub mod analysis;
+pub mod channels;
+pub mod dash;
+pub mod download;
+pub mod dither;
+pub mod dsp;
+pub mod fetch;
+pub mod output;
 pub mod player;
 pub mod queue;
+pub mod replay_gain;
+pub mod resample;
+pub mod settings;
 pub mod stream;
 pub mod track;
+pub mod waveform;
@@ -0,0 +1,775 @@
+use std::{sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Arc, Mutex}, time::Duration};
+
+use cpal::{traits::{DeviceTrait, StreamTrait}, BuildStreamError, Device, Sample, SampleFormat, SizedSample, Stream, StreamConfig, SupportedStreamConfigRange, I24};
+use ringbuf::{traits::{Consumer, Observer}, CachingCons, HeapRb};
+use snafu::{ResultExt, Snafu};
+use tokio::sync::mpsc;
+use tracing::{error, info, instrument, trace};
+
+use crate::audio::{analysis::AnalysisTap, channels::ChannelMap, dither::Dither, dsp::{DspChain, DspFormat}, player::PlayerCommand, settings::PlayerSettings, stream::{BufferSpace, SeekControl}, track::TrackMetadata};
+
+static NEXT_SOURCE_TOKEN: AtomicU64 = AtomicU64::new(0);
+
+/// How long before a crossfade the next track is requested, so its buffer has time to fill.
+const PREPARE_AHEAD_SECONDS: u64 = 10;
+
+/// Full scale for the samples in the ring buffer.
+pub const SOURCE_SCALE: f64 = 2_147_483_648.0;
+
+/// A track's ring buffer, as read by the output callback.
+pub struct Source {
+    /// Identifies this source in `PlayerCommand::TrackEnded`. Unique for every time a track is streamed.
+    pub token: u64,
+    consumer: CachingCons<Arc<HeapRb<i32>>>,
+    samples_played: Arc<AtomicU64>,
+    total_samples: Arc<AtomicU64>,
+    streaming_done: Arc<AtomicBool>,
+    seek_control: Arc<SeekControl>,
+    space: Arc<BufferSpace>,
+    /// Linear gain for this source alone, from ReplayGain.
+    gain: f64,
+    ended: bool,
+    prepare_sent: bool,
+}
+
+impl Source {
+    pub fn new(
+        consumer: CachingCons<Arc<HeapRb<i32>>>,
+        samples_played: Arc<AtomicU64>,
+        total_samples: Arc<AtomicU64>,
+        streaming_done: Arc<AtomicBool>,
+        seek_control: Arc<SeekControl>,
+        space: Arc<BufferSpace>,
+        gain: f64,
+    ) -> Self {
+        Self {
+            token: NEXT_SOURCE_TOKEN.fetch_add(1, Ordering::Relaxed),
+            consumer,
+            samples_played,
+            total_samples,
+            streaming_done,
+            seek_control,
+            space,
+            gain,
+            ended: false,
+            prepare_sent: false,
+        }
+    }
+
+    pub fn set_gain(&mut self, gain: f64) {
+        self.gain = gain;
+    }
+
+    /// Flush the ring buffer if the streaming task has seeked.
+    fn handle_seek(&mut self) {
+        let samples_played = &self.samples_played;
+        let consumer = &mut self.consumer;
+        self.seek_control.flush_with(|position| {
+            consumer.clear();
+            samples_played.store(position, Ordering::SeqCst);
+        });
+    }
+
+    fn pop(&mut self) -> Option<i32> {
+        let sample = self.consumer.try_pop()?;
+        self.samples_played.fetch_add(1, Ordering::Relaxed);
+        Some(sample)
+    }
+
+    /// Let the streaming task know how much room there is in the ring buffer.
+    fn report_space(&self) {
+        self.space.freed(self.consumer.vacant_len());
+    }
+
+    /// Estimated number of samples left in the track.
+    fn remaining(&self) -> u64 {
+        self.total_samples.load(Ordering::Relaxed).saturating_sub(self.samples_played.load(Ordering::Relaxed))
+    }
+}
+
+/// Counters kept by the output callback, shared by every output the player opens.
+#[derive(Debug, Default)]
+pub struct OutputStats {
+    clipped_samples: AtomicU64,
+    /// Callbacks that ran out of samples while the track was still streaming.
+    underruns: AtomicU64,
+    /// Callbacks that played silence because the player was changing the output's state.
+    contended: AtomicU64,
+}
+
+impl OutputStats {
+    /// Samples that were over full scale when they reached the output, and so were clipped.
+    pub fn clipped_samples(&self) -> u64 {
+        self.clipped_samples.load(Ordering::Relaxed)
+    }
+
+    pub fn reset(&self) {
+        self.clipped_samples.store(0, Ordering::Relaxed);
+    }
+
+    /// The underruns and contended callbacks since this was last called. The callback can't log
+    /// them itself, so the player does.
+    pub fn take_dropouts(&self) -> (u64, u64) {
+        (self.underruns.swap(0, Ordering::Relaxed), self.contended.swap(0, Ordering::Relaxed))
+    }
+}
+
+/// The sources read by the output callback. When `current` runs out, the callback moves straight on
+/// to `next` so there is no gap between the two tracks.
+struct OutputState {
+    current: Option<Source>,
+    next: Option<Source>,
+    /// Whether `next` should be crossfaded into, rather than following on gaplessly.
+    crossfade_next: bool,
+    settings: PlayerSettings,
+    dither: Dither,
+    channel_map: ChannelMap,
+    dsp: DspChain,
+    /// Samples read from the sources, normalised, before they are processed and quantised.
+    buffer: Vec<f64>,
+    /// Samples mapped to the output's channels.
+    mapped: Vec<f64>,
+    stats: Arc<OutputStats>,
+    tap: Arc<AnalysisTap>,
+}
+
+/// The format an output stream was opened with, as negotiated with the device.
+#[derive(Debug, Clone)]
+pub struct OutputFormat {
+    pub device: String,
+    pub sample_rate: u32,
+    pub sample_format: String,
+    pub channels: u16,
+    /// Whether the device is being used exclusively, so samples reach it unaltered.
+    pub exclusive: bool,
+}
+
+/// An open output stream. This is kept open between tracks that share the same format, so tracks can
+/// be played back to back without rebuilding the stream.
+pub struct Output {
+    stream: Stream,
+    pub metadata: TrackMetadata,
+    pub format: OutputFormat,
+    /// Id of the device, which EQ presets are chosen by.
+    device_id: String,
+    state: Arc<Mutex<OutputState>>,
+    paused: Arc<AtomicBool>,
+    player_tx: mpsc::Sender<PlayerCommand>,
+}
+
+impl std::fmt::Debug for Output {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Output")
+            .field("metadata", &self.metadata)
+            .field("format", &self.format)
+            .finish()
+    }
+}
+
+impl Output {
+    /// Open an output stream on `device` for tracks with the format given by `metadata`.
+    #[instrument(skip(device, paused, stats, tap, player_tx), err)]
+    pub fn open(
+        device: &Device,
+        metadata: TrackMetadata,
+        settings: &PlayerSettings,
+        paused: Arc<AtomicBool>,
+        stats: Arc<OutputStats>,
+        tap: Arc<AnalysisTap>,
+        player_tx: mpsc::Sender<PlayerCommand>,
+    ) -> Result<Self, OutputError> {
+        let state = OutputState {
+            current: None,
+            next: None,
+            crossfade_next: false,
+            settings: settings.clone(),
+            dither: Dither::new(),
+            channel_map: ChannelMap::new(settings, metadata.channels, metadata.channels),
+            dsp: DspChain::empty(),
+            buffer: Vec::new(),
+            mapped: Vec::new(),
+            stats,
+            tap,
+        };
+
+        Self::open_with_state(device, metadata, paused, player_tx, Arc::new(Mutex::new(state)))
+    }
+
+    fn open_with_state(
+        device: &Device,
+        metadata: TrackMetadata,
+        paused: Arc<AtomicBool>,
+        player_tx: mpsc::Sender<PlayerCommand>,
+        state: Arc<Mutex<OutputState>>,
+    ) -> Result<Self, OutputError> {
+        info!("Opening output stream for {metadata:?}");
+
+        let device_id = device_id(device);
+        let bit_perfect = state.lock().unwrap().settings.is_bit_perfect();
+        let supported_config = Self::supported_configs(device, &metadata, bit_perfect)?
+            .into_iter()
+            .find_map(|c| c.try_with_sample_rate(metadata.sample_rate))
+            .ok_or(OutputError::UnsupportedConfig { metadata })?;
+        trace!("Using supported config: {supported_config:?}");
+
+        let format = OutputFormat {
+            device: device.description().map(|description| description.name().to_owned()).unwrap_or_default(),
+            sample_rate: supported_config.sample_rate(),
+            sample_format: supported_config.sample_format().to_string(),
+            channels: supported_config.channels(),
+            exclusive: state.lock().unwrap().settings.exclusive_device.is_some(),
+        };
+
+        {
+            let mut state = state.lock().unwrap();
+            state.channel_map = ChannelMap::new(&state.settings, metadata.channels, format.channels);
+            state.dsp = DspChain::new(&state.settings, dsp_format(&format), &device_id);
+            state.tap.set_format(metadata.sample_rate, metadata.channels);
+        }
+
+        let config = supported_config.config();
+        let stream = match supported_config.sample_format() {
+            SampleFormat::I16 => Self::build_stream::<i16>(device, &config, &state, metadata, &paused, &player_tx),
+            SampleFormat::I24 => Self::build_stream::<I24>(device, &config, &state, metadata, &paused, &player_tx),
+            SampleFormat::I32 => Self::build_stream::<i32>(device, &config, &state, metadata, &paused, &player_tx),
+            SampleFormat::F32 => Self::build_stream::<f32>(device, &config, &state, metadata, &paused, &player_tx),
+            SampleFormat::U16 => Self::build_stream::<u16>(device, &config, &state, metadata, &paused, &player_tx),
+            sample_format => unreachable!("{sample_format} is filtered out by supported_configs"),
+        }
+        .context(BuildStreamSnafu)?;
+
+        trace!("Made stream");
+
+        stream.play().context(PlayStreamSnafu)?;
+
+        trace!("Playing stream");
+
+        info!("Opened output stream with {format:?}");
+
+        Ok(Self {
+            stream,
+            metadata,
+            format,
+            device_id,
+            state,
+            paused,
+            player_tx,
+        })
+    }
+
+    fn build_stream<T: OutputSample + SizedSample>(
+        device: &Device,
+        config: &StreamConfig,
+        state: &Arc<Mutex<OutputState>>,
+        metadata: TrackMetadata,
+        paused: &Arc<AtomicBool>,
+        player_tx: &mpsc::Sender<PlayerCommand>,
+    ) -> Result<Stream, BuildStreamError> {
+        let stats = state.lock().unwrap().stats.clone();
+        let state = state.clone();
+        let paused = paused.clone();
+        let player_tx = player_tx.clone();
+
+        device.build_output_stream(
+            config,
+            move |data: &mut [T], _| Self::write_audio_data(data, &state, metadata, &paused, &stats, &player_tx),
+            |err| error!("an error occurred on the output audio stream: {}", err),
+            None
+        )
+    }
+
+    /// The device's configs that can play a track, best first. Configs with the track's channel
+    /// count come first, then stereo ones it can be mixed to. Within those, formats that hold the
+    /// track's samples losslessly come first, closest to its bit depth; bit-perfect playback only
+    /// uses those, and only mixes mono tracks to stereo by copying them to both channels.
+    fn supported_configs(device: &Device, metadata: &TrackMetadata, bit_perfect: bool) -> Result<Vec<SupportedStreamConfigRange>, OutputError> {
+        let supported_configs = device.supported_output_configs().context(SupportedStreamConfigsSnafu)?;
+
+        let mut configs: Vec<_> = supported_configs
+            .filter(|c| c.channels() == metadata.channels || (c.channels() == 2 && (!bit_perfect || metadata.channels == 1)))
+            .inspect(|c| trace!("inspect: {c:?}"))
+            .filter(|c| {
+                format_precision(c.sample_format())
+                    .is_some_and(|precision| !bit_perfect || metadata.sample_size.is_none_or(|size| precision >= size))
+            })
+            .collect();
+        configs.sort_by_key(|c| (c.channels() != metadata.channels, format_rank(c.sample_format(), metadata.sample_size)));
+
+        Ok(configs)
+    }
+
+    /// The sample rate to resample a track to so it can be played on `device`, or `None` if it can
+    /// be played at its own rate. Tracks are only resampled if the device can't play their rate, or
+    /// a rate is forced in the settings. Bit-perfect playback never resamples.
+    pub fn resample_rate(device: &Device, metadata: &TrackMetadata, settings: &PlayerSettings) -> Result<Option<u32>, OutputError> {
+        if let Some(rate) = settings.resample_to && !settings.is_bit_perfect() {
+            return Ok((rate != metadata.sample_rate).then_some(rate));
+        }
+
+        let configs = Self::supported_configs(device, metadata, settings.is_bit_perfect())?;
+        let supports_rate = |c: &SupportedStreamConfigRange| (c.min_sample_rate()..=c.max_sample_rate()).contains(&metadata.sample_rate);
+
+        if configs.iter().any(supports_rate) {
+            return Ok(None);
+        }
+
+        if settings.is_bit_perfect() {
+            return Err(OutputError::UnsupportedConfig { metadata: *metadata });
+        }
+
+        // Use the closest rate the device supports
+        configs.iter()
+            .map(|c| metadata.sample_rate.clamp(c.min_sample_rate(), c.max_sample_rate()))
+            .min_by_key(|rate| rate.abs_diff(metadata.sample_rate))
+            .map(Some)
+            .ok_or(OutputError::UnsupportedConfig { metadata: *metadata })
+    }
+
+    /// Reopen the stream on another device, keeping the current and next sources.
+    pub fn switch_device(self, device: &Device) -> Result<Self, OutputError> {
+        let Self { stream, metadata, state, paused, player_tx, .. } = self;
+        // Close the old stream first, in case the device can only be opened once
+        drop(stream);
+
+        Self::open_with_state(device, metadata, paused, player_tx, state)
+    }
+
+    /// Whether a track can be played through this output without reopening it.
+    pub fn supports(&self, metadata: &TrackMetadata) -> bool {
+        self.metadata.sample_rate == metadata.sample_rate
+            && self.metadata.sample_size == metadata.sample_size
+            && self.metadata.channels == metadata.channels
+    }
+
+    /// Pass new settings to the output callback. The DSP chain is rebuilt if its settings changed.
+    pub fn update_settings(&self, settings: &PlayerSettings) {
+        let rebuild = DspChain::needs_rebuild(&self.state.lock().unwrap().settings, settings, &self.device_id);
+        // Build the chain outside the lock, so the callback isn't held up
+        let dsp = rebuild.then(|| DspChain::new(settings, dsp_format(&self.format), &self.device_id));
+        let channel_map = ChannelMap::new(settings, self.metadata.channels, self.format.channels);
+
+        let mut state = self.state.lock().unwrap();
+        state.settings = settings.clone();
+        state.channel_map = channel_map;
+        if let Some(dsp) = dsp {
+            state.dsp = dsp;
+        }
+    }
+
+    /// How long the DSP chain delays samples by.
+    pub fn dsp_latency(&self) -> Duration {
+        let frames = self.state.lock().unwrap().dsp.latency();
+        Duration::from_secs_f64(frames as f64 / self.metadata.sample_rate as f64)
+    }
+
+    /// Start playing `source` immediately, dropping anything that was playing or queued.
+    pub fn play(&self, source: Source) {
+        let mut state = self.state.lock().unwrap();
+        state.current = Some(source);
+        state.next = None;
+    }
+
+    /// Queue `source` to play once the current source runs out, either straight after it or by
+    /// crossfading into it.
+    pub fn queue_next(&self, source: Source, crossfade: bool) {
+        trace!("Queueing source #{} (crossfade: {crossfade})", source.token);
+        let mut state = self.state.lock().unwrap();
+        state.next = Some(source);
+        state.crossfade_next = crossfade;
+    }
+
+    /// Set the gain of the source with `token`, if the output has it.
+    pub fn set_gain(&self, token: u64, gain: f64) {
+        let mut state = self.state.lock().unwrap();
+        let OutputState { current, next, .. } = &mut *state;
+
+        for source in [current, next].into_iter().flatten() {
+            if source.token == token {
+                source.set_gain(gain);
+            }
+        }
+    }
+
+    /// Make the source with `token` the current source, if it is queued next. Returns false if the
+    /// output doesn't have the source at all.
+    pub fn promote(&self, token: u64) -> bool {
+        let mut state = self.state.lock().unwrap();
+
+        if state.current.as_ref().is_some_and(|source| source.token == token) {
+            return true;
+        }
+
+        if state.next.as_ref().is_some_and(|source| source.token == token) {
+            state.current = state.next.take();
+            return true;
+        }
+
+        false
+    }
+
+    /// Runs on the audio thread, so it never waits on a lock or logs.
+    fn write_audio_data<T: OutputSample + SizedSample>(
+        output: &mut [T],
+        state: &Mutex<OutputState>,
+        metadata: TrackMetadata,
+        paused: &AtomicBool,
+        stats: &OutputStats,
+        player_tx: &mpsc::Sender<PlayerCommand>,
+    ) {
+        // The player only holds the lock for a moment, so a callback that misses it plays silence
+        let Ok(mut state) = state.try_lock() else {
+            output.fill(T::EQUILIBRIUM);
+            stats.contended.fetch_add(1, Ordering::Relaxed);
+            return;
+        };
+
+        // Seeks are flushed even while paused, so the streaming task isn't left waiting
+        if let Some(current) = state.current.as_mut() {
+            current.handle_seek();
+        }
+        if let Some(next) = state.next.as_mut() {
+            next.handle_seek();
+        }
+
+        if paused.load(Ordering::SeqCst) {
+            output.fill(T::EQUILIBRIUM);
+            return;
+        }
+
+        let crossfade = state.settings.crossfade;
+        let volume = state.settings.gain();
+        // Bit-perfect playback never mixes tracks, even if crossfading was wanted when the next one was queued
+        let crossfade_len = if state.settings.is_bit_perfect() {
+            0
+        } else {
+            crossfade.length_in_samples(metadata.sample_rate, metadata.channels)
+        };
+        let prepare_ahead = crossfade_len + PREPARE_AHEAD_SECONDS * metadata.sample_rate as u64 * metadata.channels as u64;
+
+        let OutputState { current, next, crossfade_next, dither, channel_map, dsp, buffer, mapped, tap, .. } = &mut *state;
+        let wanted = output.len() / channel_map.output_channels() * channel_map.source_channels();
+        buffer.clear();
+        // Whether every sample has been read without any gain applied, from a source the output
+        // format holds exactly. Anything else needs dithering down to the output format.
+        let lossless = metadata.sample_size.is_some_and(|size| format_precision(T::FORMAT).is_some_and(|precision| precision >= size));
+        let mut untouched = lossless && volume == 1.0 && !dsp.is_active();
+
+        while buffer.len() < wanted {
+            let Some(source) = current.as_mut() else {
+                break;
+            };
+
+            let remaining = source.remaining();
+
+            // Ask for the next track early enough that it's buffered before the crossfade starts
+            if crossfade_len > 0 && !source.prepare_sent && remaining <= prepare_ahead {
+                source.prepare_sent = true;
+                let _ = player_tx.try_send(PlayerCommand::PrepareNext);
+            }
+
+            if let Some(sample) = source.pop() {
+                let sample = match next.as_mut() {
+                    Some(next) if *crossfade_next && crossfade_len > 0 && remaining <= crossfade_len => {
+                        let progress = 1.0 - remaining as f64 / crossfade_len as f64;
+                        let (fade_out, fade_in) = crossfade.curve.gains(progress);
+                        let incoming = next.pop().unwrap_or(0) as f64 * next.gain;
+                        untouched = false;
+
+                        (sample as f64 * source.gain * fade_out + incoming * fade_in) / SOURCE_SCALE
+                    }
+                    _ => {
+                        untouched &= source.gain == 1.0;
+                        sample as f64 / SOURCE_SCALE * source.gain
+                    }
+                };
+                buffer.push(sample);
+                continue;
+            }
+
+            if !source.streaming_done.load(Ordering::Relaxed) {
+                stats.underruns.fetch_add(1, Ordering::Relaxed);
+                break;
+            }
+
+            // The source is exhausted and streaming is done, so the track has finished
+            if !source.ended {
+                source.ended = true;
+                let _ = player_tx.try_send(PlayerCommand::TrackEnded(source.token));
+            }
+
+            match next.take() {
+                Some(next) => *current = Some(next),
+                None => break,
+            }
+        }
+
+        for source in [current.as_ref(), next.as_ref()].into_iter().flatten() {
+            source.report_space();
+        }
+
+        tap.push(buffer);
+
+        // Leave samples untouched at unity gain, rather than dithering them for nothing
+        if untouched && let Some(routing) = channel_map.routing() {
+            let frames = buffer.len() / channel_map.source_channels();
+            let source_frames = buffer.chunks_exact(channel_map.source_channels());
+            for (out, frame) in output.chunks_exact_mut(routing.len()).zip(source_frames) {
+                for (out, channel) in out.iter_mut().zip(routing) {
+                    *out = T::from_source((frame[*channel] * SOURCE_SCALE) as i32);
+                }
+            }
+            output[frames * routing.len()..].fill(T::EQUILIBRIUM);
+            return;
+        }
+
+        // Pad with silence, so the chain always sees whole frames and filter tails play out
+        buffer.resize(wanted, 0.0);
+        channel_map.apply(buffer, mapped);
+        dsp.process(mapped);
+
+        let mut clipped = 0;
+        for (out, sample) in output.iter_mut().zip(mapped.iter()) {
+            let sample = sample * volume;
+            if sample.abs() > 1.0 {
+                clipped += 1;
+            }
+            *out = T::quantize(sample, dither);
+        }
+
+        if clipped > 0 {
+            stats.clipped_samples.fetch_add(clipped, Ordering::Relaxed);
+        }
+    }
+}
+
+/// The id device-specific settings such as EQ presets and impulse responses are stored under.
+pub fn device_id(device: &Device) -> String {
+    device.id().map(|id| id.to_string()).unwrap_or_default()
+}
+
+fn dsp_format(format: &OutputFormat) -> DspFormat {
+    DspFormat {
+        sample_rate: format.sample_rate,
+        channels: format.channels,
+    }
+}
+
+/// The output formats that can be written to, in order of preference when they are otherwise equal.
+const OUTPUT_FORMATS: [SampleFormat; 5] = [
+    SampleFormat::I16,
+    SampleFormat::I24,
+    SampleFormat::I32,
+    SampleFormat::F32,
+    SampleFormat::U16,
+];
+
+/// How many bits of a sample `format` can hold exactly, or `None` if it can't be written to.
+fn format_precision(format: SampleFormat) -> Option<u32> {
+    match format {
+        SampleFormat::I16 | SampleFormat::U16 => Some(16),
+        // f32 has a 24 bit mantissa
+        SampleFormat::I24 | SampleFormat::F32 => Some(24),
+        SampleFormat::I32 => Some(32),
+        _ => None,
+    }
+}
+
+/// Sort key for `format` when playing samples of `sample_size` bits, lowest first. Lossless formats
+/// rank by how little padding they add, and lossy ones by how much precision they keep. Sources
+/// without a bit depth were decoded to floats, so they are best played as floats, or failing that
+/// with as many bits as the device takes.
+fn format_rank(format: SampleFormat, sample_size: Option<u32>) -> (bool, u32, usize) {
+    let precision = format_precision(format).unwrap_or(0);
+    let preference = OUTPUT_FORMATS.iter().position(|f| *f == format).unwrap_or(OUTPUT_FORMATS.len());
+
+    match sample_size {
+        Some(sample_size) => (precision < sample_size, precision.abs_diff(sample_size), preference),
+        None => (format != SampleFormat::F32, 32 - precision, preference),
+    }
+}
+
+/// Find the ALSA `hw:` device with the given PCM name, to be used exclusively. `hw:` devices bypass
+/// ALSA's plugins, so nothing is mixed in or converted on the way to the hardware.
+#[cfg(target_os = "linux")]
+pub fn exclusive_device(pcm_id: &str) -> Result<Device, OutputError> {
+    use cpal::{traits::HostTrait, DeviceId, HostId};
+
+    if !pcm_id.starts_with("hw:") {
+        return Err(OutputError::NotHardwareDevice { pcm_id: pcm_id.to_owned() });
+    }
+
+    let host = cpal::host_from_id(HostId::Alsa).context(HostUnavailableSnafu)?;
+    host.device_by_id(&DeviceId(HostId::Alsa, pcm_id.to_owned()))
+        .ok_or(OutputError::DeviceNotFound { pcm_id: pcm_id.to_owned() })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn exclusive_device(_pcm_id: &str) -> Result<Device, OutputError> {
+    Err(OutputError::ExclusiveUnsupported)
+}
+
+/// The ALSA `hw:` devices that can be used exclusively, as (PCM name, description) pairs.
+#[cfg(target_os = "linux")]
+pub fn exclusive_devices() -> Result<Vec<(String, String)>, OutputError> {
+    use cpal::{traits::HostTrait, HostId};
+
+    let host = cpal::host_from_id(HostId::Alsa).context(HostUnavailableSnafu)?;
+    let devices = host.output_devices().context(DevicesSnafu)?;
+
+    Ok(devices
+        .filter_map(|device| {
+            let pcm_id = device.id().ok()?.1;
+            let name = device.description().ok()?.name().to_owned();
+            pcm_id.starts_with("hw:").then_some((pcm_id, name))
+        })
+        .collect())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn exclusive_devices() -> Result<Vec<(String, String)>, OutputError> {
+    Ok(Vec::new())
+}
+
+/// A sample type that the output stream can be opened with.
+trait OutputSample: Sample {
+    /// Convert a sample from the ring buffer without any processing.
+    fn from_source(sample: i32) -> Self;
+
+    /// Quantise a processed sample, in the range -1 to 1, to this type.
+    fn quantize(sample: f64, dither: &mut Dither) -> Self;
+}
+
+impl OutputSample for i16 {
+    fn from_source(sample: i32) -> Self {
+        sample.to_sample()
+    }
+
+    fn quantize(sample: f64, dither: &mut Dither) -> Self {
+        (sample * 32_768.0 + dither.sample()).round().clamp(i16::MIN as f64, i16::MAX as f64) as i16
+    }
+}
+
+impl OutputSample for u16 {
+    fn from_source(sample: i32) -> Self {
+        sample.to_sample()
+    }
+
+    fn quantize(sample: f64, dither: &mut Dither) -> Self {
+        i16::quantize(sample, dither).to_sample()
+    }
+}
+
+impl OutputSample for I24 {
+    fn from_source(sample: i32) -> Self {
+        // Keep the top 24 bits, so full scale in the source is full scale on the device
+        sample.to_sample()
+    }
+
+    fn quantize(sample: f64, dither: &mut Dither) -> Self {
+        I24::new_unchecked((sample * 8_388_608.0 + dither.sample()).round().clamp(-8_388_608.0, 8_388_607.0) as i32)
+    }
+}
+
+impl OutputSample for i32 {
+    fn from_source(sample: i32) -> Self {
+        sample
+    }
+
+    fn quantize(sample: f64, _dither: &mut Dither) -> Self {
+        (sample * SOURCE_SCALE).round().clamp(i32::MIN as f64, i32::MAX as f64) as i32
+    }
+}
+
+impl OutputSample for f32 {
+    fn from_source(sample: i32) -> Self {
+        // Scaled by 2^31 rather than i32::MAX, so every sample maps into [-1, 1) without clipping
+        (sample as f64 / SOURCE_SCALE) as f32
+    }
+
+    fn quantize(sample: f64, _dither: &mut Dither) -> Self {
+        sample as f32
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum OutputError {
+    #[snafu(display("Error getting supported output configs"))]
+    SupportedStreamConfigs {
+        source: cpal::SupportedStreamConfigsError,
+    },
+    #[snafu(display("Device does not support playing track: {metadata:?}"))]
+    UnsupportedConfig {
+        metadata: TrackMetadata,
+    },
+    #[snafu(display("Failed to build output stream"))]
+    BuildStream {
+        source: cpal::BuildStreamError,
+    },
+    #[snafu(display("Failed to start output stream"))]
+    PlayStream {
+        source: cpal::PlayStreamError,
+    },
+    #[snafu(display("The ALSA host is unavailable"))]
+    HostUnavailable {
+        source: cpal::HostUnavailable,
+    },
+    #[snafu(display("Failed to list output devices"))]
+    Devices {
+        source: cpal::DevicesError,
+    },
+    #[snafu(display("'{pcm_id}' is not an ALSA hw: device, so it can't be used exclusively"))]
+    NotHardwareDevice {
+        pcm_id: String,
+    },
+    #[snafu(display("Could not find ALSA device '{pcm_id}'"))]
+    DeviceNotFound {
+        pcm_id: String,
+    },
+    #[snafu(display("Exclusive output is only supported with ALSA on Linux"))]
+    ExclusiveUnsupported,
+    #[snafu(display("'{id}' is not a valid device id"))]
+    InvalidDeviceId {
+        id: String,
+        source: cpal::DeviceIdError,
+    },
+    #[snafu(display("Could not find device '{id}'"))]
+    UnknownDevice {
+        id: String,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dither_keeps_detail_below_an_lsb() {
+        // A quarter of an LSB would round away to nothing, but survives on average with TPDF dither
+        let mut dither = Dither::new();
+        let count = 100_000;
+        let sum: i64 = (0..count).map(|_| i16::quantize(0.25 / 32_768.0, &mut dither) as i64).sum();
+
+        let mean = sum as f64 / count as f64;
+        assert!((mean - 0.25).abs() < 0.01, "mean was {mean}");
+    }
+
+    #[test]
+    fn dither_stays_within_an_lsb() {
+        let mut dither = Dither::new();
+
+        for _ in 0..10_000 {
+            assert!((-1..=1).contains(&i16::quantize(0.0, &mut dither)));
+            assert!((16_383..=16_385).contains(&i16::quantize(0.5, &mut dither)));
+        }
+    }
+
+    #[test]
+    fn quantize_clamps_to_full_scale() {
+        let mut dither = Dither::new();
+
+        assert_eq!(i16::quantize(2.0, &mut dither), i16::MAX);
+        assert_eq!(i16::quantize(-2.0, &mut dither), i16::MIN);
+        assert_eq!(i32::quantize(1.0, &mut dither), i32::MAX);
+        assert_eq!(i32::quantize(-1.0, &mut dither), i32::MIN);
+    }
+}
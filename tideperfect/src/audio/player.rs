@@ -1,22 +1,47 @@
-use std::{str::FromStr, sync::{atomic::{AtomicBool, Ordering}, Arc}};
+use std::{str::FromStr, sync::{atomic::{AtomicBool, Ordering}, Arc}, time::Duration};
 
-use cpal::{traits::{DeviceTrait, HostTrait}, Device, Devices};
-use serde::de;
+use cpal::{traits::{DeviceTrait, HostTrait}, Device};
 use snafu::Report;
 use strum_macros::EnumDiscriminants;
 use tokio::sync::{broadcast, mpsc, oneshot, Mutex};
-use tracing::{error, info, trace};
+use tracing::{error, info, trace, warn};
 use cpal::{Host, DeviceId};
 
-use crate::{audio::{queue::Queue, track::Track}, Event};
+use crate::{audio::{analysis::{AnalysisTap, ChannelLevel}, channels::ChannelSettings, dsp::{convolution::ImpulseResponse, eq::EqPreset, DspSettings}, output::{device_id, exclusive_device, Output, OutputError, OutputFormat, OutputStats}, queue::Queue, settings::{CrossfadeSettings, PlayerSettings, ReplayGainMode}, track::Track}, utils::persistence::Persistence, Event};
+
+/// How often dropouts counted by the output callback are logged.
+const DROPOUT_REPORT_INTERVAL: Duration = Duration::from_secs(1);
 
 pub enum PlayerCommand {
     Play,
     Pause,
     Skip,
     Previous,
+    Seek(Duration),
+    /// Sent by a streaming task once its track is fully buffered, so the next track can start buffering.
+    PrepareNext,
+    /// Sent by the output when the source with the given token has played to the end.
+    TrackEnded(u64),
     SwitchDevice(String),
-    GetDevices(oneshot::Sender<Vec<CommandDevice>>)
+    GetDevices(oneshot::Sender<Vec<CommandDevice>>),
+    GetSettings(oneshot::Sender<PlayerSettings>),
+    SetCrossfade(CrossfadeSettings),
+    SetVolume(f64),
+    SetBitPerfect(bool),
+    SetReplayGain(ReplayGainMode),
+    SetResampleTo(Option<u32>),
+    SetExclusiveDevice(Option<String>),
+    SetChannels(ChannelSettings),
+    SetDsp(DspSettings),
+    SaveEqPreset(String, EqPreset),
+    DeleteEqPreset(String),
+    /// Use the named preset with the device with the given id, or no preset.
+    SetDeviceEqPreset(String, Option<String>),
+    /// Use an impulse response with the device with the given id, replacing any with the same rate.
+    AddImpulseResponse(String, ImpulseResponse),
+    /// Stop using the device's impulse response for the given rate.
+    RemoveImpulseResponse(String, u32),
+    GetDspLatency(oneshot::Sender<Duration>),
 }
 
 pub struct CommandDevice {
@@ -24,14 +49,173 @@ pub struct CommandDevice {
     pub id: String,
 }
 
+/// Playback statistics, collected since the player started or they were last reset.
+#[derive(Debug, Clone)]
+pub struct PlayerStats {
+    /// Samples that went over full scale and were clipped at the output.
+    pub clipped_samples: u64,
+}
+
+/// The track being played, and how it is being played.
+#[derive(Debug, Clone)]
+pub struct NowPlaying {
+    pub track: tidalrs::Track,
+    /// ReplayGain applied to the track in dB, or `None` if it is played as-is.
+    pub replay_gain: Option<f64>,
+    /// The sample rate the track is resampled to, or `None` if it is played at its own rate.
+    pub resampled_to: Option<u32>,
+    /// The quality the track is being played in, which can be lower than the one asked for.
+    pub quality: tidalrs::AudioQuality,
+}
+
 #[derive(Debug, Clone, EnumDiscriminants)]
 pub enum PlayerEvent {
-    UpdatedCurrentTrack(Option<Box<tidalrs::Track>>),
+    UpdatedCurrentTrack(Option<Box<NowPlaying>>),
     UpdatedPauseState(bool),
     UpdatedTrackProgress(u32),
+    UpdatedVolume(f64),
+    /// The output stream was opened, in the given format.
+    UpdatedOutputFormat(OutputFormat),
+    PlaybackError(String),
+    /// Magnitudes in dB of `SPECTRUM_BANDS` logarithmically spaced bands, from 20 Hz to 20 kHz.
+    /// Only emitted while analysis is enabled.
+    Spectrum(Vec<f64>),
+    /// Levels of each channel since the last event. Only emitted while analysis is enabled.
+    Levels(Vec<ChannelLevel>),
+}
+
+/// Start playing `track` through `output`. The output is reused if it can play the track as-is,
+/// otherwise it is reopened in the track's format.
+#[allow(clippy::too_many_arguments)]
+fn start_track(
+    track: &mut Track,
+    output: &mut Option<Output>,
+    device: &Device,
+    settings: &PlayerSettings,
+    paused: &Arc<AtomicBool>,
+    stats: &Arc<OutputStats>,
+    tap: &Arc<AnalysisTap>,
+    command_tx: &mpsc::Sender<PlayerCommand>,
+    event_emitter: &broadcast::Sender<Event>,
+) -> Result<(), OutputError> {
+    // A pre-buffered track has to be streamed again if it was processed for a different output
+    let resample_to = Output::resample_rate(device, &track.metadata, settings)?;
+    let impulse = settings.impulse_for(&device_id(device), resample_to.unwrap_or(track.metadata.sample_rate)).cloned();
+    if track.resample_to != resample_to || track.impulse != impulse {
+        track.stop_track();
+        track.set_resample_to(resample_to);
+        track.set_impulse(impulse);
+    }
+
+    // A pre-buffered track may already have been handed to the output
+    if track.source.is_none() && track.is_streaming() {
+        let promoted = track.source_token
+            .is_some_and(|token| output.as_ref().is_some_and(|output| output.promote(token)));
+
+        if promoted {
+            track.start_progress(event_emitter.clone());
+            return Ok(());
+        }
+
+        // The output no longer has the source, so stream the track again
+        track.stop_track();
+    }
+
+    if !track.is_streaming() {
+        track.start_streaming(command_tx.clone());
+    }
+
+    if output.as_ref().is_none_or(|output| !output.supports(&track.output_metadata())) {
+        // Close the old stream before opening the new one
+        *output = None;
+        let opened = Output::open(device, track.output_metadata(), settings, paused.clone(), stats.clone(), tap.clone(), command_tx.clone())?;
+        let _ = event_emitter.send(Event::PlayerEvent(PlayerEvent::UpdatedOutputFormat(opened.format.clone())));
+        *output = Some(opened);
+    }
+
+    if let (Some(output), Some(source)) = (output.as_ref(), track.source.take()) {
+        output.play(source);
+    }
+
+    track.start_progress(event_emitter.clone());
+
+    Ok(())
+}
+
+/// Work out the ReplayGain for `track` from the settings and the tracks played either side of it.
+fn apply_replay_gain(
+    track: &mut Track,
+    previous: Option<&Track>,
+    next: Option<&Track>,
+    settings: &PlayerSettings,
+    output: &Option<Output>,
+) {
+    let in_album_order = previous.is_some_and(|previous| previous.is_album_successor(track))
+        || next.is_some_and(|next| track.is_album_successor(next));
+
+    let gain_db = if settings.is_bit_perfect() {
+        None
+    } else {
+        track.replay_gain.gain_db(settings.replay_gain, in_album_order)
+    };
+
+    trace!("Applying ReplayGain of {gain_db:?} dB to track (ID #{})", track.metadata.id);
+    track.set_applied_gain(gain_db);
+
+    // The track's source may already be playing or queued on the output
+    if let (Some(output), Some(token)) = (output, track.source_token) {
+        output.set_gain(token, track.gain());
+    }
+}
+
+/// Work out the ReplayGain again for the current and pre-buffered tracks, after the settings change.
+fn refresh_replay_gain(
+    current_track: &mut Option<Track>,
+    played: &[Track],
+    queue: &mut Queue,
+    settings: &PlayerSettings,
+    output: &Option<Output>,
+    event_emitter: &broadcast::Sender<Event>,
+) {
+    if let Some(track) = current_track.as_mut() {
+        apply_replay_gain(track, played.last(), queue.front(), settings, output);
+        let _ = event_emitter.send(Event::PlayerEvent(PlayerEvent::UpdatedCurrentTrack(Some(Box::new(track.now_playing())))));
+    }
+
+    if let Some(next) = queue.front_mut() && next.is_streaming() {
+        apply_replay_gain(next, current_track.as_ref(), None, settings, output);
+    }
+}
+
+/// Reopen the output on `device`, if there is one open.
+fn switch_output(output: &mut Option<Output>, device: &Device, event_emitter: &broadcast::Sender<Event>) {
+    if let Some(old) = output.take() {
+        match old.switch_device(device) {
+            Ok(new) => {
+                let _ = event_emitter.send(Event::PlayerEvent(PlayerEvent::UpdatedOutputFormat(new.format.clone())));
+                *output = Some(new);
+            }
+            Err(e) => {
+                error!("Failed to switch output device: {}", Report::from_error(&e));
+                let _ = event_emitter.send(Event::PlayerEvent(PlayerEvent::PlaybackError(Report::from_error(e).to_string())));
+            }
+        }
+    }
+}
+
+/// Persist the settings and pass them on to the output.
+fn apply_settings(settings: &PlayerSettings, output: &Option<Output>, persistence: &Persistence) {
+    if let Err(e) = persistence.store(settings) {
+        error!("Failed to store player settings: {}", Report::from_error(e));
+    }
+
+    if let Some(output) = output {
+        output.update_settings(settings);
+    }
 }
 
 // TODO: Should we be passing references for Senders/Recievers? How about other types?
+#[allow(clippy::too_many_arguments)]
 pub async fn player_loop(
     mut command_rx: mpsc::Receiver<PlayerCommand>,
     command_tx: mpsc::Sender<PlayerCommand>,
@@ -40,29 +224,52 @@ pub async fn player_loop(
     default_device: Device,
     queue: Arc<Mutex<Queue>>,
     played: Arc<Mutex<Vec<Track>>>,
+    persistence: Arc<Persistence>,
+    mut settings: PlayerSettings,
+    stats: Arc<OutputStats>,
+    tap: Arc<AnalysisTap>,
 ) {
     let mut current_track: Option<Track> = None;
+    let mut output: Option<Output> = None;
     let mut device = default_device;
     let paused = Arc::new(AtomicBool::new(true));
+    // The output callback can't log, so the dropouts it counts are logged from here
+    let mut dropout_report = tokio::time::interval(DROPOUT_REPORT_INTERVAL);
 
     // TODO: We need to properly handle results in this thread
     loop {
         tokio::select! {
+            _ = dropout_report.tick() => {
+                let (underruns, contended) = stats.take_dropouts();
+                if underruns > 0 {
+                    warn!("Output ran out of samples in {underruns} callbacks");
+                }
+                if contended > 0 {
+                    warn!("Output played silence in {contended} callbacks while its state was locked");
+                }
+            }
             Some(cmd) = command_rx.recv() => {
                 match cmd {
                     PlayerCommand::Play => {
                         info!("Got play command. Playing queue");
                         paused.store(false, Ordering::SeqCst);
-                        event_emitter.send(Event::PlayerEvent(PlayerEvent::UpdatedPauseState(false)));
+                        let _ = event_emitter.send(Event::PlayerEvent(PlayerEvent::UpdatedPauseState(false)));
 
                         if current_track.is_none() {
-                            if let Ok(Some(mut track)) = queue.lock().await.deque() {
+                            let next = queue.lock().await.deque();
+                            if let Ok(Some(mut track)) = next {
                                 info!("Starting first track in queue");
+                                {
+                                    // Tracks aren't Sync, so they can't be borrowed across the awaits
+                                    let (played, queue) = (played.lock().await, queue.lock().await);
+                                    apply_replay_gain(&mut track, played.last(), queue.front(), &settings, &output);
+                                }
 
-                                if let Err(e) = track.start_playback(&device, event_emitter.clone(), command_tx.clone(), paused.clone()) {
-                                    error!("Failed to start track: {}", Report::from_error(e));
+                                if let Err(e) = start_track(&mut track, &mut output, &device, &settings, &paused, &stats, &tap, &command_tx, &event_emitter) {
+                                    error!("Failed to start track: {}", Report::from_error(&e));
+                                    let _ = event_emitter.send(Event::PlayerEvent(PlayerEvent::PlaybackError(Report::from_error(e).to_string())));
                                 } else {
-                                    event_emitter.send(Event::PlayerEvent(PlayerEvent::UpdatedCurrentTrack(Some(Box::new(track.track.clone())))));
+                                    let _ = event_emitter.send(Event::PlayerEvent(PlayerEvent::UpdatedCurrentTrack(Some(Box::new(track.now_playing())))));
                                     current_track = Some(track);
                                 }
                             }
@@ -71,31 +278,45 @@ pub async fn player_loop(
                     PlayerCommand::Pause => {
                         info!("Pausing current track");
                         paused.store(true, Ordering::SeqCst);
-                        event_emitter.send(Event::PlayerEvent(PlayerEvent::UpdatedPauseState(true)));
+                        let _ = event_emitter.send(Event::PlayerEvent(PlayerEvent::UpdatedPauseState(true)));
+                    }
+                    PlayerCommand::TrackEnded(token) if current_track.as_ref().and_then(|track| track.source_token) != Some(token) => {
+                        trace!("Ignoring end of stale source #{token}");
                     }
-                    PlayerCommand::Skip => {
-                        info!("Skipping current track");
+                    cmd @ (PlayerCommand::Skip | PlayerCommand::TrackEnded(_)) => {
+                        match cmd {
+                            PlayerCommand::TrackEnded(token) => info!("Track playback complete (source #{token})"),
+                            _ => info!("Skipping current track"),
+                        }
 
                         if let Some(mut track) = current_track.take() {
                             track.stop_track();
                             played.lock().await.push(track);
                         }
 
-                        if let Ok(Some(mut track)) = queue.lock().await.deque() {
-                            if let Err(e) = track.start_playback(&device, event_emitter.clone(), command_tx.clone(), paused.clone()) {
-                                error!("Failed to start next track: {}", e);
+                        let next = queue.lock().await.deque();
+                        if let Ok(Some(mut track)) = next {
+                            {
+                                let (played, queue) = (played.lock().await, queue.lock().await);
+                                apply_replay_gain(&mut track, played.last(), queue.front(), &settings, &output);
+                            }
+
+                            if let Err(e) = start_track(&mut track, &mut output, &device, &settings, &paused, &stats, &tap, &command_tx, &event_emitter) {
+                                error!("Failed to start next track: {}", Report::from_error(&e));
+                                let _ = event_emitter.send(Event::PlayerEvent(PlayerEvent::PlaybackError(Report::from_error(e).to_string())));
                             } else {
-                                event_emitter.send(Event::PlayerEvent(PlayerEvent::UpdatedCurrentTrack(Some(Box::new(track.track.clone())))));
-                                event_emitter.send(Event::PlayerEvent(PlayerEvent::UpdatedPauseState(false)));
+                                let _ = event_emitter.send(Event::PlayerEvent(PlayerEvent::UpdatedCurrentTrack(Some(Box::new(track.now_playing())))));
+                                let _ = event_emitter.send(Event::PlayerEvent(PlayerEvent::UpdatedPauseState(false)));
                                 paused.store(false, Ordering::SeqCst);
                                 current_track = Some(track);
                             }
                         } else {
                             info!("Queue is empty, stopping playback");
                             paused.store(true, Ordering::SeqCst);
+                            output = None;
 
-                            event_emitter.send(Event::PlayerEvent(PlayerEvent::UpdatedCurrentTrack(None)));
-                            event_emitter.send(Event::PlayerEvent(PlayerEvent::UpdatedPauseState(true)));
+                            let _ = event_emitter.send(Event::PlayerEvent(PlayerEvent::UpdatedCurrentTrack(None)));
+                            let _ = event_emitter.send(Event::PlayerEvent(PlayerEvent::UpdatedPauseState(true)));
                         }
                     }
                     PlayerCommand::Previous => {
@@ -104,27 +325,110 @@ pub async fn player_loop(
                             track.stop_track();
                         }
 
-                        if let Some(mut track) = played.lock().await.pop() {
-                            if let Err(e) = track.start_playback(&device, event_emitter.clone(), command_tx.clone(), paused.clone()) {
-                                error!("Failed to start next track: {}", e);
+                        // The pre-buffered track is no longer next, so it will be streamed again later
+                        if let Some(next) = queue.lock().await.front_mut() && next.is_streaming() {
+                            next.stop_track();
+                        }
+
+                        let previous = played.lock().await.pop();
+                        if let Some(mut track) = previous {
+                            {
+                                let (played, queue) = (played.lock().await, queue.lock().await);
+                                apply_replay_gain(&mut track, played.last(), queue.front(), &settings, &output);
+                            }
+
+                            if let Err(e) = start_track(&mut track, &mut output, &device, &settings, &paused, &stats, &tap, &command_tx, &event_emitter) {
+                                error!("Failed to start next track: {}", Report::from_error(&e));
+                                let _ = event_emitter.send(Event::PlayerEvent(PlayerEvent::PlaybackError(Report::from_error(e).to_string())));
                             } else {
-                                event_emitter.send(Event::PlayerEvent(PlayerEvent::UpdatedCurrentTrack(Some(Box::new(track.track.clone())))));
-                                event_emitter.send(Event::PlayerEvent(PlayerEvent::UpdatedPauseState(false)));
+                                let _ = event_emitter.send(Event::PlayerEvent(PlayerEvent::UpdatedCurrentTrack(Some(Box::new(track.now_playing())))));
+                                let _ = event_emitter.send(Event::PlayerEvent(PlayerEvent::UpdatedPauseState(false)));
                                 paused.store(false, Ordering::SeqCst);
                                 current_track = Some(track);
                             }
                         } else {
                             info!("No previous track, stopping playback");
                             paused.store(true, Ordering::SeqCst);
+                            output = None;
+
+                            let _ = event_emitter.send(Event::PlayerEvent(PlayerEvent::UpdatedCurrentTrack(None)));
+                            let _ = event_emitter.send(Event::PlayerEvent(PlayerEvent::UpdatedPauseState(true)));
+                        }
+                    }
+                    PlayerCommand::Seek(position) => {
+                        if let Some(track) = current_track.as_mut() {
+                            info!("Seeking to {position:?}");
+                            track.seek(position, command_tx.clone());
+                            let _ = event_emitter.send(Event::PlayerEvent(PlayerEvent::UpdatedTrackProgress(position.as_secs() as u32)));
+                        }
+                    }
+                    PlayerCommand::PrepareNext => {
+                        if current_track.is_some() && let Some(next) = queue.lock().await.front_mut() && !next.is_streaming() {
+                            info!("Pre-buffering next track (ID #{})", next.metadata.id);
+                            apply_replay_gain(next, current_track.as_ref(), None, &settings, &output);
+
+                            match Output::resample_rate(&device, &next.metadata, &settings) {
+                                Ok(resample_to) => next.set_resample_to(resample_to),
+                                Err(e) => error!("Failed to find output rate for next track: {}", Report::from_error(e)),
+                            }
+                            next.set_impulse(settings.impulse_for(&device_id(&device), next.output_metadata().sample_rate).cloned());
+                            next.start_streaming(command_tx.clone());
 
-                            event_emitter.send(Event::PlayerEvent(PlayerEvent::UpdatedCurrentTrack(None)));
-                            event_emitter.send(Event::PlayerEvent(PlayerEvent::UpdatedPauseState(true)));
+                            // Tracks in a different format still need the output reopened, so only
+                            // matching tracks can follow on without a gap
+                            if let Some(output) = &output && output.supports(&next.output_metadata()) && let Some(source) = next.source.take() {
+                                // Crossfading would spoil gapless albums, so only crossfade between unrelated tracks,
+                                // and never while playing bit-perfect
+                                let crossfade = settings.crossfade.duration_ms > 0
+                                    && !settings.is_bit_perfect()
+                                    && current_track.as_ref().is_some_and(|current| !current.is_album_successor(next));
+                                output.queue_next(source, crossfade);
+                            }
                         }
                     }
                     PlayerCommand::SwitchDevice(new_device) => {
                         info!("Switching to device {new_device}");
-                        let device_id = DeviceId::from_str(&new_device).unwrap();
-                        device = host.device_by_id(&device_id).unwrap();
+                        let found = DeviceId::from_str(&new_device)
+                            .map_err(|source| OutputError::InvalidDeviceId { id: new_device.clone(), source })
+                            .and_then(|device_id| host.device_by_id(&device_id).ok_or(OutputError::UnknownDevice { id: new_device.clone() }));
+
+                        match found {
+                            Ok(found) => {
+                                device = found;
+
+                                // Choosing a device ends exclusive playback
+                                if settings.exclusive_device.take().is_some() {
+                                    apply_settings(&settings, &output, &persistence);
+                                }
+
+                                switch_output(&mut output, &device, &event_emitter);
+                            }
+                            Err(e) => {
+                                error!("Failed to find output device: {}", Report::from_error(&e));
+                                let _ = event_emitter.send(Event::PlayerEvent(PlayerEvent::PlaybackError(Report::from_error(e).to_string())));
+                            }
+                        }
+                    }
+                    PlayerCommand::SetExclusiveDevice(pcm_id) => {
+                        info!("Setting exclusive device to {pcm_id:?}");
+
+                        let new_device = match &pcm_id {
+                            Some(pcm_id) => exclusive_device(pcm_id),
+                            None => host.default_output_device().ok_or(OutputError::DeviceNotFound { pcm_id: "default".to_owned() }),
+                        };
+
+                        match new_device {
+                            Ok(new_device) => {
+                                device = new_device;
+                                settings.exclusive_device = pcm_id;
+                                apply_settings(&settings, &output, &persistence);
+                                switch_output(&mut output, &device, &event_emitter);
+                            }
+                            Err(e) => {
+                                error!("Failed to open exclusive device: {}", Report::from_error(&e));
+                                let _ = event_emitter.send(Event::PlayerEvent(PlayerEvent::PlaybackError(Report::from_error(e).to_string())));
+                            }
+                        }
                     }
                     PlayerCommand::GetDevices(sender) => {
                         trace!("Getting devices");
@@ -133,7 +437,92 @@ pub async fn player_loop(
                             name: device.description().unwrap().name().to_owned(),
                             id: device.id().unwrap().to_string(),
                         }).collect();
-                        sender.send(devices);
+                        let _ = sender.send(devices);
+                    }
+                    PlayerCommand::GetSettings(sender) => {
+                        let _ = sender.send(settings.clone());
+                    }
+                    PlayerCommand::SetCrossfade(crossfade) => {
+                        info!("Setting crossfade to {crossfade:?}");
+                        settings.crossfade = crossfade;
+                        apply_settings(&settings, &output, &persistence);
+                    }
+                    PlayerCommand::SetVolume(volume) => {
+                        let volume = volume.clamp(0.0, 1.0);
+                        trace!("Setting volume to {volume}");
+                        settings.volume = volume;
+                        apply_settings(&settings, &output, &persistence);
+                        let _ = event_emitter.send(Event::PlayerEvent(PlayerEvent::UpdatedVolume(volume)));
+                    }
+                    PlayerCommand::SetBitPerfect(bit_perfect) => {
+                        info!("Setting bit-perfect playback to {bit_perfect}");
+                        settings.bit_perfect = bit_perfect;
+                        apply_settings(&settings, &output, &persistence);
+
+                        let (played, mut queue) = (played.lock().await, queue.lock().await);
+                        refresh_replay_gain(&mut current_track, &played, &mut queue, &settings, &output, &event_emitter);
+                    }
+                    PlayerCommand::SetDsp(dsp) => {
+                        info!("Setting DSP chain to {dsp:?}");
+                        settings.dsp = dsp;
+                        apply_settings(&settings, &output, &persistence);
+                    }
+                    PlayerCommand::SaveEqPreset(name, preset) => {
+                        info!("Saving EQ preset {name}: {preset:?}");
+                        settings.eq.presets.insert(name, preset);
+                        apply_settings(&settings, &output, &persistence);
+                    }
+                    PlayerCommand::DeleteEqPreset(name) => {
+                        info!("Deleting EQ preset {name}");
+                        settings.eq.presets.remove(&name);
+                        settings.eq.devices.retain(|_, preset| *preset != name);
+                        apply_settings(&settings, &output, &persistence);
+                    }
+                    PlayerCommand::SetDeviceEqPreset(device_id, preset) => {
+                        info!("Setting EQ preset for {device_id} to {preset:?}");
+                        match preset {
+                            Some(preset) => settings.eq.devices.insert(device_id, preset),
+                            None => settings.eq.devices.remove(&device_id),
+                        };
+                        apply_settings(&settings, &output, &persistence);
+                    }
+                    PlayerCommand::AddImpulseResponse(device_id, impulse) => {
+                        info!("Adding impulse response for {device_id}: {impulse:?}");
+                        let impulses = settings.convolution.devices.entry(device_id).or_default();
+                        impulses.retain(|existing| existing.sample_rate != impulse.sample_rate);
+                        impulses.push(impulse);
+                        apply_settings(&settings, &output, &persistence);
+                    }
+                    PlayerCommand::RemoveImpulseResponse(device_id, sample_rate) => {
+                        info!("Removing {sample_rate} Hz impulse response for {device_id}");
+                        if let Some(impulses) = settings.convolution.devices.get_mut(&device_id) {
+                            impulses.retain(|impulse| impulse.sample_rate != sample_rate);
+                            if impulses.is_empty() {
+                                settings.convolution.devices.remove(&device_id);
+                            }
+                        }
+                        apply_settings(&settings, &output, &persistence);
+                    }
+                    PlayerCommand::GetDspLatency(sender) => {
+                        let _ = sender.send(output.as_ref().map(Output::dsp_latency).unwrap_or_default());
+                    }
+                    PlayerCommand::SetResampleTo(resample_to) => {
+                        info!("Setting forced resampling rate to {resample_to:?}");
+                        settings.resample_to = resample_to;
+                        apply_settings(&settings, &output, &persistence);
+                    }
+                    PlayerCommand::SetChannels(channels) => {
+                        info!("Setting channel options to {channels:?}");
+                        settings.channels = channels;
+                        apply_settings(&settings, &output, &persistence);
+                    }
+                    PlayerCommand::SetReplayGain(mode) => {
+                        info!("Setting ReplayGain mode to {mode:?}");
+                        settings.replay_gain = mode;
+                        apply_settings(&settings, &output, &persistence);
+
+                        let (played, mut queue) = (played.lock().await, queue.lock().await);
+                        refresh_replay_gain(&mut current_track, &played, &mut queue, &settings, &output, &event_emitter);
                     }
                 }
             }
@@ -46,6 +46,16 @@ impl Queue {
 
         Ok(result)
     }
+
+    /// The track that will be dequeued next.
+    pub fn front(&self) -> Option<&Track> {
+        self.tracks.front()
+    }
+
+    /// The track that will be dequeued next, so it can be pre-buffered.
+    pub fn front_mut(&mut self) -> Option<&mut Track> {
+        self.tracks.front_mut()
+    }
 }
 
 impl From<&mut Queue> for Vec<tidalrs::Track> {
@@ -0,0 +1,54 @@
+use tidalrs::TrackDashPlaybackInfo;
+
+use crate::audio::settings::ReplayGainMode;
+
+/// ReplayGain values for a track, as given by TIDAL's playback info.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ReplayGain {
+    /// Track gain in dB.
+    pub track_gain: f64,
+    /// Track peak amplitude, where 1.0 is full scale.
+    pub track_peak: f64,
+    /// Album gain in dB.
+    pub album_gain: f64,
+    /// Album peak amplitude, where 1.0 is full scale.
+    pub album_peak: f64,
+}
+
+// The tidalrs fork and the crates.io release disagree on whether these fields are f32 or f64.
+#[allow(clippy::useless_conversion)]
+impl From<&TrackDashPlaybackInfo> for ReplayGain {
+    fn from(info: &TrackDashPlaybackInfo) -> Self {
+        Self {
+            track_gain: f64::from(info.track_replay_gain),
+            track_peak: f64::from(info.track_peak_amplitude),
+            album_gain: f64::from(info.album_replay_gain),
+            album_peak: f64::from(info.album_peak_amplitude),
+        }
+    }
+}
+
+impl ReplayGain {
+    /// The gain in dB to play the track at, or `None` if normalisation is off. The gain is reduced
+    /// where needed so the peak doesn't clip.
+    pub fn gain_db(&self, mode: ReplayGainMode, in_album_order: bool) -> Option<f64> {
+        let (gain, peak) = match mode {
+            ReplayGainMode::Off => return None,
+            ReplayGainMode::Track => (self.track_gain, self.track_peak),
+            ReplayGainMode::Album => (self.album_gain, self.album_peak),
+            ReplayGainMode::Auto if in_album_order => (self.album_gain, self.album_peak),
+            ReplayGainMode::Auto => (self.track_gain, self.track_peak),
+        };
+
+        if peak > 0.0 {
+            Some(gain.min(-20.0 * peak.log10()))
+        } else {
+            Some(gain)
+        }
+    }
+}
+
+/// Convert a gain in dB to a linear factor.
+pub fn db_to_gain(db: f64) -> f64 {
+    10f64.powf(db / 20.0)
+}
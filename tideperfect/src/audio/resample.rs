@@ -0,0 +1,199 @@
+use rubato::{ResampleError, Resampler as _, ResamplerConstructionError, SincFixedIn, SincInterpolationParameters, SincInterpolationType, WindowFunction};
+
+use crate::audio::output::SOURCE_SCALE;
+
+/// Number of frames the resampler works on at a time.
+const CHUNK_FRAMES: usize = 1024;
+
+/// The windowed-sinc filter used for all resampling.
+pub fn sinc_parameters() -> SincInterpolationParameters {
+    SincInterpolationParameters {
+        sinc_len: 256,
+        f_cutoff: 0.95,
+        oversampling_factor: 256,
+        interpolation: SincInterpolationType::Cubic,
+        window: WindowFunction::BlackmanHarris2,
+    }
+}
+
+/// Converts interleaved samples from one sample rate to another with a windowed-sinc filter. This
+/// sits between the decoder and the ring buffer, so the output callback never has to resample.
+pub struct Resampler {
+    inner: SincFixedIn<f64>,
+    channels: usize,
+    /// Deinterleaved input, waiting until there is a full chunk.
+    input: Vec<Vec<f64>>,
+    ratio: f64,
+    /// Frames taken in and given out since the last reset, so the end of the track can be cut at
+    /// exactly the right length.
+    frames_in: u64,
+    frames_out: u64,
+    finished: bool,
+}
+
+impl Resampler {
+    pub fn new(from_rate: u32, to_rate: u32, channels: u16) -> Result<Self, ResamplerConstructionError> {
+        let ratio = to_rate as f64 / from_rate as f64;
+        let inner = SincFixedIn::new(ratio, 1.0, sinc_parameters(), CHUNK_FRAMES, channels as usize)?;
+        let channels = channels as usize;
+
+        Ok(Self {
+            inner,
+            channels,
+            input: vec![Vec::with_capacity(CHUNK_FRAMES); channels],
+            ratio,
+            frames_in: 0,
+            frames_out: 0,
+            finished: false,
+        })
+    }
+
+    /// Resample `samples`. Input is held back until there is a full chunk, so the output may be
+    /// empty.
+    pub fn process(&mut self, samples: &[i32]) -> Result<Vec<i32>, ResampleError> {
+        self.finished = false;
+        self.frames_in += (samples.len() / self.channels) as u64;
+
+        for frame in samples.chunks_exact(self.channels) {
+            for (channel, sample) in self.input.iter_mut().zip(frame) {
+                channel.push(*sample as f64 / SOURCE_SCALE);
+            }
+        }
+
+        let mut output = Vec::new();
+        while self.input[0].len() >= CHUNK_FRAMES {
+            let chunk: Vec<Vec<f64>> = self.input.iter_mut()
+                .map(|channel| channel.drain(..CHUNK_FRAMES).collect())
+                .collect();
+
+            let resampled = self.inner.process(&chunk, None)?;
+            self.interleave(&resampled, &mut output);
+        }
+
+        Ok(output)
+    }
+
+    /// Resample whatever input is left at the end of the track. Returns `None` if this has already
+    /// been done.
+    pub fn finish(&mut self) -> Result<Option<Vec<i32>>, ResampleError> {
+        if self.finished {
+            return Ok(None);
+        }
+        self.finished = true;
+
+        let mut output = Vec::new();
+
+        let resampled = self.inner.process_partial(Some(&self.input), None)?;
+        self.interleave(&resampled, &mut output);
+        self.input.iter_mut().for_each(Vec::clear);
+
+        // The filter needs input beyond the end of the track to finish it, so flush until it's all
+        // out, then cut off the silence after it so the next track follows on without a gap
+        let expected = (self.frames_in as f64 * self.ratio).round() as u64;
+        while self.frames_out < expected {
+            let resampled = self.inner.process_partial::<Vec<f64>>(None, None)?;
+            if resampled.first().is_none_or(Vec::is_empty) {
+                break;
+            }
+            self.interleave(&resampled, &mut output);
+        }
+
+        let excess = (self.frames_out.saturating_sub(expected) as usize * self.channels).min(output.len());
+        output.truncate(output.len() - excess);
+        self.frames_out -= (excess / self.channels) as u64;
+
+        Ok(Some(output))
+    }
+
+    /// Drop all buffered input, after a seek.
+    pub fn reset(&mut self) {
+        self.inner.reset();
+        self.input.iter_mut().for_each(Vec::clear);
+        self.frames_in = 0;
+        self.frames_out = 0;
+        self.finished = false;
+    }
+
+    fn interleave(&mut self, resampled: &[Vec<f64>], output: &mut Vec<i32>) {
+        let frames = resampled.first().map_or(0, Vec::len);
+
+        for frame in 0..frames {
+            for channel in resampled {
+                output.push((channel[frame] * SOURCE_SCALE).round().clamp(i32::MIN as f64, i32::MAX as f64) as i32);
+            }
+        }
+
+        self.frames_out += frames as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A sine wave at `frequency` Hz, as interleaved samples with the same signal in every channel.
+    fn sine(frames: usize, frequency: f64, sample_rate: u32, channels: usize) -> Vec<i32> {
+        (0..frames)
+            .flat_map(|frame| {
+                let sample = (2.0 * std::f64::consts::PI * frequency * frame as f64 / sample_rate as f64).sin() * 0.5;
+                std::iter::repeat_n((sample * SOURCE_SCALE) as i32, channels)
+            })
+            .collect()
+    }
+
+    /// Resample `samples` in uneven chunks, as the decoder hands them over, and finish the stream.
+    fn resample_all(resampler: &mut Resampler, samples: &[i32], channels: usize) -> Vec<i32> {
+        let mut output = Vec::new();
+        for chunk in samples.chunks(777 * channels) {
+            output.extend(resampler.process(chunk).unwrap());
+        }
+        output.extend(resampler.finish().unwrap().unwrap());
+        output
+    }
+
+    #[test]
+    fn output_length_matches_rate_ratio() {
+        for (from_rate, to_rate, frames) in [(44_100, 48_000, 10_000), (48_000, 44_100, 10_000), (96_000, 48_000, 5_000), (44_100, 96_000, 300)] {
+            let mut resampler = Resampler::new(from_rate, to_rate, 2).unwrap();
+            let output = resample_all(&mut resampler, &sine(frames, 1_000.0, from_rate, 2), 2);
+
+            let expected = (frames as f64 * to_rate as f64 / from_rate as f64).round() as usize;
+            assert_eq!(output.len(), expected * 2, "{from_rate} Hz to {to_rate} Hz");
+        }
+    }
+
+    #[test]
+    fn output_stays_in_time() {
+        let (from_rate, to_rate) = (44_100, 48_000);
+        let mut samples = vec![0; 4_000];
+        samples[1_000] = (0.5 * SOURCE_SCALE) as i32;
+
+        let mut resampler = Resampler::new(from_rate, to_rate, 1).unwrap();
+        let output = resample_all(&mut resampler, &samples, 1);
+
+        let peak = (0..output.len()).max_by_key(|index| output[*index].unsigned_abs()).unwrap();
+        let expected = (1_000.0 * to_rate as f64 / from_rate as f64).round() as usize;
+        assert!(peak.abs_diff(expected) <= 1, "impulse moved from {expected} to {peak}");
+    }
+
+    #[test]
+    fn finishes_once() {
+        let mut resampler = Resampler::new(44_100, 48_000, 2).unwrap();
+        resampler.process(&sine(100, 1_000.0, 44_100, 2)).unwrap();
+
+        assert!(resampler.finish().unwrap().is_some());
+        assert!(resampler.finish().unwrap().is_none());
+    }
+
+    #[test]
+    fn reset_starts_afresh() {
+        let samples = sine(3_000, 440.0, 48_000, 2);
+        let mut resampler = Resampler::new(48_000, 44_100, 2).unwrap();
+        let first = resample_all(&mut resampler, &samples, 2);
+
+        resampler.reset();
+        let second = resample_all(&mut resampler, &samples, 2);
+
+        assert_eq!(first, second);
+    }
+}
@@ -0,0 +1,213 @@
+use std::f64::consts::FRAC_PI_2;
+
+use serde::{Deserialize, Serialize};
+use tidalrs::AudioQuality;
+
+use crate::{audio::{channels::ChannelSettings, dsp::{convolution::{ConvolutionSettings, ImpulseResponse}, eq::EqSettings, DspSettings, DspStageConfig}}, utils::persistence::PersistenceContext};
+
+/// The longest crossfade that can be configured.
+pub const MAX_CROSSFADE_MS: u32 = 12_000;
+
+/// Player settings, persisted between sessions.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PlayerSettings {
+    pub crossfade: CrossfadeSettings,
+    /// Volume between 0 and 1.
+    pub volume: f64,
+    /// Bypass all processing, including volume, ReplayGain and the DSP chain, so samples reach the
+    /// device exactly as decoded.
+    pub bit_perfect: bool,
+    pub replay_gain: ReplayGainMode,
+    /// Always resample to this rate, rather than only when the device can't play a track's own rate.
+    pub resample_to: Option<u32>,
+    /// PCM name of an ALSA `hw:` device to play to exclusively, such as `hw:CARD=DAC,DEV=0`. Tracks
+    /// are played bit-perfect in their own format, and fail to play if the device can't take it.
+    pub exclusive_device: Option<String>,
+    pub channels: ChannelSettings,
+    pub dsp: DspSettings,
+    pub eq: EqSettings,
+    pub convolution: ConvolutionSettings,
+}
+
+impl PlayerSettings {
+    /// Whether samples must reach the device unaltered, either because bit-perfect playback is
+    /// enabled or because a device is being used exclusively.
+    pub fn is_bit_perfect(&self) -> bool {
+        self.bit_perfect || self.exclusive_device.is_some()
+    }
+
+    /// The impulse response to convolve tracks played at `sample_rate` with, on the device with
+    /// `device_id`, or `None` if they aren't convolved.
+    pub fn impulse_for(&self, device_id: &str, sample_rate: u32) -> Option<&ImpulseResponse> {
+        let enabled = self.dsp.stages.iter()
+            .any(|stage| stage.enabled && matches!(stage.config, DspStageConfig::Convolution));
+        if !enabled || self.is_bit_perfect() {
+            return None;
+        }
+
+        self.convolution.impulse_for(device_id, sample_rate)
+    }
+
+    /// The gain to apply to samples for the current volume. The volume is cubed so that the
+    /// volume control roughly follows perceived loudness.
+    pub fn gain(&self) -> f64 {
+        if self.is_bit_perfect() {
+            return 1.0;
+        }
+
+        self.volume.clamp(0.0, 1.0).powi(3)
+    }
+}
+
+impl Default for PlayerSettings {
+    fn default() -> Self {
+        Self {
+            crossfade: CrossfadeSettings::default(),
+            volume: 1.0,
+            bit_perfect: false,
+            replay_gain: ReplayGainMode::default(),
+            resample_to: None,
+            exclusive_device: None,
+            channels: ChannelSettings::default(),
+            dsp: DspSettings::default(),
+            eq: EqSettings::default(),
+            convolution: ConvolutionSettings::default(),
+        }
+    }
+}
+
+impl PersistenceContext for PlayerSettings {}
+
+/// The quality tracks are requested from TIDAL in, persisted between sessions. Lower qualities are
+/// used when the account or track doesn't offer the one chosen.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QualitySettings {
+    pub streaming: AudioQuality,
+    /// Quality to download tracks in for offline listening.
+    pub offline: AudioQuality,
+}
+
+impl Default for QualitySettings {
+    fn default() -> Self {
+        Self {
+            streaming: AudioQuality::HiResLossless,
+            offline: AudioQuality::HiResLossless,
+        }
+    }
+}
+
+impl PersistenceContext for QualitySettings {}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CrossfadeSettings {
+    /// Length of the crossfade in milliseconds. A length of 0 disables crossfading.
+    pub duration_ms: u32,
+    pub curve: CrossfadeCurve,
+}
+
+impl CrossfadeSettings {
+    /// The crossfade length in interleaved samples, for a stream with the given format.
+    pub fn length_in_samples(&self, sample_rate: u32, channels: u16) -> u64 {
+        self.duration_ms.min(MAX_CROSSFADE_MS) as u64 * sample_rate as u64 / 1000 * channels as u64
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CrossfadeCurve {
+    Linear,
+    /// Keeps the combined power constant, so there is no dip in loudness halfway through.
+    #[default]
+    EqualPower,
+    /// Eases in and out of the crossfade.
+    SCurve,
+}
+
+impl CrossfadeCurve {
+    /// Gains for the outgoing and incoming tracks, `progress` of the way through the crossfade.
+    pub fn gains(self, progress: f64) -> (f64, f64) {
+        let progress = progress.clamp(0.0, 1.0);
+
+        match self {
+            Self::Linear => (1.0 - progress, progress),
+            Self::EqualPower => ((progress * FRAC_PI_2).cos(), (progress * FRAC_PI_2).sin()),
+            Self::SCurve => {
+                let eased = progress * progress * (3.0 - 2.0 * progress);
+                (1.0 - eased, eased)
+            }
+        }
+    }
+}
+
+/// Which ReplayGain values, if any, are used to normalise loudness.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReplayGainMode {
+    #[default]
+    Off,
+    Track,
+    Album,
+    /// Album gain while an album is being played in order, otherwise track gain.
+    Auto,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CURVES: [CrossfadeCurve; 3] = [CrossfadeCurve::Linear, CrossfadeCurve::EqualPower, CrossfadeCurve::SCurve];
+
+    #[test]
+    fn curves_start_and_end_on_one_track() {
+        for curve in CURVES {
+            let (out, into) = curve.gains(0.0);
+            assert!((out - 1.0).abs() < 1e-12 && into.abs() < 1e-12, "{curve:?} starts at {out}, {into}");
+            let (out, into) = curve.gains(1.0);
+            assert!(out.abs() < 1e-12 && (into - 1.0).abs() < 1e-12, "{curve:?} ends at {out}, {into}");
+        }
+    }
+
+    #[test]
+    fn gains_sum_to_unity_at_midpoint() {
+        for curve in [CrossfadeCurve::Linear, CrossfadeCurve::SCurve] {
+            let (out, into) = curve.gains(0.5);
+            assert!((out - 0.5).abs() < 1e-12 && (into - 0.5).abs() < 1e-12, "{curve:?} is at {out}, {into}");
+        }
+
+        // Equal power keeps the sum of the squares at unity instead
+        let (out, into) = CrossfadeCurve::EqualPower.gains(0.5);
+        assert!((out - std::f64::consts::FRAC_1_SQRT_2).abs() < 1e-12 && (out - into).abs() < 1e-12);
+    }
+
+    #[test]
+    fn gains_stay_balanced_throughout() {
+        for step in 0..=100 {
+            let progress = step as f64 / 100.0;
+
+            for curve in [CrossfadeCurve::Linear, CrossfadeCurve::SCurve] {
+                let (out, into) = curve.gains(progress);
+                assert!((out + into - 1.0).abs() < 1e-12, "{curve:?} at {progress}");
+            }
+            let (out, into) = CrossfadeCurve::EqualPower.gains(progress);
+            assert!((out * out + into * into - 1.0).abs() < 1e-12, "EqualPower at {progress}");
+        }
+    }
+
+    #[test]
+    fn progress_is_clamped() {
+        for curve in CURVES {
+            assert_eq!(curve.gains(-0.5), curve.gains(0.0));
+            assert_eq!(curve.gains(1.5), curve.gains(1.0));
+        }
+    }
+
+    #[test]
+    fn length_is_capped() {
+        let settings = CrossfadeSettings { duration_ms: 2_000, curve: CrossfadeCurve::Linear };
+        assert_eq!(settings.length_in_samples(44_100, 2), 176_400);
+
+        let settings = CrossfadeSettings { duration_ms: u32::MAX, curve: CrossfadeCurve::Linear };
+        assert_eq!(settings.length_in_samples(48_000, 1), MAX_CROSSFADE_MS as u64 * 48);
+    }
+}
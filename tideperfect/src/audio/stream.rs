@@ -1,132 +1,363 @@
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
 use std::time::Duration;
 use std::{io::Cursor, sync::Arc};
 
-use dash_mpd::{SegmentTimeline, MPD};
-use reqwest::Client;
-use ringbuf::{traits::Producer, CachingProd, HeapRb};
-use stream_download::http::HttpStream;
-use stream_download::storage::temp::TempStorageProvider;
-use stream_download::{Settings, StreamDownload};
-use symphonia::core::{audio::{SampleBuffer}, codecs::{DecoderOptions, CODEC_TYPE_NULL}, formats::FormatOptions, 
-        io::{MediaSourceStream, ReadOnlySource}, meta::MetadataOptions, probe::Hint};
-use tracing::{instrument, trace};
-
-#[instrument(skip(producer, mpd), err)]
-pub async fn stream_dash_audio(mut producer: CachingProd<Arc<HeapRb<i32>>>, mpd: MPD) -> Result<(), String> {
-    trace!("Streaming...");
-    let client = Client::new();
+use bytes::Bytes;
+use reqwest::header::RANGE;
+use snafu::Report;
+use ringbuf::{traits::{Observer, Producer}, CachingProd, HeapRb};
+use symphonia::core::{audio::{AudioBufferRef, SampleBuffer}, codecs::{DecoderOptions, CODEC_TYPE_NULL}, errors::Error as SymphoniaError, formats::{FormatOptions, FormatReader, SeekMode, SeekTo},
+        io::{MediaSource, MediaSourceStream}, meta::MetadataOptions, probe::Hint, units::Time};
+use tokio::{runtime::Handle, sync::{mpsc, Notify}};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, instrument, trace};
+
+use crate::audio::{dash::DashStream, download::{ResumableDownload, UrlRefresh}, dsp::{convolution::{ImpulseResponse, StreamConvolution}, DspFormat}, fetch::{fetch, http_client, SegmentFetcher}, output::SOURCE_SCALE, resample::Resampler};
+
+/// Coordinates a seek between the player loop, the streaming task and the output callback.
+///
+/// The player places a request, which the streaming task picks up before it next pushes samples. Once
+/// it has moved to the new position, the streaming task asks the output callback to flush the ring
+/// buffer, and waits for that to happen before pushing samples from the new position.
+#[derive(Debug, Default)]
+pub struct SeekControl {
+    request: Mutex<Option<Duration>>,
+    flush: AtomicBool,
+    /// Signalled by the output callback once it has flushed the ring buffer.
+    flushed: Notify,
+    samples_played: AtomicU64,
+}
+
+impl SeekControl {
+    /// Request a seek to `position`, replacing any request that hasn't been handled yet.
+    pub fn request(&self, position: Duration) {
+        *self.request.lock().unwrap() = Some(position);
+    }
+
+    fn is_requested(&self) -> bool {
+        self.request.lock().unwrap().is_some()
+    }
+
+    fn take_request(&self) -> Option<Duration> {
+        self.request.lock().unwrap().take()
+    }
+
+    /// Called from the output callback. If the streaming task is waiting for a flush, `flush` is
+    /// called with the new value for `samples_played`, and the streaming task is then released.
+    pub fn flush_with(&self, flush: impl FnOnce(u64)) {
+        if self.flush.load(Ordering::SeqCst) {
+            flush(self.samples_played.load(Ordering::SeqCst));
+            self.flush.store(false, Ordering::SeqCst);
+            self.flushed.notify_one();
+        }
+    }
+
+    async fn flush(&self, samples_played: u64) {
+        self.samples_played.store(samples_played, Ordering::SeqCst);
+        self.flush.store(true, Ordering::SeqCst);
+
+        // A flush that happened before waiting leaves a permit, so it isn't missed
+        while self.flush.load(Ordering::SeqCst) {
+            self.flushed.notified().await;
+        }
+    }
+
+    /// Wait for a flush from a blocking task, giving up if streaming is cancelled.
+    fn flush_blocking(&self, samples_played: u64, cancel: &CancellationToken) {
+        Handle::current().block_on(async {
+            tokio::select! {
+                _ = cancel.cancelled() => {}
+                _ = self.flush(samples_played) => {}
+            }
+        });
+    }
+}
+
+/// Segments downloaded ahead of the decoder.
+const DECODE_AHEAD_SEGMENTS: usize = 2;
+
+/// Wakes the streaming task once the output callback has made room in a full ring buffer, and
+/// readers that wait for samples rather than polling for them.
+#[derive(Debug, Default)]
+pub struct BufferSpace {
+    /// Free space the streaming task is waiting for, or 0 if it isn't waiting.
+    wanted: AtomicUsize,
+    notify: Notify,
+    pushed: Notify,
+}
+
+impl BufferSpace {
+    /// Called from the output callback after reading from the ring buffer. The streaming task is
+    /// only woken once there's as much room as it asked for, rather than after every callback.
+    pub fn freed(&self, vacant: usize) {
+        let wanted = self.wanted.load(Ordering::SeqCst);
+        if wanted != 0 && vacant >= wanted && self.wanted.compare_exchange(wanted, 0, Ordering::SeqCst, Ordering::Relaxed).is_ok() {
+            self.notify.notify_one();
+        }
+    }
+
+    /// Wake the streaming task whether or not there's room, so it sees a seek request.
+    pub fn wake(&self) {
+        self.wanted.store(0, Ordering::SeqCst);
+        self.notify.notify_one();
+    }
+
+    /// Wait until samples are pushed, or `finished` is called. Samples pushed since the last wait
+    /// return straight away.
+    pub async fn pushed(&self) {
+        self.pushed.notified().await;
+    }
+
+    /// Wake a reader waiting in `pushed`, once the streaming task has nothing more to push.
+    pub fn finished(&self) {
+        self.pushed.notify_one();
+    }
+}
 
-    trace!("Getting seg template");
-    let repr = &mpd.periods[0].adaptations[0].representations[0];
-    let seg_template = repr.SegmentTemplate.as_ref().ok_or("No SegmentTemplate")?;
+/// The streaming task's end of a ring buffer. Once the buffer is full, it waits for the output to
+/// free a quarter of it before pushing more.
+pub struct BufferProducer {
+    producer: CachingProd<Arc<HeapRb<i32>>>,
+    space: Arc<BufferSpace>,
+}
 
-    trace!("Getting sample_rate");
-    let sample_rate: u32 = repr.audioSamplingRate
-        .as_ref()
-        .and_then(|s| s.parse().ok())
-        .ok_or("No SampleRate")?;
-    trace!("Sample rate: {sample_rate}");
+impl BufferProducer {
+    pub fn new(producer: CachingProd<Arc<HeapRb<i32>>>, space: Arc<BufferSpace>) -> Self {
+        Self { producer, space }
+    }
 
-    let init_url = seg_template.initialization.as_ref().ok_or("No initialization url")?;
-    trace!("initialization url: {init_url}");
+    /// Push every sample, waiting for room as needed. Returns `false` if a seek is requested first.
+    async fn push(&mut self, samples: Vec<i32>, seek_control: &SeekControl) -> bool {
+        let mut samples = samples.into_iter().peekable();
+        loop {
+            if seek_control.is_requested() {
+                return false;
+            }
 
-    let init_data = client.get(init_url).send().await
-        .map_err(|e| e.to_string())?
-        .bytes().await
-        .map_err(|e| e.to_string())?
-        .to_vec();
+            if self.producer.push_iter(&mut samples) > 0 {
+                self.space.pushed.notify_one();
+            }
+            if samples.peek().is_none() {
+                return true;
+            }
+
+            self.wait_for_space().await;
+        }
+    }
+
+    /// Push samples from a blocking task, giving up if a seek is requested or streaming is cancelled.
+    fn push_blocking(&mut self, samples: Vec<i32>, seek_control: &SeekControl, cancel: &CancellationToken) {
+        Handle::current().block_on(async {
+            tokio::select! {
+                _ = cancel.cancelled() => {}
+                _ = self.push(samples, seek_control) => {}
+            }
+        });
+    }
+
+    async fn wait_for_space(&mut self) {
+        let wanted = (self.producer.capacity().get() / 4).max(1);
+        self.space.wanted.store(wanted, Ordering::SeqCst);
+
+        // The output may have read from the buffer before it could see `wanted`
+        if self.producer.vacant_len() >= wanted {
+            self.space.wanted.store(0, Ordering::SeqCst);
+            return;
+        }
+
+        self.space.notify.notified().await;
+    }
+}
+
+/// Converts a position in the track into the number of interleaved samples before it.
+fn position_to_samples(position: Duration, sample_rate: u32, channels: u16) -> u64 {
+    (position.as_secs_f64() * sample_rate as f64) as u64 * channels as u64
+}
+
+/// Stream a DASH track into `producer` until it ends, or `cancel` is cancelled.
+#[instrument(skip(producer, stream, seek_control, total_samples, cancel), err)]
+pub async fn stream_dash_audio(
+    producer: BufferProducer,
+    stream: DashStream,
+    seek_control: Arc<SeekControl>,
+    total_samples: Arc<AtomicU64>,
+    processing: StreamProcessing,
+    cancel: CancellationToken,
+) -> Result<(), String> {
+    let streamed = cancel.run_until_cancelled(dash_segments(producer, stream, seek_control, total_samples, processing, cancel.clone())).await;
+    if streamed.is_none() {
+        trace!("Streaming cancelled");
+    }
+    streamed.unwrap_or(Ok(()))
+}
+
+async fn dash_segments(
+    mut producer: BufferProducer,
+    stream: DashStream,
+    seek_control: Arc<SeekControl>,
+    total_samples: Arc<AtomicU64>,
+    processing: StreamProcessing,
+    cancel: CancellationToken,
+) -> Result<(), String> {
+    trace!("Streaming...");
+    let mut init_period = 0;
+    let mut init_data = init_segment(&stream, init_period).await?;
 
     let track_info = parse_init_segment(&init_data)?;
     let channels = track_info.channels;
     trace!("Track info: {track_info:#?}");
 
-    let timeline = seg_template.SegmentTimeline.as_ref()
-        .ok_or("No SegmentTimeline")?;
+    let output_rate = processing.output_rate(track_info.sample_rate);
+    // Building a convolution reads the impulse response from disk
+    let mut processor = tokio::task::spawn_blocking(move || processing.processor(track_info.sample_rate, channels))
+        .await
+        .map_err(|e| format!("Blocking task failed: {e}"))??;
 
-    let num_segments = calculate_num_segments(timeline);
+    let segments = &stream.segments;
+    let num_segments = segments.len();
     trace!("Segment count: {num_segments}");
 
-    for seg_num in 1..=num_segments {
-        trace!("Fetching segment {seg_num}/{num_segments}");
+    total_samples.store((stream.duration.as_secs_f64() * output_rate as f64) as u64 * channels as u64, Ordering::Relaxed);
+
+    let mut seg_index = 0;
+    // Samples to drop from the start of the stream, so playback begins at the seeked position
+    let mut skip_samples = 0;
+
+    // Each run decodes a period from `seg_index` to its end, unless a seek interrupts it
+    loop {
+        let period = segments[seg_index].period;
+        if period != init_period {
+            init_period = period;
+            init_data = init_segment(&stream, period).await?;
+        }
+
+        let (segment_tx, segment_rx) = mpsc::channel(DECODE_AHEAD_SEGMENTS);
+        let decoder = {
+            let (init_data, seek_control, cancel) = (init_data.clone(), seek_control.clone(), cancel.clone());
+            tokio::task::spawn_blocking(move || decode_segments(init_data, segment_rx, producer, processor, skip_samples, &seek_control, &cancel))
+        };
+
+        // The next period is decoded with its own init segment
+        let period_segments = segments[seg_index..].iter().take_while(|segment| segment.period == period);
+        let mut fetcher = SegmentFetcher::new(period_segments.map(|segment| segment.url.clone()));
 
-        let seg_url = seg_template.media.as_ref()
-            .ok_or("No media template")?
-            .replace("$Number$", &seg_num.to_string());
+        let mut next_index = seg_index;
+        while !seek_control.is_requested() && let Some(seg_data) = fetcher.next().await {
+            trace!("Fetched segment {}/{num_segments}", next_index + 1);
 
-        let seg_data = client.get(seg_url).send().await
-            .map_err(|e| e.to_string())?
-            .bytes().await
-            .map_err(|e| e.to_string())?
-            .to_vec();
+            // The decoder only stops early for a seek
+            if segment_tx.send(seg_data.map_err(|e| e.to_string())?).await.is_err() {
+                break;
+            }
+            next_index += 1;
+        }
+        // Stops any downloads a seek has made pointless
+        drop(fetcher);
+        drop(segment_tx);
 
-        let mut complete_data = init_data.clone();
-        complete_data.extend_from_slice(&seg_data);
+        (producer, processor) = decoder.await.map_err(|e| format!("Decoding task failed: {e}"))??;
 
-        let mut samples = decode_segment(complete_data)?.into_iter().peekable();
+        match seek_control.take_request() {
+            Some(position) => {
+                seg_index = stream.segment_at(position);
+                skip_samples = position_to_samples(position.saturating_sub(segments[seg_index].start), track_info.sample_rate, channels) as usize;
+                trace!("Seeking to {position:?} (segment {}/{num_segments})", seg_index + 1);
 
-        while samples.peek().is_some() {
-            producer.push_iter(&mut samples);
-            tokio::time::sleep(Duration::from_millis(5)).await;
+                processor.reset();
+                seek_control.flush(position_to_samples(position, output_rate, channels)).await;
+            }
+            None if next_index < num_segments => {
+                seg_index = next_index;
+                skip_samples = 0;
+            }
+            None => break,
         }
     }
 
+    // The resampler and convolution hold on to the end of the track until told there's no more input
+    if let Some(tail) = processor.finish()? {
+        producer.push(tail, &seek_control).await;
+    }
+
     Ok(())
 }
 
-#[derive(Debug)]
-struct TrackInfo {
-    channels: u16,
-    sample_rate: u32,
-    bits_per_sample: u32,
+/// The init segment of `period`. The first period's is usually already downloaded, from probing
+/// the stream.
+async fn init_segment(stream: &DashStream, period: usize) -> Result<Bytes, String> {
+    match (&stream.init_data, period) {
+        (Some(init_data), 0) => Ok(init_data.clone()),
+        _ => fetch(stream.init_urls[period].clone()).await.map_err(|e| e.to_string()),
+    }
 }
 
-#[instrument(skip(data), err)]
-fn parse_init_segment(data: &[u8]) -> Result<TrackInfo, String> {
-    let cursor = Cursor::new(data.to_vec());
-    let mss = MediaSourceStream::new(Box::new(cursor), Default::default());
-
-    let hint = Hint::new();
-    // No hint - let Symphonia auto-detect the format
-
-    trace!("Probing");
-    let probed = symphonia::default::get_probe()
-        .format(&hint, mss, &FormatOptions::default(), &MetadataOptions::default()).map_err(|e| e.to_string())?;
-    trace!("Probed");
+/// Reads the segments of a DASH stream as one fragmented MP4 file: the init segment, followed by
+/// each media segment as it is downloaded.
+struct SegmentReader {
+    current: Cursor<Bytes>,
+    segments: mpsc::Receiver<Bytes>,
+}
 
-    let track = probed.format
-        .tracks()
-        .iter()
-        .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
-        .ok_or("No audio track")?;
+impl Read for SegmentReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let read = self.current.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
 
-    let channels = track.codec_params.channels
-        .map(|c| c.count() as u16)
-        .ok_or("No channels?")?;
-    let sample_rate = track.codec_params.sample_rate.ok_or("No sample rate?")?;
-    let bits_per_sample = track.codec_params.bits_per_sample.ok_or("No bits_per_sample")?;
+            // Waits for the next segment, and ends the stream once there are none left
+            match self.segments.blocking_recv() {
+                Some(segment) => self.current = Cursor::new(segment),
+                None => return Ok(0),
+            }
+        }
+    }
+}
 
-    Ok(TrackInfo { channels, sample_rate, bits_per_sample })
+impl Seek for SegmentReader {
+    fn seek(&mut self, _: SeekFrom) -> std::io::Result<u64> {
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
 }
 
-#[instrument]
-fn calculate_num_segments(timeline: &SegmentTimeline) -> u64 {
-    let mut total = 0u64;
-    for s in &timeline.segments {
-        total += 1 + s.r.unwrap_or(0) as u64;
+impl MediaSource for SegmentReader {
+    fn is_seekable(&self) -> bool {
+        false
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        None
     }
-    total
 }
 
-#[instrument(skip(data), err)]
-fn decode_segment(data: Vec<u8>) -> Result<Vec<i32>, String> {
-    let cursor = Cursor::new(data);
-    let mss = MediaSourceStream::new(Box::new(cursor), Default::default());
+/// Decode the segments of a DASH period with a single demuxer and decoder, so there's nothing to
+/// reset between segments. Runs until the segments run out or a seek is requested, then hands
+/// back the producer and processor for the next run.
+#[instrument(skip_all, err)]
+fn decode_segments(
+    init_data: Bytes,
+    segments: mpsc::Receiver<Bytes>,
+    mut producer: BufferProducer,
+    mut processor: SampleProcessor,
+    mut skip_samples: usize,
+    seek_control: &SeekControl,
+    cancel: &CancellationToken,
+) -> Result<(BufferProducer, SampleProcessor), String> {
+    let reader = SegmentReader { current: Cursor::new(init_data), segments };
+    let mss = MediaSourceStream::new(Box::new(reader), Default::default());
 
     let mut hint = Hint::new();
     hint.with_extension("mp4");
 
-    let probed = symphonia::default::get_probe()
-        .format(&hint, mss, &FormatOptions::default(), &MetadataOptions::default()).map_err(|e| e.to_string())?;
+    // A seek stops the download, which can cut the stream off before the first segment
+    let probed = match symphonia::default::get_probe().format(&hint, mss, &FormatOptions::default(), &MetadataOptions::default()) {
+        Ok(probed) => probed,
+        Err(_) if seek_control.is_requested() || cancel.is_cancelled() => return Ok((producer, processor)),
+        Err(e) => return Err(e.to_string()),
+    };
 
     let mut format = probed.format;
 
@@ -140,71 +371,349 @@ fn decode_segment(data: Vec<u8>) -> Result<Vec<i32>, String> {
         .make(&track.codec_params, &DecoderOptions::default()).map_err(|e| e.to_string())?;
 
     let track_id = track.id;
-    let mut sample_buf: Option<SampleBuffer<i32>> = None;
-    let mut samples = Vec::new();
+    let mut sample_buf: Option<DecodeBuffer> = None;
+
+    loop {
+        if seek_control.is_requested() || cancel.is_cancelled() {
+            return Ok((producer, processor));
+        }
+
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(SymphoniaError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            // Or part way through a segment
+            Err(_) if seek_control.is_requested() => return Ok((producer, processor)),
+            Err(e) => return Err(e.to_string()),
+        };
 
-    while let Ok(packet) = format.next_packet() {
         if packet.track_id() != track_id {
             continue;
         }
 
         let decoded = decoder.decode(&packet).map_err(|e| e.to_string())?;
+        let decoded = sample_buf.get_or_insert_with(|| DecodeBuffer::new(&decoded)).copy(decoded);
+
+        let skipped = skip_samples.min(decoded.len());
+        skip_samples -= skipped;
+
+        let samples = processor.process(&decoded[skipped..])?;
+        producer.push_blocking(samples, seek_control, cancel);
+    }
+
+    trace!("Finished decoding segments");
+    Ok((producer, processor))
+}
 
-        if sample_buf.is_none() {
-            let spec = *decoded.spec();
-            let duration = decoded.capacity() as u64;
-            sample_buf = Some(SampleBuffer::<i32>::new(duration, spec));
+/// Interleaves decoded audio into samples for the ring buffer. Lossy codecs decode to floats, which
+/// are scaled straight into the buffer rather than converted to integers by Symphonia.
+enum DecodeBuffer {
+    Integer(SampleBuffer<i32>),
+    Float(SampleBuffer<f32>, Vec<i32>),
+}
+
+impl DecodeBuffer {
+    fn new(decoded: &AudioBufferRef) -> Self {
+        let spec = *decoded.spec();
+        let duration = decoded.capacity() as u64;
+
+        match decoded {
+            AudioBufferRef::F32(_) | AudioBufferRef::F64(_) => Self::Float(SampleBuffer::new(duration, spec), Vec::new()),
+            _ => Self::Integer(SampleBuffer::new(duration, spec)),
         }
+    }
 
-        if let Some(ref mut buf) = sample_buf {
-            buf.copy_interleaved_ref(decoded);
-            samples.extend(buf.samples());
+    fn copy(&mut self, decoded: AudioBufferRef) -> &[i32] {
+        match self {
+            Self::Integer(buf) => {
+                buf.copy_interleaved_ref(decoded);
+                buf.samples()
+            }
+            Self::Float(buf, scaled) => {
+                buf.copy_interleaved_ref(decoded);
+                scaled.clear();
+                scaled.extend(buf.samples().iter().map(|sample| {
+                    (*sample as f64 * SOURCE_SCALE).round().clamp(i32::MIN as f64, i32::MAX as f64) as i32
+                }));
+                scaled
+            }
         }
     }
+}
 
-    Ok(samples)
+/// What is done to a track's samples between decoding them and pushing them to the ring buffer.
+#[derive(Debug, Clone, Default)]
+pub struct StreamProcessing {
+    /// The sample rate to resample to, if the track isn't played at its own rate.
+    pub resample_to: Option<u32>,
+    /// The impulse response to convolve with, made for the rate the track is played at.
+    pub impulse: Option<ImpulseResponse>,
 }
 
-#[instrument(skip(producer), err)]
-pub async fn stream_url(mut producer: CachingProd<Arc<HeapRb<i32>>>, url: String) -> Result<(), String> {
-    trace!("Streaming URL: {}", url);
+impl StreamProcessing {
+    fn output_rate(&self, sample_rate: u32) -> u32 {
+        self.resample_to.unwrap_or(sample_rate)
+    }
+
+    /// Build the processor for a track decoded at `sample_rate`. A convolution that can't be built
+    /// is skipped, rather than stopping the track from playing.
+    fn processor(&self, sample_rate: u32, channels: u16) -> Result<SampleProcessor, String> {
+        let resampler = match self.resample_to {
+            Some(rate) if rate != sample_rate => {
+                trace!("Resampling from {sample_rate} Hz to {rate} Hz");
+                Some(Resampler::new(sample_rate, rate, channels).map_err(|e| e.to_string())?)
+            }
+            _ => None,
+        };
+
+        let format = DspFormat { sample_rate: self.output_rate(sample_rate), channels };
+        let convolution = self.impulse.as_ref().and_then(|impulse| match StreamConvolution::new(impulse, format) {
+            Ok(convolution) => Some(convolution),
+            Err(e) => {
+                error!("Skipping convolution: {}", Report::from_error(e));
+                None
+            }
+        });
+
+        Ok(SampleProcessor { resampler, convolution, finished: false })
+    }
+}
+
+/// Resamples and convolves decoded samples, in the streaming task so the output callback only has
+/// cheap work left to do.
+struct SampleProcessor {
+    resampler: Option<Resampler>,
+    convolution: Option<StreamConvolution>,
+    finished: bool,
+}
+
+impl SampleProcessor {
+    /// Process `samples`. Input can be held back, so the output may be shorter or empty.
+    fn process(&mut self, samples: &[i32]) -> Result<Vec<i32>, String> {
+        self.finished = false;
+
+        let samples = match self.resampler.as_mut() {
+            Some(resampler) => resampler.process(samples).map_err(|e| e.to_string())?,
+            None => samples.to_vec(),
+        };
+
+        Ok(match self.convolution.as_mut() {
+            Some(convolution) => convolution.process(&samples),
+            None => samples,
+        })
+    }
+
+    /// Everything still held back at the end of the track. Returns `None` if there is nothing to
+    /// flush, or this has already been done.
+    fn finish(&mut self) -> Result<Option<Vec<i32>>, String> {
+        if self.finished || (self.resampler.is_none() && self.convolution.is_none()) {
+            return Ok(None);
+        }
+        self.finished = true;
+
+        let mut tail = match self.resampler.as_mut() {
+            Some(resampler) => resampler.finish().map_err(|e| e.to_string())?.unwrap_or_default(),
+            None => Vec::new(),
+        };
+        if let Some(convolution) = self.convolution.as_mut() {
+            tail = convolution.process(&tail);
+            tail.extend(convolution.finish());
+        }
+
+        Ok(Some(tail))
+    }
+
+    /// Drop everything held back, after a seek.
+    fn reset(&mut self) {
+        if let Some(resampler) = self.resampler.as_mut() {
+            resampler.reset();
+        }
+        if let Some(convolution) = self.convolution.as_mut() {
+            convolution.reset();
+        }
+        self.finished = false;
+    }
+}
+
+/// The format of a stream, as read from the stream itself.
+#[derive(Debug, Clone, Copy)]
+pub struct TrackInfo {
+    pub channels: u16,
+    pub sample_rate: u32,
+    /// Bit depth of the source. Lossy codecs don't have one.
+    pub bits_per_sample: Option<u32>,
+}
+
+/// How much of the start of a file is downloaded to probe it, which is enough for the `moov` atom
+/// of most MP4 files.
+const FILE_PROBE_LEN: usize = 256 * 1024;
 
-    // Create HTTP stream with temporary file storage
-    let stream = HttpStream::new(
-        Client::new(),
-        url.parse().map_err(|e: url::ParseError| e.to_string())?
-    ).await.map_err(|e| e.to_string())?;
+/// Length of the "fLaC" marker, block header and STREAMINFO block that start every FLAC file.
+const FLAC_HEADER_LEN: usize = 42;
 
-    // Configure streaming settings to prefetch enough data for FLAC headers
-    // FLAC headers need to be fully available before Symphonia can probe
-    let settings = Settings::default()
-        .prefetch_bytes(2 * 1024 * 1024); // Prefetch 2MB to ensure headers are available
+/// Read the format of the FLAC file at `url` from its STREAMINFO block, without downloading the
+/// rest of the file.
+#[instrument(err)]
+pub async fn probe_flac(url: &str) -> Result<TrackInfo, String> {
+    let header = fetch_start(url, FLAC_HEADER_LEN).await?;
 
-    trace!("Creating stream download reader");
+    parse_streaminfo(&header)
+}
+
+/// Download the first `len` bytes of the file at `url`, or all of it if it is shorter.
+async fn fetch_start(url: &str, len: usize) -> Result<Vec<u8>, String> {
+    let mut response = http_client()
+        .get(url)
+        .header(RANGE, format!("bytes=0-{}", len - 1))
+        .send().await
+        .map_err(|e| e.to_string())?;
+
+    // The server may ignore the range, so stop reading as soon as enough has arrived
+    let mut start = Vec::with_capacity(len);
+    while start.len() < len && let Some(chunk) = response.chunk().await.map_err(|e| e.to_string())? {
+        start.extend_from_slice(&chunk);
+    }
+    start.truncate(len);
+
+    Ok(start)
+}
+
+fn parse_streaminfo(header: &[u8]) -> Result<TrackInfo, String> {
+    if header.len() < FLAC_HEADER_LEN || &header[..4] != b"fLaC" {
+        return Err("Not a FLAC stream".to_owned());
+    }
 
-    // Create the stream download reader - this downloads in background while we read
-    let reader = StreamDownload::from_stream(
-        stream,
-        TempStorageProvider::default(),
-        settings
-    ).await.map_err(|e| e.to_string())?;
+    // STREAMINFO is always the first metadata block, and has type 0
+    if header[4] & 0x7F != 0 {
+        return Err("FLAC stream does not start with STREAMINFO".to_owned());
+    }
 
-    trace!("Streaming reader created, spawning blocking decode task");
+    // Skip the block header, and the block and frame sizes at the start of STREAMINFO
+    let info = &header[18..];
+    let sample_rate = ((info[0] as u32) << 12) | ((info[1] as u32) << 4) | ((info[2] as u32) >> 4);
+    let channels = ((info[2] >> 1) & 0x07) as u16 + 1;
+    let bits_per_sample = (((info[2] & 0x01) << 4) | (info[3] >> 4)) as u32 + 1;
 
-    // Run the synchronous decoding work in a blocking task to avoid blocking the async runtime
+    Ok(TrackInfo { channels, sample_rate, bits_per_sample: Some(bits_per_sample) })
+}
+
+/// Read the format of a DASH stream from its init segment, which is kept for streaming to start
+/// with. This is also where the chosen representation's bit depth is found out.
+#[instrument(skip(stream), err)]
+pub async fn probe_dash(stream: &mut DashStream) -> Result<TrackInfo, String> {
+    let init_data = init_segment(stream, 0).await?;
+    let info = parse_init_segment(&init_data)?;
+    stream.init_data = Some(init_data);
+    stream.representation.bits_per_sample = info.bits_per_sample;
+
+    Ok(info)
+}
+
+#[instrument(skip(data), err)]
+fn parse_init_segment(data: &[u8]) -> Result<TrackInfo, String> {
+    let cursor = Cursor::new(data.to_vec());
+    let mss = MediaSourceStream::new(Box::new(cursor), Default::default());
+
+    let hint = Hint::new();
+    // No hint - let Symphonia auto-detect the format
+
+    trace!("Probing");
+    let probed = symphonia::default::get_probe()
+        .format(&hint, mss, &FormatOptions::default(), &MetadataOptions::default()).map_err(|e| e.to_string())?;
+    trace!("Probed");
+
+    track_info(probed.format.as_ref())
+}
+
+/// Read the format of the file at `url` by probing the start of it. An MP4 file with its `moov`
+/// atom at the end can't be probed from the start alone, so it is probed through a download that
+/// seeks there, and is stopped once the format has been read.
+#[instrument(skip(refresh), err)]
+pub async fn probe_file(url: &str, refresh: Option<UrlRefresh>) -> Result<TrackInfo, String> {
+    let start = fetch_start(url, FILE_PROBE_LEN).await?;
+    match tokio::task::spawn_blocking(move || probe_source(Box::new(Cursor::new(start))))
+        .await
+        .map_err(|e| format!("Blocking task failed: {e}"))? {
+        Ok(info) => return Ok(info),
+        Err(e) => trace!("Could not probe the start of the file, probing all of it: {e}"),
+    }
+
+    let download = ResumableDownload::start(url.to_owned(), refresh, CancellationToken::new()).await?;
+    tokio::task::spawn_blocking(move || probe_source(Box::new(download)))
+        .await
+        .map_err(|e| format!("Blocking task failed: {e}"))?
+}
+
+fn probe_source(source: Box<dyn MediaSource>) -> Result<TrackInfo, String> {
+    let mss = MediaSourceStream::new(source, Default::default());
+    let probed = symphonia::default::get_probe()
+        .format(&Hint::new(), mss, &FormatOptions::default(), &MetadataOptions::default()).map_err(|e| e.to_string())?;
+
+    track_info(probed.format.as_ref())
+}
+
+fn track_info(format: &dyn FormatReader) -> Result<TrackInfo, String> {
+    let track = format
+        .tracks()
+        .iter()
+        .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+        .ok_or("No audio track")?;
+
+    let channels = track.codec_params.channels
+        .map(|c| c.count() as u16)
+        .ok_or("No channels?")?;
+    let sample_rate = track.codec_params.sample_rate.ok_or("No sample rate?")?;
+    let bits_per_sample = track.codec_params.bits_per_sample;
+
+    Ok(TrackInfo { channels, sample_rate, bits_per_sample })
+}
+
+/// Stream a FLAC or AAC file into `producer` until it ends, or `cancel` is cancelled. If the link expires
+/// part way through, `refresh` is used to get a new one.
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip(producer, seek_control, total_samples, refresh, cancel), err)]
+pub async fn stream_url(
+    producer: BufferProducer,
+    url: String,
+    refresh: Option<UrlRefresh>,
+    seek_control: Arc<SeekControl>,
+    total_samples: Arc<AtomicU64>,
+    processing: StreamProcessing,
+    cancel: CancellationToken,
+) -> Result<(), String> {
+    let streamed = cancel.run_until_cancelled(stream_file(producer, url, refresh, seek_control, total_samples, processing, cancel.clone())).await;
+    if streamed.is_none() {
+        trace!("Streaming cancelled");
+    }
+    streamed.unwrap_or(Ok(()))
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn stream_file(
+    mut producer: BufferProducer,
+    url: String,
+    refresh: Option<UrlRefresh>,
+    seek_control: Arc<SeekControl>,
+    total_samples: Arc<AtomicU64>,
+    processing: StreamProcessing,
+    cancel: CancellationToken,
+) -> Result<(), String> {
+    trace!("Streaming URL: {}", url);
+
+    let reader = ResumableDownload::start(url, refresh, cancel.clone()).await?;
+
+    trace!("Download started, spawning blocking decode task");
+
+    // Run the synchronous decoding work in a blocking task to avoid blocking the async runtime.
+    // Blocking tasks can't be aborted, so it checks `cancel` itself, and dropping the reader when
+    // it returns stops the download.
     tokio::task::spawn_blocking(move || -> Result<(), String> {
         trace!("In blocking task, setting up decoder");
 
-        // Wrap the reader with ReadOnlySource (which implements MediaSource)
-        let media_source = ReadOnlySource::new(reader);
-        let mss = MediaSourceStream::new(Box::new(media_source), Default::default());
-
-        let mut hint = Hint::new();
-        hint.with_extension("flac");
+        let mss = MediaSourceStream::new(Box::new(reader), Default::default());
 
-        trace!("Probing FLAC stream");
+        trace!("Probing stream");
         let probed = symphonia::default::get_probe()
-            .format(&hint, mss, &FormatOptions::default(), &MetadataOptions::default())
+            .format(&Hint::new(), mss, &FormatOptions::default(), &MetadataOptions::default())
             .map_err(|e| e.to_string())?;
 
         let mut format = probed.format;
@@ -215,40 +724,72 @@ pub async fn stream_url(mut producer: CachingProd<Arc<HeapRb<i32>>>, url: String
             .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
             .ok_or("No audio track found")?;
 
-        trace!("Creating decoder for FLAC stream");
+        trace!("Creating decoder for {:?}", track.codec_params.codec);
         let mut decoder = symphonia::default::get_codecs()
             .make(&track.codec_params, &DecoderOptions::default())
             .map_err(|e| e.to_string())?;
 
         let track_id = track.id;
-        let mut sample_buf: Option<SampleBuffer<i32>> = None;
+        let sample_rate = track.codec_params.sample_rate.ok_or("No sample rate")?;
+        let channels = track.codec_params.channels.map(|c| c.count() as u16).ok_or("No channels")?;
+        let mut processor = processing.processor(sample_rate, channels)?;
+        let output_rate = processing.output_rate(sample_rate);
+        if let Some(n_frames) = track.codec_params.n_frames {
+            total_samples.store(n_frames * output_rate as u64 / sample_rate as u64 * channels as u64, Ordering::Relaxed);
+        }
+        let mut sample_buf: Option<DecodeBuffer> = None;
+        // Samples to drop after an accurate seek, which lands on the packet containing the position
+        let mut skip_samples = 0;
 
         trace!("Decoding and streaming audio packets");
         // As packets arrive from the HTTP stream (downloaded in background),
         // decode and push them to the producer. This happens incrementally -
         // we don't wait for the full file to download
-        while let Ok(packet) = format.next_packet() {
-            if packet.track_id() != track_id {
-                continue;
+        loop {
+            if cancel.is_cancelled() {
+                trace!("Decoding cancelled");
+                return Ok(());
             }
 
-            let decoded = decoder.decode(&packet).map_err(|e| e.to_string())?;
+            if let Some(position) = seek_control.take_request() {
+                trace!("Seeking to {position:?}");
+                let seeked = format.seek(SeekMode::Accurate, SeekTo::Time { time: Time::from(position.as_secs_f64()), track_id: Some(track_id) })
+                    .map_err(|e| e.to_string())?;
+                decoder.reset();
+                processor.reset();
 
-            if sample_buf.is_none() {
-                let spec = *decoded.spec();
-                let duration = decoded.capacity() as u64;
-                sample_buf = Some(SampleBuffer::<i32>::new(duration, spec));
+                skip_samples = (seeked.required_ts.saturating_sub(seeked.actual_ts) * channels as u64) as usize;
+                seek_control.flush_blocking(position_to_samples(position, output_rate, channels), &cancel);
             }
 
-            if let Some(ref mut buf) = sample_buf {
-                buf.copy_interleaved_ref(decoded);
-
-                let mut samples = buf.samples().iter().copied().peekable();
-                while samples.peek().is_some() {
-                    producer.push_iter(&mut samples);
-                    std::thread::sleep(Duration::from_millis(5));
+            let packet = match format.next_packet() {
+                Ok(packet) => packet,
+                // A seek may still be requested after the last packet, while the end of the track plays
+                Err(_) if seek_control.is_requested() => continue,
+                Err(_) => {
+                    // The resampler and convolution hold on to the end of the track until told there's no more input
+                    match processor.finish()? {
+                        Some(tail) => {
+                            producer.push_blocking(tail, &seek_control, &cancel);
+                            continue;
+                        }
+                        None => break,
+                    }
                 }
+            };
+
+            if packet.track_id() != track_id {
+                continue;
             }
+
+            let decoded = decoder.decode(&packet).map_err(|e| e.to_string())?;
+            let decoded = sample_buf.get_or_insert_with(|| DecodeBuffer::new(&decoded)).copy(decoded);
+
+            let skipped = skip_samples.min(decoded.len());
+            skip_samples -= skipped;
+
+            let samples = processor.process(&decoded[skipped..])?;
+            producer.push_blocking(samples, &seek_control, &cancel);
         }
 
         trace!("Finished streaming URL");
@@ -259,3 +800,59 @@ pub async fn stream_url(mut producer: CachingProd<Arc<HeapRb<i32>>>, url: String
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A FLAC header with just a STREAMINFO block, in the layout the FLAC format gives it.
+    fn flac_header(sample_rate: u32, channels: u8, bits_per_sample: u8) -> Vec<u8> {
+        let mut header = b"fLaC".to_vec();
+        // Last metadata block, of type STREAMINFO and 34 bytes long
+        header.extend([0x80, 0, 0, 34]);
+        // Block and frame sizes
+        header.extend([0x10, 0x00, 0x10, 0x00, 0, 0, 0, 0, 0, 0]);
+        header.extend([
+            (sample_rate >> 12) as u8,
+            (sample_rate >> 4) as u8,
+            ((sample_rate as u8 & 0x0F) << 4) | ((channels - 1) << 1) | ((bits_per_sample - 1) >> 4),
+            ((bits_per_sample - 1) & 0x0F) << 4,
+        ]);
+        // Rest of the sample count, and the MD5 signature
+        header.extend([0; 20]);
+        assert_eq!(header.len(), FLAC_HEADER_LEN);
+        header
+    }
+
+    #[test]
+    fn parses_hi_res_streaminfo() {
+        let info = parse_streaminfo(&flac_header(96_000, 2, 24)).unwrap();
+
+        assert_eq!((info.sample_rate, info.channels, info.bits_per_sample), (96_000, 2, Some(24)));
+    }
+
+    #[test]
+    fn parses_mono_streaminfo() {
+        let info = parse_streaminfo(&flac_header(44_100, 1, 16)).unwrap();
+
+        assert_eq!((info.sample_rate, info.channels, info.bits_per_sample), (44_100, 1, Some(16)));
+    }
+
+    #[test]
+    fn rejects_other_streams() {
+        let mut header = flac_header(44_100, 2, 16);
+        header[..4].copy_from_slice(b"OggS");
+
+        assert!(parse_streaminfo(&header).is_err());
+        assert!(parse_streaminfo(b"fLaC").is_err());
+    }
+
+    #[test]
+    fn rejects_missing_streaminfo() {
+        let mut header = flac_header(44_100, 2, 16);
+        // A VORBIS_COMMENT block first
+        header[4] = 0x04;
+
+        assert!(parse_streaminfo(&header).is_err());
+    }
+}
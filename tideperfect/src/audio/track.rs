@@ -1,26 +1,46 @@
 use std::{string::FromUtf8Error, sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Arc}, time::Duration};
 
 use base64::prelude::*;
-use cpal::{traits::{DeviceTrait, StreamTrait}, Device, Sample, SampleFormat, SampleRate, Stream};
-use dash_mpd::MPD;
-use ringbuf::{traits::{Consumer, Observer, Split}, CachingCons, CachingProd, HeapRb};
+use ringbuf::{traits::{Observer, Split}, CachingProd, HeapRb};
 use serde::Deserialize;
 use snafu::{ResultExt, Snafu};
 use tokio::{sync::{broadcast, mpsc}, task::JoinHandle, time::sleep};
-use tidalrs::{TidalClient, Track as TidalTrack, TrackDashPlaybackInfo};
+use tokio_util::sync::CancellationToken;
+use tidalrs::{AudioQuality, TidalApiError, TidalClient, Track as TidalTrack, TrackDashPlaybackInfo};
 use tracing::{error, info, instrument, trace, warn};
 
-use crate::{audio::{player::{PlayerCommand, PlayerEvent}, stream::{stream_dash_audio, stream_url}}, Event};
+use crate::{audio::{dash::{quality_rank, DashError, DashStream}, output::Source, player::{NowPlaying, PlayerCommand, PlayerEvent}, replay_gain::{db_to_gain, ReplayGain}, download::UrlRefresh, stream::{probe_dash, probe_file, probe_flac, stream_dash_audio, stream_url, BufferProducer, BufferSpace, SeekControl, StreamProcessing, TrackInfo}, dsp::convolution::ImpulseResponse}, Event};
 
 pub struct Track {
     pub metadata: TrackMetadata,
     pub track: TidalTrack,
+    /// The quality TIDAL is serving the track in.
+    pub quality: AudioQuality,
     pub buffer: Arc<HeapRb<i32>>,
-    pub stream: Option<Stream>,
-    pub mpd: Option<MPD>,
+    pub source: Option<Source>,
+    pub source_token: Option<u64>,
+    pub dash: Option<DashStream>,
     pub url: Option<String>,
     pub samples_played: Arc<AtomicU64>,
+    /// Length of the track in interleaved samples. Estimated from the track duration until the
+    /// streaming task reads the real length.
+    pub total_samples: Arc<AtomicU64>,
     pub progress_handle: Option<JoinHandle<()>>,
+    pub stream_handle: Option<JoinHandle<()>>,
+    /// Stops the streaming task, including any download or decoding it has running.
+    pub stream_cancel: CancellationToken,
+    pub streaming_done: Arc<AtomicBool>,
+    pub seek_control: Arc<SeekControl>,
+    pub buffer_space: Arc<BufferSpace>,
+    pub replay_gain: ReplayGain,
+    /// The ReplayGain applied to the track in dB, or `None` if it is played as-is.
+    pub applied_gain: Option<f64>,
+    /// The sample rate the track is resampled to, if it isn't played at its own rate.
+    pub resample_to: Option<u32>,
+    /// The impulse response the track is convolved with as it is decoded, if any.
+    pub impulse: Option<ImpulseResponse>,
+    /// Used to get a new link to the file when the current one expires.
+    client: Arc<TidalClient>,
 }
 
 impl std::fmt::Debug for Track {
@@ -28,97 +48,180 @@ impl std::fmt::Debug for Track {
         f.debug_struct("PlayerTrack")
             .field("metadata", &self.metadata)
             .field("buffer", &format!("[i32; {}]", self.buffer.occupied_len() + self.buffer.vacant_len()))
-            .field("mpd", &"MPD {...}")
+            .field("dash", &self.dash.as_ref().map(|dash| &dash.representation))
             .finish()
     }
 }
 
+impl Drop for Track {
+    fn drop(&mut self) {
+        self.stream_cancel.cancel();
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct TrackMetadata {
     pub id: u64,
     pub sample_rate: u32,
-    pub sample_size: u32,
+    /// Bit depth of the source, or `None` for lossy codecs, which decode to floats.
+    pub sample_size: Option<u32>,
     pub channels: u16,
 }
 
+impl TrackMetadata {
+    fn from_info(id: u64, info: TrackInfo) -> Self {
+        Self {
+            id,
+            sample_rate: info.sample_rate,
+            sample_size: info.bits_per_sample,
+            channels: info.channels,
+        }
+    }
+}
+
+/// A manifest linking to the whole file, which is FLAC, or AAC for the lower qualities.
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
-struct FlacManifest {
+struct UrlManifest {
     _mime_type: String,
-    _codecs: String,
+    codecs: String,
     _encryption_type: String,
     urls: Vec<String>,
 }
 
 const BUFFER_SIZE_SECONDS: usize = 5;
 
+/// The next quality down from `quality`, if there is one.
+fn lower_quality(quality: AudioQuality) -> Option<AudioQuality> {
+    match quality {
+        AudioQuality::HiResLossless => Some(AudioQuality::Lossless),
+        AudioQuality::Lossless => Some(AudioQuality::High),
+        AudioQuality::High => Some(AudioQuality::Low),
+        AudioQuality::Low => None,
+    }
+}
+
+/// Whether `error` means the requested quality isn't available, rather than the request failing
+/// for some other reason such as an expired session. TIDAL refuses qualities the subscription
+/// doesn't include with a 403, and files that don't exist yet with sub-status 4005.
+fn is_quality_unavailable(error: &tidalrs::Error) -> bool {
+    matches!(error, tidalrs::Error::TidalApiError(TidalApiError { status: 403, .. } | TidalApiError { sub_status: 4005, .. }))
+}
+
+/// Gets a new link to the file of track `id` from TIDAL, in `quality`.
+fn url_refresh(client: Arc<TidalClient>, id: u64, quality: AudioQuality) -> UrlRefresh {
+    Arc::new(move || {
+        let client = client.clone();
+        Box::pin(async move {
+            let stream = client.track_dash_playback_info(id, quality).await.map_err(|e| e.to_string())?;
+            let manifest = decode_manifest(stream.manifest).map_err(|e| e.to_string())?;
+            let manifest: UrlManifest = serde_json::from_str(&manifest).map_err(|e| e.to_string())?;
+            manifest.urls.into_iter().next().ok_or_else(|| "Manifest has no links".to_owned())
+        })
+    })
+}
+
+/// Decode the base64 manifest TIDAL sends with a track.
+fn decode_manifest(manifest: String) -> Result<String, TrackError> {
+    let manifest = BASE64_STANDARD.decode(manifest).context(Base64Snafu)?;
+    String::from_utf8(manifest).context(UTF8Snafu)
+}
+
 impl Track {
 
     #[instrument(skip(client), err)]
-    pub async fn fetch(client: &TidalClient, id: u64) -> Result<Self, TrackError> {
+    pub async fn fetch(client: &Arc<TidalClient>, id: u64, quality: AudioQuality) -> Result<Self, TrackError> {
         info!("Fetching track with id {id}");
         let track = client.track(id).await.context(TidalSnafu)?;
-        Self::fetch_from_track(client, &track).await
+        Self::fetch_from_track(client, &track, quality).await
     }
 
-    #[instrument(skip_all, err)]
-    pub async fn fetch_from_track(client: &TidalClient, track: &TidalTrack) -> Result<Self, TrackError> {
+    /// Fetch the track in `quality`, or the best quality below it that the account and track
+    /// offer.
+    #[instrument(skip(client, track), err)]
+    pub async fn fetch_from_track(client: &Arc<TidalClient>, track: &TidalTrack, quality: AudioQuality) -> Result<Self, TrackError> {
         info!("Fetching track from supplied tidal track");
-        let stream = client.track_dash_playback_info(track.id, tidalrs::AudioQuality::HiResLossless).await
-            .context(TidalSnafu)?;
+        let mut requested = quality;
+        let stream = loop {
+            let result = client.track_dash_playback_info(track.id, requested).await;
+            match (result, lower_quality(requested)) {
+                (Ok(stream), _) => break stream,
+                // The account's subscription or the track doesn't include this quality. Session
+                // errors fall through, as a lower quality won't fix them.
+                (Err(e), Some(lower)) if is_quality_unavailable(&e) => {
+                    warn!("{requested:?} is not available for track #{}, trying {lower:?}", track.id);
+                    requested = lower;
+                }
+                (Err(e), _) => return Err(e).context(TidalSnafu),
+            }
+        };
 
-        Self::parse_manifest(stream, track)
+        if stream.audio_quality != quality {
+            info!("Requested {quality:?}, got {:?}", stream.audio_quality);
+        }
+
+        Self::parse_manifest(client, stream, track).await
     }
 
-    fn parse_manifest(stream: TrackDashPlaybackInfo, track: &TidalTrack) -> Result<Self, TrackError> {
-        let manifest = BASE64_STANDARD.decode(stream.manifest).context(Base64Snafu)?;
-        let manifest = String::from_utf8(manifest).context(UTF8Snafu)?;
+    /// Build the track from its manifest. The stream is probed for its format, so the output can be
+    /// opened to match it.
+    async fn parse_manifest(client: &Arc<TidalClient>, stream: TrackDashPlaybackInfo, track: &TidalTrack) -> Result<Self, TrackError> {
+        let replay_gain = ReplayGain::from(&stream);
+        let quality = stream.audio_quality;
+        let manifest = decode_manifest(stream.manifest)?;
 
         // check first character of manifest
         //  - if '{', then it is json containing a link to the track audio file
         //  - if '<', then it is a MPEG-DASH manifest, formatted with XML
         match manifest.chars().next().expect("Manifest from Tidal is empty - this is a bug") {
             '{' => {
-                let manifest: FlacManifest = serde_json::from_str(&manifest).context(SerdeSnafu)?;
-
-                // TODO: Proper sample rate
-                let sample_rate = 44100;
-                let channels = 2;
-                let sample_size = 16;
-
-                let metadata = TrackMetadata {
-                    id: track.id,
-                    sample_rate,
-                    sample_size,
-                    channels,
+                let manifest: UrlManifest = serde_json::from_str(&manifest).context(SerdeSnafu)?;
+                // FLAC's format can be read from the start of the file, but AAC needs probing
+                let info = match manifest.codecs.as_str() {
+                    "flac" => probe_flac(&manifest.urls[0]).await,
+                    _ => probe_file(&manifest.urls[0], Some(url_refresh(client.clone(), track.id, quality))).await,
                 };
+                let info = info.map_err(|message| TrackError::StreamFormat { message })?;
+                let metadata = TrackMetadata::from_info(track.id, info);
+                let sample_rate = metadata.sample_rate;
 
                 let buffer = Arc::new(HeapRb::<i32>::new(BUFFER_SIZE_SECONDS * sample_rate as usize));
 
                 Ok(Self {
                     metadata,
                     track: track.clone(),
+                    quality,
                     buffer,
                     samples_played: Arc::new(AtomicU64::new(0)),
-                    stream: None,
-                    mpd: None,
+                    total_samples: Arc::new(AtomicU64::new(Self::estimate_total_samples(track, &metadata))),
+                    source: None,
+                    source_token: None,
+                    dash: None,
                     url: Some(manifest.urls[0].clone()),
                     progress_handle: None,
+                    stream_handle: None,
+                    stream_cancel: CancellationToken::new(),
+                    streaming_done: Arc::new(AtomicBool::new(false)),
+                    seek_control: Arc::new(SeekControl::default()),
+                    buffer_space: Arc::new(BufferSpace::default()),
+                    replay_gain,
+                    applied_gain: None,
+                    resample_to: None,
+                    impulse: None,
+                    client: client.clone(),
                 })
             },
             '<' => {
-                let mpd = dash_mpd::parse(&manifest).context(MPDSnafu)?;
-
-                // TODO: Is this an issue for any song?
-                let sample_rate = mpd.periods[0].adaptations[0].representations[0].audioSamplingRate.clone().unwrap().parse().unwrap();
-                let channels = 2;
-                let sample_size = 24;
-
-                let metadata = TrackMetadata {
-                    id: track.id,
-                    sample_rate,
-                    sample_size,
-                    channels,
+                let mpd = dash_mpd::parse(&manifest).context(MpdSnafu)?;
+                let mut dash = DashStream::select(&mpd, quality).context(DashSnafu)?;
+                let info = probe_dash(&mut dash).await
+                    .map_err(|message| TrackError::StreamFormat { message })?;
+                let metadata = TrackMetadata::from_info(track.id, info);
+                let sample_rate = metadata.sample_rate;
+                // The manifest can list several qualities, and the one chosen may be below what TIDAL reports
+                let quality = match dash.representation.quality() {
+                    played if quality_rank(played) < quality_rank(quality) => played,
+                    _ => quality,
                 };
 
                 let buffer = Arc::new(HeapRb::<i32>::new(BUFFER_SIZE_SECONDS * sample_rate as usize));
@@ -127,120 +230,170 @@ impl Track {
                     metadata,
                     buffer,
                     samples_played: Arc::new(AtomicU64::new(0)),
+                    total_samples: Arc::new(AtomicU64::new(Self::estimate_total_samples(track, &metadata))),
                     track: track.clone(),
-                    stream: None,
-                    mpd: Some(mpd),
+                    quality,
+                    source: None,
+                    source_token: None,
+                    dash: Some(dash),
                     url: None,
                     progress_handle: None,
+                    stream_handle: None,
+                    stream_cancel: CancellationToken::new(),
+                    streaming_done: Arc::new(AtomicBool::new(false)),
+                    seek_control: Arc::new(SeekControl::default()),
+                    buffer_space: Arc::new(BufferSpace::default()),
+                    replay_gain,
+                    applied_gain: None,
+                    resample_to: None,
+                    impulse: None,
+                    client: client.clone(),
                 })
             },
             _ => Err(TrackError::UnsupportedManifest { manifest })?
         }
     }
 
-    pub fn start_playback(&mut self, device: &Device, event_emitter: broadcast::Sender<Event>, player_tx: mpsc::Sender<PlayerCommand>, paused: Arc<AtomicBool>) -> Result <(), TrackError> {
-        info!("Playing track (ID #{})", self.metadata.id);
+    /// Gets a new link to the track's file from TIDAL, at the quality it is being played in.
+    pub fn url_refresh(&self) -> UrlRefresh {
+        url_refresh(self.client.clone(), self.track.id, self.quality)
+    }
+
+    /// The format of the samples the track is played with, after any resampling.
+    pub fn output_metadata(&self) -> TrackMetadata {
+        TrackMetadata {
+            sample_rate: self.resample_to.unwrap_or(self.metadata.sample_rate),
+            ..self.metadata
+        }
+    }
+
+    /// Set the sample rate to resample the track to. This only takes effect the next time the track
+    /// starts streaming.
+    pub fn set_resample_to(&mut self, resample_to: Option<u32>) {
+        self.resample_to = resample_to;
+        self.total_samples.store(Self::estimate_total_samples(&self.track, &self.output_metadata()), Ordering::Relaxed);
+    }
+
+    /// Set the impulse response to convolve the track with. Like the sample rate, this only takes
+    /// effect the next time the track starts streaming.
+    pub fn set_impulse(&mut self, impulse: Option<ImpulseResponse>) {
+        self.impulse = impulse;
+    }
+
+    /// What the streaming task does to samples before they reach the ring buffer.
+    fn processing(&self) -> StreamProcessing {
+        StreamProcessing {
+            resample_to: self.resample_to,
+            impulse: self.impulse.clone(),
+        }
+    }
+
+    fn estimate_total_samples(track: &TidalTrack, metadata: &TrackMetadata) -> u64 {
+        track.duration as u64 * metadata.sample_rate as u64 * metadata.channels as u64
+    }
 
-        self.stream = None;
+    /// Whether `next` follows straight on from this track on the same album, so it should be
+    /// played gaplessly.
+    pub fn is_album_successor(&self, next: &Track) -> bool {
+        self.track.album.id == next.track.album.id && self.track.track_number + 1 == next.track.track_number
+    }
+
+    /// Start filling the ring buffer. The output reads it through `self.source`, which is taken
+    /// when the track is handed to the output.
+    pub fn start_streaming(&mut self, player_tx: mpsc::Sender<PlayerCommand>) {
+        info!("Streaming track (ID #{})", self.metadata.id);
 
         let buffer = self.buffer.clone();
-        let (producer, mut consumer) = buffer.split();
+        let (producer, consumer) = buffer.split();
+        let producer = BufferProducer::new(producer, self.buffer_space.clone());
 
         // Track when streaming is complete
-        let streaming_done = Arc::new(AtomicBool::new(false));
-        let track_finished_sent = Arc::new(AtomicBool::new(false));
+        self.streaming_done.store(false, Ordering::SeqCst);
 
         // begin filling buffer
-        Self::stream(producer, self.mpd.clone(), self.url.clone(), streaming_done.clone());
-
-        let metadata = self.metadata;
-        let supported_configs = device.supported_output_configs().context(SupportedStreamConfigsSnafu)?;
-        let supported_config = supported_configs
-            .filter(|c| c.channels() == metadata.channels)
-            .inspect(|c| trace!("inspect: {c:?}"))
-            .find(|c| c.sample_format() == Self::sample_size_to_format(metadata.sample_size))
-            .ok_or(TrackError::UnsupportedConfig { metadata })?
-            .try_with_sample_rate(metadata.sample_rate)
-            .ok_or(TrackError::UnsupportedConfig { metadata })?;
-        trace!("Using supported config: {supported_config:?}");
+        self.stream_cancel = CancellationToken::new();
+        self.stream_handle = Some(Self::stream(producer, self.dash.clone(), self.url.clone(), self.url_refresh(), self.streaming_done.clone(), self.seek_control.clone(), self.total_samples.clone(), self.processing(), self.stream_cancel.clone(), player_tx));
 
-        let samples_played = self.samples_played.clone();
-        let err_fn = |err| error!("an error occurred on the output audio stream: {}", err);
-        let stream = match metadata.sample_size {
-            16 => {
-                let streaming_done_clone = streaming_done.clone();
-                let track_finished_sent_clone = track_finished_sent.clone();
-                let player_tx_clone = player_tx.clone();
-
-                device.build_output_stream(
-                    &supported_config.config(),
-                    move |data, _| {
-                        let buffer_empty = Self::write_audio_data_16_bit(data, &mut consumer, paused.clone(), samples_played.clone());
-
-                        // If streaming is done AND buffer is empty AND we haven't sent the signal yet
-                        if buffer_empty &&
-                           streaming_done_clone.load(Ordering::Relaxed) &&
-                           !track_finished_sent_clone.swap(true, Ordering::Relaxed) {
-                            info!("Track playback complete (buffer empty and streaming done)");
-                            let _ = player_tx_clone.try_send(PlayerCommand::Skip);
-                        }
-                    },
-                    err_fn,
-                    None
-                )
-            }
-            24 => {
-                let streaming_done_clone = streaming_done.clone();
-                let track_finished_sent_clone = track_finished_sent.clone();
-                let player_tx_clone = player_tx.clone();
-
-                device.build_output_stream(
-                    &supported_config.config(),
-                    move |data, _| {
-                        let buffer_empty = Self::write_audio_data_24_bit(data, &mut consumer, paused.clone(), samples_played.clone());
-
-                        // If streaming is done AND buffer is empty AND we haven't sent the signal yet
-                        if buffer_empty &&
-                           streaming_done_clone.load(Ordering::Relaxed) &&
-                           !track_finished_sent_clone.swap(true, Ordering::Relaxed) {
-                            info!("Track playback complete (buffer empty and streaming done)");
-                            let _ = player_tx_clone.try_send(PlayerCommand::Skip);
-                        }
-                    },
-                    err_fn,
-                    None
-                )
-            }
-            _ => return Err(TrackError::UnsupportedSampleSize { sample_size: metadata.sample_size })
-        }
-        .unwrap();
+        let source = Source::new(consumer, self.samples_played.clone(), self.total_samples.clone(), self.streaming_done.clone(), self.seek_control.clone(), self.buffer_space.clone(), self.gain());
+        self.source_token = Some(source.token);
+        self.source = Some(source);
+    }
+
+    /// Linear gain to play the track at.
+    pub fn gain(&self) -> f64 {
+        self.applied_gain.map_or(1.0, db_to_gain)
+    }
 
-        trace!("Made stream");
+    /// Set the ReplayGain to apply, in dB. This only reaches a source the track still holds; the
+    /// player updates sources that have been handed to the output.
+    pub fn set_applied_gain(&mut self, gain_db: Option<f64>) {
+        self.applied_gain = gain_db;
+        let gain = self.gain();
 
-        stream.play().unwrap();
+        if let Some(source) = self.source.as_mut() {
+            source.set_gain(gain);
+        }
+    }
 
-        trace!("Playing stream");
+    /// Details about the track for the now-playing display.
+    pub fn now_playing(&self) -> NowPlaying {
+        NowPlaying {
+            track: self.track.clone(),
+            replay_gain: self.applied_gain,
+            resampled_to: self.resample_to,
+            quality: self.quality,
+        }
+    }
 
+    pub fn is_streaming(&self) -> bool {
+        self.stream_handle.is_some()
+    }
+
+    /// Start reporting playback progress, once the track is playing.
+    pub fn start_progress(&mut self, event_emitter: broadcast::Sender<Event>) {
+        let metadata = self.output_metadata();
         let samples_played = self.samples_played.clone();
 
         self.progress_handle = Some(tokio::spawn(async move {
             loop {
                 let samples_played = samples_played.load(Ordering::SeqCst);
                 let progress = samples_played / metadata.channels as u64 / metadata.sample_rate as u64;
-                event_emitter.send(Event::PlayerEvent(PlayerEvent::UpdatedTrackProgress(progress as u32)));
+                let _ = event_emitter.send(Event::PlayerEvent(PlayerEvent::UpdatedTrackProgress(progress as u32)));
                 sleep(Duration::from_millis(100)).await;
             }
         }));
+    }
+
+    /// Seek within the track. The streaming task moves to the new position, and the ring buffer is
+    /// flushed once it gets there.
+    pub fn seek(&mut self, position: Duration, player_tx: mpsc::Sender<PlayerCommand>) {
+        let position = position.min(Duration::from_secs(self.track.duration as u64));
+        info!("Seeking track (ID #{}) to {position:?}", self.metadata.id);
 
-        self.stream = Some(stream);
+        self.seek_control.request(position);
+        // The streaming task may be waiting for room in the buffer
+        self.buffer_space.wake();
 
-        Ok(())
+        // The streaming task exits once the whole track is buffered, so restart it to handle the seek
+        if self.stream_handle.as_ref().is_some_and(|handle| handle.is_finished()) {
+            trace!("Restarting finished streaming task");
+            self.streaming_done.store(false, Ordering::SeqCst);
+
+            let producer = BufferProducer::new(CachingProd::new(self.buffer.clone()), self.buffer_space.clone());
+            self.stream_cancel = CancellationToken::new();
+            self.stream_handle = Some(Self::stream(producer, self.dash.clone(), self.url.clone(), self.url_refresh(), self.streaming_done.clone(), self.seek_control.clone(), self.total_samples.clone(), self.processing(), self.stream_cancel.clone(), player_tx));
+        }
     }
 
     pub fn stop_track(&mut self) {
         let buffer = Arc::new(HeapRb::<i32>::new(BUFFER_SIZE_SECONDS * self.metadata.sample_rate as usize));
 
         self.buffer = buffer;
+        self.seek_control = Arc::new(SeekControl::default());
+
+        // The task winds down on its own, as aborting it wouldn't stop a blocking decode
+        self.stream_cancel.cancel();
+        self.stream_handle = None;
 
         if let Some(handle) = &self.progress_handle {
             handle.abort();
@@ -248,112 +401,52 @@ impl Track {
             self.samples_played.store(0, Ordering::SeqCst);
         }
 
-        self.stream = None;
+        self.source = None;
+        self.source_token = None;
     }
 
-    #[instrument(skip(producer, streaming_done))]
+    #[allow(clippy::too_many_arguments)]
+    #[instrument(skip(producer, refresh, streaming_done, seek_control, total_samples, cancel, player_tx))]
     fn stream(
-        producer: CachingProd<Arc<HeapRb<i32>>>,
-        mpd: Option<MPD>,
+        producer: BufferProducer,
+        dash: Option<DashStream>,
         url: Option<String>,
-        streaming_done: Arc<AtomicBool>
+        refresh: UrlRefresh,
+        streaming_done: Arc<AtomicBool>,
+        seek_control: Arc<SeekControl>,
+        total_samples: Arc<AtomicU64>,
+        processing: StreamProcessing,
+        cancel: CancellationToken,
+        player_tx: mpsc::Sender<PlayerCommand>,
     ) -> JoinHandle<()> {
-        if let Some(mpd) = mpd {
+        if let Some(dash) = dash {
             tokio::spawn(async move {
-                if let Err(error) = stream_dash_audio(producer, mpd).await {
+                if let Err(error) = stream_dash_audio(producer, dash, seek_control, total_samples, processing, cancel.clone()).await {
                     error!("Stream Error: {error}");
                 }
+                if cancel.is_cancelled() {
+                    return;
+                }
                 streaming_done.store(true, Ordering::SeqCst);
                 info!("Streaming complete (buffer filled)");
+                let _ = player_tx.send(PlayerCommand::PrepareNext).await;
             })
         } else if let Some(url) = url {
             tokio::spawn(async move {
-                if let Err(error) = stream_url(producer, url).await {
+                if let Err(error) = stream_url(producer, url, Some(refresh), seek_control, total_samples, processing, cancel.clone()).await {
                     error!("Stream Error: {error}");
                 }
+                if cancel.is_cancelled() {
+                    return;
+                }
                 streaming_done.store(true, Ordering::SeqCst);
                 info!("Streaming complete (buffer filled)");
+                let _ = player_tx.send(PlayerCommand::PrepareNext).await;
             })
         } else {
             unreachable!("Stream function did not recieve a URl or MPD to stream from. This is a bug.");
         }
     }
-
-    #[instrument(skip(output, consumer))]
-    fn write_audio_data_24_bit(
-        output: &mut [i32],
-        consumer: &mut CachingCons<Arc<HeapRb<i32>>>,
-        paused: Arc<AtomicBool>,
-        samples_played: Arc<AtomicU64>,
-    ) -> bool {
-        let mut i = 0;
-        let mut buffer_was_empty = false;
-
-        while i < output.len() {
-            if paused.load(Ordering::SeqCst) {
-                output[i] = i32::EQUILIBRIUM;
-                i += 1;
-                continue;
-            }
-
-            if let Some(sample) = consumer.try_pop() {
-                output[i] = sample;
-                samples_played.fetch_add(1, Ordering::Relaxed);
-                i += 1;
-            } else {
-                buffer_was_empty = true;
-                for sample in &mut output[i..] {
-                    *sample = i32::EQUILIBRIUM;
-                }
-                break;
-            }
-        }
-
-        buffer_was_empty
-    }
-
-    #[instrument(skip(output, consumer))]
-    fn write_audio_data_16_bit(
-        output: &mut [i16],
-        consumer: &mut CachingCons<Arc<HeapRb<i32>>>,
-        paused: Arc<AtomicBool>,
-        samples_played: Arc<AtomicU64>,
-    ) -> bool {
-        let mut i = 0;
-        let mut buffer_was_empty = false;
-
-        while i < output.len() {
-            if paused.load(Ordering::Relaxed) {
-                output[i] = i16::EQUILIBRIUM;
-                i += 1;
-                continue;
-            }
-
-            if let Some(sample) = consumer.try_pop() {
-                output[i] = sample.to_sample();
-                samples_played.fetch_add(1, Ordering::Relaxed);
-                i += 1;
-            } else {
-                warn!("Buffer empty!");
-                buffer_was_empty = true;
-                for sample in &mut output[i..] {
-                    *sample = i16::EQUILIBRIUM;
-                }
-                break;
-            }
-        }
-
-        buffer_was_empty
-    }
-    
-    // TODO: I need to find out more about what sample format to use... does it really matter?
-    fn sample_size_to_format(sample_size: u32) -> SampleFormat {
-        match sample_size {
-            16 => SampleFormat::I16,
-            24 => SampleFormat::I24,
-            _ => SampleFormat::I32,
-        }
-    }
 }
 
 #[derive(Debug, Snafu)]
@@ -375,20 +468,16 @@ pub enum TrackError {
         source: serde_json::Error,
     },
     #[snafu(display("Failed to parse MPD manifest"))]
-    MPD {
+    Mpd {
         source: dash_mpd::DashMpdError,
     },
-    #[snafu(display("Error getting supported output configs"))]
-    SupportedStreamConfigs {
-        source: cpal::SupportedStreamConfigsError,
-    },
-    #[snafu(display("Device does not support playing track: {metadata:?}"))]
-    UnsupportedConfig {
-        metadata: TrackMetadata,
+    #[snafu(display("Failed to choose a stream from the MPD manifest"))]
+    Dash {
+        source: DashError,
     },
-    #[snafu(display("TidePerfect cannot play track with sample size: {sample_size}"))]
-    UnsupportedSampleSize {
-        sample_size: u32,
+    #[snafu(display("Failed to read the stream format: {message}"))]
+    StreamFormat {
+        message: String,
     },
     #[snafu(display("Unsupported manifest type: {manifest}"))]
     UnsupportedManifest {
@@ -0,0 +1,179 @@
+use std::{future::Future, sync::{atomic::AtomicU64, Arc}, time::Duration};
+
+use ringbuf::{traits::{Consumer, Observer, Split}, HeapRb};
+use serde::{Deserialize, Serialize};
+use snafu::Snafu;
+use tokio::time::{sleep_until, Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, instrument};
+
+use crate::{audio::{output::SOURCE_SCALE, replay_gain::db_to_gain, stream::{stream_dash_audio, stream_url, BufferProducer, BufferSpace, SeekControl, StreamProcessing}, track::Track}, utils::persistence::PersistenceContext};
+
+/// Peaks are measured over blocks of this many milliseconds, and combined into as many buckets as
+/// are asked for.
+const BLOCK_MS: u32 = 20;
+
+/// Seconds of samples buffered between the streaming task and the peak measurement.
+const BUFFER_SECONDS: usize = 5;
+
+/// How many times faster than real time a track is decoded, so decoding a waveform doesn't take
+/// CPU time and bandwidth away from playback.
+const MAX_SPEED: f64 = 20.0;
+
+/// Peaks below this level in dBFS count as silence at the start and end of a track.
+const SILENCE_THRESHOLD_DB: f64 = -60.0;
+
+/// The lowest and highest sample in a stretch of a track, across every channel, where 1.0 is full
+/// scale.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct WaveformBucket {
+    pub min: f32,
+    pub max: f32,
+}
+
+impl WaveformBucket {
+    fn merge(self, other: Self) -> Self {
+        Self {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+        }
+    }
+
+    fn is_silent(&self) -> bool {
+        (self.min.abs().max(self.max.abs()) as f64) < db_to_gain(SILENCE_THRESHOLD_DB)
+    }
+}
+
+/// The peaks of a whole track, as cached on disk.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Waveform {
+    /// Peaks of each block of `BLOCK_MS`.
+    blocks: Vec<WaveformBucket>,
+}
+
+impl PersistenceContext for Waveform {}
+
+impl Waveform {
+    /// Decode `track` and measure its peaks. The track is streamed on its own, separately from
+    /// playback, at its own sample rate, and no faster than `MAX_SPEED` times real time.
+    #[instrument(err)]
+    pub async fn decode(track: Track) -> Result<Self, WaveformError> {
+        let channels = track.metadata.channels as usize;
+        let block_len = (track.metadata.sample_rate * BLOCK_MS / 1000) as usize * channels;
+
+        let buffer = Arc::new(HeapRb::<i32>::new(BUFFER_SECONDS * track.metadata.sample_rate as usize * channels));
+        let (producer, mut consumer) = buffer.split();
+        let space = Arc::new(BufferSpace::default());
+        let producer = BufferProducer::new(producer, space.clone());
+        let seek_control = Arc::new(SeekControl::default());
+        let total_samples = Arc::new(AtomicU64::new(0));
+        // Stops the stream if decoding is abandoned
+        let cancel = CancellationToken::new();
+        let _cancel_on_drop = cancel.clone().drop_guard();
+
+        let stream = match (&track.dash, &track.url) {
+            (Some(dash), _) => tokio::spawn(Self::stream(stream_dash_audio(producer, dash.clone(), seek_control, total_samples, StreamProcessing::default(), cancel), space.clone())),
+            (None, Some(url)) => tokio::spawn(Self::stream(stream_url(producer, url.clone(), Some(track.url_refresh()), seek_control, total_samples, StreamProcessing::default(), cancel), space.clone())),
+            (None, None) => return Err(WaveformError::NoStream),
+        };
+        let samples_per_second = track.metadata.sample_rate as f64 * channels as f64 * MAX_SPEED;
+        let started = Instant::now();
+        let mut samples_read = 0;
+
+        let mut blocks = Vec::new();
+        let mut block = WaveformBucket::default();
+        let mut block_filled = 0;
+        let mut samples = vec![0; block_len.max(1) * 64];
+
+        loop {
+            // Checked before reading, so everything pushed before the task finished is read
+            let finished = stream.is_finished();
+            let read = consumer.pop_slice(&mut samples);
+            samples_read += read;
+            space.freed(consumer.vacant_len());
+
+            for sample in &samples[..read] {
+                let sample = (*sample as f64 / SOURCE_SCALE) as f32;
+                block = block.merge(WaveformBucket { min: sample, max: sample });
+                block_filled += 1;
+
+                if block_filled == block_len {
+                    blocks.push(block);
+                    block = WaveformBucket::default();
+                    block_filled = 0;
+                }
+            }
+
+            if read == 0 {
+                if finished {
+                    break;
+                }
+                space.pushed().await;
+            }
+
+            // Holding back reads holds back the streaming task, once the buffer fills
+            sleep_until(started + Duration::from_secs_f64(samples_read as f64 / samples_per_second)).await;
+        }
+
+        if block_filled > 0 {
+            blocks.push(block);
+        }
+
+        stream.await
+            .map_err(|e| e.to_string())
+            .and_then(|result| result)
+            .map_err(|message| WaveformError::Stream { message })?;
+
+        info!("Measured {} blocks of peaks for track #{}", blocks.len(), track.metadata.id);
+
+        Ok(Self { blocks })
+    }
+
+    /// Run `stream`, waking the reader once it ends so it sees there's nothing more to read.
+    async fn stream(stream: impl Future<Output = Result<(), String>>, space: Arc<BufferSpace>) -> Result<(), String> {
+        let result = stream.await;
+        space.finished();
+        result
+    }
+
+    /// Combine the peaks into `count` buckets of equal length.
+    pub fn buckets(&self, count: usize) -> Vec<WaveformBucket> {
+        let len = self.blocks.len();
+        if len == 0 {
+            return vec![WaveformBucket::default(); count];
+        }
+
+        (0..count)
+            .map(|bucket| {
+                let start = (bucket * len / count).min(len - 1);
+                let end = ((bucket + 1) * len / count).clamp(start + 1, len);
+
+                self.blocks[start..end].iter().copied().reduce(WaveformBucket::merge).unwrap_or_default()
+            })
+            .collect()
+    }
+
+    /// How long the track is silent for before it starts.
+    pub fn leading_silence(&self) -> Duration {
+        Self::blocks_duration(self.blocks.iter().take_while(|block| block.is_silent()).count())
+    }
+
+    /// How long the track is silent for after it ends.
+    pub fn trailing_silence(&self) -> Duration {
+        Self::blocks_duration(self.blocks.iter().rev().take_while(|block| block.is_silent()).count())
+    }
+
+    fn blocks_duration(blocks: usize) -> Duration {
+        Duration::from_millis(blocks as u64 * BLOCK_MS as u64)
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum WaveformError {
+    #[snafu(display("Track has no stream to decode"))]
+    NoStream,
+    #[snafu(display("Failed to decode track: {message}"))]
+    Stream {
+        message: String,
+    },
+}
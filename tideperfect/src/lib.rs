@@ -2,9 +2,9 @@ use std::{env, path::Path, sync::Arc};
 
 use snafu::{ResultExt, Snafu};
 use strum_macros::EnumDiscriminants;
-use tokio::sync::broadcast;
+use tokio::sync::{broadcast, Mutex};
 
-use crate::{audio::{player::PlayerEvent, queue::QueueEvent}, services::{album::AlbumService, auth::{AuthEvent, AuthService, AuthServiceError},
+use crate::{audio::{player::PlayerEvent, queue::QueueEvent, settings::QualitySettings}, services::{album::AlbumService, auth::{AuthEvent, AuthService, AuthServiceError},
         player::{PlayerService, PlayerServiceError}, queue::QueueService, track::TrackService}, utils::persistence::{PersistanceError, Persistence}};
 
 use dotenvy::dotenv;
@@ -38,11 +38,12 @@ impl TidePerfect {
         let persistence = Arc::new(Persistence::new(data_dir).context(PersistenceSnafu)?);
         
         let (auth_service, tidal_client) = AuthService::init(persistence.clone(), event_emitter.clone(), &client_id, &client_secret);
-        let (queue_service, queue) = QueueService::init(tidal_client.clone(), event_emitter.clone());
+        let quality = Arc::new(Mutex::new(persistence.load::<QualitySettings>().unwrap_or_default()));
+        let (queue_service, queue) = QueueService::init(tidal_client.clone(), quality.clone(), event_emitter.clone());
 
         let album_service = AlbumService::new(tidal_client.clone());
-        let player_service = PlayerService::init_default_output(queue.clone(), event_emitter.clone()).context(PlayerServiceSnafu)?;
-        let track_service = TrackService::new(tidal_client.clone());
+        let player_service = PlayerService::init_default_output(queue.clone(), persistence.clone(), event_emitter.clone()).context(PlayerServiceSnafu)?;
+        let track_service = TrackService::new(tidal_client.clone(), persistence.clone(), quality);
 
         Ok(Self {
             auth_service,
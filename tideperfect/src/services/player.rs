@@ -1,28 +1,49 @@
-use std::sync::Arc;
+use std::{collections::{BTreeMap, HashMap}, path::Path, sync::Arc, time::Duration};
 
 use cpal::{default_host, traits::{DeviceTrait, HostTrait}};
-use snafu::Snafu;
+use snafu::{Report, ResultExt, Snafu};
 use tokio::sync::{broadcast, mpsc, oneshot, Mutex};
-use tracing::{instrument, trace};
+use tracing::{instrument, trace, warn};
 
-use crate::{audio::{player::{player_loop, PlayerCommand}, queue::Queue, track::Track}, Event};
+use crate::{audio::{analysis::AnalysisTap, output::{exclusive_device, exclusive_devices, OutputError, OutputStats}, player::{player_loop, PlayerCommand}, queue::Queue, settings::PlayerSettings, track::Track}, utils::persistence::Persistence, Event};
 
-pub use crate::audio::player::{PlayerEvent, PlayerEventDiscriminants, CommandDevice};
+pub use crate::audio::{output::OutputFormat, player::{PlayerEvent, PlayerEventDiscriminants, PlayerStats, CommandDevice, NowPlaying}};
+pub use crate::audio::dsp::{DspSettings, DspStageConfig, DspStageSettings, convolution::{ConvolutionError, ImpulseResponse}, crossfeed::{CrossfeedSettings, MAX_CROSSFEED_CUTOFF_HZ, MAX_CROSSFEED_FEED_DB, MIN_CROSSFEED_CUTOFF_HZ, MIN_CROSSFEED_FEED_DB}, eq::{EqBand, EqFilter, EqParseError, EqPreset}, gain::GainSettings, limiter::LimiterSettings};
+pub use crate::audio::{analysis::{ChannelLevel, SPECTRUM_BANDS, SPECTRUM_FLOOR_DB}, channels::ChannelSettings};
+pub use crate::audio::settings::{CrossfadeSettings, CrossfadeCurve, ReplayGainMode, MAX_CROSSFADE_MS};
 
 pub struct PlayerService {
     control_tx: mpsc::Sender<PlayerCommand>,
     pub queue: Arc<Mutex<Queue>>,
     pub played: Arc<Mutex<Vec<Track>>>,
+    stats: Arc<OutputStats>,
+    tap: Arc<AnalysisTap>,
 }
 
 impl PlayerService {
     #[instrument(skip_all, err)]
-    pub fn init_default_output(queue: Arc<Mutex<Queue>>, event_emitter: broadcast::Sender<Event>) -> Result<Self, PlayerServiceError> {
+    pub fn init_default_output(queue: Arc<Mutex<Queue>>, persistence: Arc<Persistence>, event_emitter: broadcast::Sender<Event>) -> Result<Self, PlayerServiceError> {
         let host = default_host();
-        let device = host.default_output_device().ok_or(PlayerServiceError::NoDefaultDevice)?;
+
+        let mut settings = persistence.load::<PlayerSettings>().unwrap_or_default();
+        trace!("Loaded player settings: {settings:?}");
+
+        let exclusive = settings.exclusive_device.as_deref().map(exclusive_device);
+        let device = match exclusive {
+            Some(Ok(device)) => device,
+            Some(Err(e)) => {
+                warn!("Exclusive device is unavailable, using the default device: {}", Report::from_error(e));
+                settings.exclusive_device = None;
+                host.default_output_device().ok_or(PlayerServiceError::NoDefaultDevice)?
+            }
+            None => host.default_output_device().ok_or(PlayerServiceError::NoDefaultDevice)?,
+        };
         trace!("Using device: {:?}", device.description());
 
         let played = Arc::new(Mutex::new(Vec::new()));
+        let stats = Arc::new(OutputStats::default());
+        let (tap, analyser) = AnalysisTap::new();
+        tokio::spawn(analyser.run(event_emitter.clone()));
         let (control_tx, control_rx) = mpsc::channel(32);
 
         tokio::spawn(player_loop(
@@ -32,13 +53,19 @@ impl PlayerService {
                 host, 
                 device.clone(),
                 queue.clone(),
-                played.clone()
+                played.clone(),
+                persistence,
+                settings,
+                stats.clone(),
+                tap.clone(),
         ));
 
         Ok(Self {
             control_tx,
             queue,
             played,
+            stats,
+            tap,
         })
     }
 
@@ -66,6 +93,12 @@ impl PlayerService {
         Ok(())
     }
 
+    pub async fn seek(&self, position: Duration) -> Result<(), PlayerServiceError> {
+        self.control_tx.send(PlayerCommand::Seek(position)).await
+            .map_err(|_| PlayerServiceError::BackgroundThreadDied)?;
+        Ok(())
+    }
+
     pub async fn devices(&self) -> Result<Vec<CommandDevice>, PlayerServiceError> {
         let (tx, rx) = oneshot::channel();
 
@@ -81,6 +114,220 @@ impl PlayerService {
 
         Ok(())
     }
+
+    async fn settings(&self) -> Result<PlayerSettings, PlayerServiceError> {
+        let (tx, rx) = oneshot::channel();
+
+        self.control_tx.send(PlayerCommand::GetSettings(tx)).await
+            .map_err(|_| PlayerServiceError::BackgroundThreadDied)?;
+
+        rx.await.map_err(|_| PlayerServiceError::BackgroundThreadDied)
+    }
+
+    pub async fn crossfade(&self) -> Result<CrossfadeSettings, PlayerServiceError> {
+        Ok(self.settings().await?.crossfade)
+    }
+
+    /// Set the crossfade between tracks. Tracks that follow on from each other on the same album
+    /// are never crossfaded.
+    pub async fn set_crossfade(&self, crossfade: CrossfadeSettings) -> Result<(), PlayerServiceError> {
+        self.control_tx.send(PlayerCommand::SetCrossfade(crossfade)).await
+            .map_err(|_| PlayerServiceError::BackgroundThreadDied)?;
+
+        Ok(())
+    }
+
+    pub async fn volume(&self) -> Result<f64, PlayerServiceError> {
+        Ok(self.settings().await?.volume)
+    }
+
+    /// Set the volume, between 0 and 1. Has no effect on the output while bit-perfect playback is
+    /// enabled.
+    pub async fn set_volume(&self, volume: f64) -> Result<(), PlayerServiceError> {
+        self.control_tx.send(PlayerCommand::SetVolume(volume)).await
+            .map_err(|_| PlayerServiceError::BackgroundThreadDied)?;
+
+        Ok(())
+    }
+
+    pub async fn bit_perfect(&self) -> Result<bool, PlayerServiceError> {
+        Ok(self.settings().await?.bit_perfect)
+    }
+
+    /// Enable or disable bit-perfect playback, which bypasses volume, ReplayGain and the DSP chain
+    /// completely.
+    pub async fn set_bit_perfect(&self, bit_perfect: bool) -> Result<(), PlayerServiceError> {
+        self.control_tx.send(PlayerCommand::SetBitPerfect(bit_perfect)).await
+            .map_err(|_| PlayerServiceError::BackgroundThreadDied)?;
+
+        Ok(())
+    }
+
+    pub async fn replay_gain(&self) -> Result<ReplayGainMode, PlayerServiceError> {
+        Ok(self.settings().await?.replay_gain)
+    }
+
+    /// Set which ReplayGain values are used to normalise loudness. The gain applied to the current
+    /// track is reported in `PlayerEvent::UpdatedCurrentTrack`.
+    pub async fn set_replay_gain(&self, mode: ReplayGainMode) -> Result<(), PlayerServiceError> {
+        self.control_tx.send(PlayerCommand::SetReplayGain(mode)).await
+            .map_err(|_| PlayerServiceError::BackgroundThreadDied)?;
+
+        Ok(())
+    }
+
+    pub async fn exclusive_device(&self) -> Result<Option<String>, PlayerServiceError> {
+        Ok(self.settings().await?.exclusive_device)
+    }
+
+    /// Play bit-perfect to an ALSA `hw:` device, given by its PCM name, with exclusive access. The
+    /// output is reopened in each track's own format, and tracks the device can't play unchanged
+    /// fail with a `PlayerEvent::PlaybackError`. `None` goes back to the default device.
+    pub async fn set_exclusive_device(&self, pcm_id: Option<String>) -> Result<(), PlayerServiceError> {
+        self.control_tx.send(PlayerCommand::SetExclusiveDevice(pcm_id)).await
+            .map_err(|_| PlayerServiceError::BackgroundThreadDied)?;
+
+        Ok(())
+    }
+
+    /// The devices that can be used with `set_exclusive_device`. The id of each is its PCM name.
+    pub fn exclusive_devices(&self) -> Result<Vec<CommandDevice>, PlayerServiceError> {
+        let devices = exclusive_devices().context(ExclusiveDevicesSnafu)?;
+
+        Ok(devices.into_iter().map(|(id, name)| CommandDevice { name, id }).collect())
+    }
+
+    pub async fn resample_to(&self) -> Result<Option<u32>, PlayerServiceError> {
+        Ok(self.settings().await?.resample_to)
+    }
+
+    /// Always resample to the given rate, or `None` to only resample when the device can't play a
+    /// track's own rate. Takes effect from the next track.
+    pub async fn set_resample_to(&self, resample_to: Option<u32>) -> Result<(), PlayerServiceError> {
+        self.control_tx.send(PlayerCommand::SetResampleTo(resample_to)).await
+            .map_err(|_| PlayerServiceError::BackgroundThreadDied)?;
+
+        Ok(())
+    }
+
+    pub async fn channels(&self) -> Result<ChannelSettings, PlayerServiceError> {
+        Ok(self.settings().await?.channels)
+    }
+
+    /// Set the balance, mono downmix and left/right swap. These are ignored during bit-perfect
+    /// playback.
+    pub async fn set_channels(&self, channels: ChannelSettings) -> Result<(), PlayerServiceError> {
+        self.control_tx.send(PlayerCommand::SetChannels(channels)).await
+            .map_err(|_| PlayerServiceError::BackgroundThreadDied)?;
+
+        Ok(())
+    }
+
+    pub async fn dsp(&self) -> Result<DspSettings, PlayerServiceError> {
+        Ok(self.settings().await?.dsp)
+    }
+
+    /// Set the stages of the DSP chain, in the order they are applied. Changes are heard straight
+    /// away. The chain is bypassed while bit-perfect playback is enabled.
+    pub async fn set_dsp(&self, dsp: DspSettings) -> Result<(), PlayerServiceError> {
+        self.control_tx.send(PlayerCommand::SetDsp(dsp)).await
+            .map_err(|_| PlayerServiceError::BackgroundThreadDied)?;
+
+        Ok(())
+    }
+
+    pub async fn eq_presets(&self) -> Result<BTreeMap<String, EqPreset>, PlayerServiceError> {
+        Ok(self.settings().await?.eq.presets)
+    }
+
+    /// Save an EQ preset, replacing any preset with the same name.
+    pub async fn save_eq_preset(&self, name: String, preset: EqPreset) -> Result<(), PlayerServiceError> {
+        self.control_tx.send(PlayerCommand::SaveEqPreset(name, preset)).await
+            .map_err(|_| PlayerServiceError::BackgroundThreadDied)?;
+
+        Ok(())
+    }
+
+    /// Save an EQ preset from the contents of an AutoEQ or EqualizerAPO `ParametricEQ.txt` file.
+    pub async fn import_eq_preset(&self, name: String, text: &str) -> Result<(), PlayerServiceError> {
+        let preset = EqPreset::parse_parametric_eq(text).context(ImportEqPresetSnafu)?;
+
+        self.save_eq_preset(name, preset).await
+    }
+
+    /// Delete an EQ preset. Devices that used it are left without EQ.
+    pub async fn delete_eq_preset(&self, name: String) -> Result<(), PlayerServiceError> {
+        self.control_tx.send(PlayerCommand::DeleteEqPreset(name)).await
+            .map_err(|_| PlayerServiceError::BackgroundThreadDied)?;
+
+        Ok(())
+    }
+
+    /// The EQ preset used with each device, by device id.
+    pub async fn device_eq_presets(&self) -> Result<HashMap<String, String>, PlayerServiceError> {
+        Ok(self.settings().await?.eq.devices)
+    }
+
+    /// Use the named EQ preset whenever the device with `device_id` is played to, or `None` to turn
+    /// EQ off for it. The preset is applied by the `Eq` stage of the DSP chain.
+    pub async fn set_device_eq_preset(&self, device_id: String, preset: Option<String>) -> Result<(), PlayerServiceError> {
+        self.control_tx.send(PlayerCommand::SetDeviceEqPreset(device_id, preset)).await
+            .map_err(|_| PlayerServiceError::BackgroundThreadDied)?;
+
+        Ok(())
+    }
+
+    /// The impulse responses used for convolution, by device id.
+    pub async fn impulse_responses(&self) -> Result<HashMap<String, Vec<ImpulseResponse>>, PlayerServiceError> {
+        Ok(self.settings().await?.convolution.devices)
+    }
+
+    /// Convolve playback on the device with `device_id` with the WAV impulse response at `path`.
+    /// Each device can have one impulse response for each sample rate, and the one for the output's
+    /// rate is used, or the closest one resampled if there isn't one. The response is applied while
+    /// tracks are decoded, when the `Convolution` stage of the DSP chain is enabled, from the next
+    /// track that starts streaming.
+    pub async fn add_impulse_response(&self, device_id: String, path: &Path) -> Result<(), PlayerServiceError> {
+        let impulse = ImpulseResponse::probe(path).context(LoadImpulseResponseSnafu)?;
+
+        self.control_tx.send(PlayerCommand::AddImpulseResponse(device_id, impulse)).await
+            .map_err(|_| PlayerServiceError::BackgroundThreadDied)?;
+
+        Ok(())
+    }
+
+    pub async fn remove_impulse_response(&self, device_id: String, sample_rate: u32) -> Result<(), PlayerServiceError> {
+        self.control_tx.send(PlayerCommand::RemoveImpulseResponse(device_id, sample_rate)).await
+            .map_err(|_| PlayerServiceError::BackgroundThreadDied)?;
+
+        Ok(())
+    }
+
+    /// How far the DSP chain delays playback, such as the latency of convolution.
+    pub async fn dsp_latency(&self) -> Result<Duration, PlayerServiceError> {
+        let (tx, rx) = oneshot::channel();
+
+        self.control_tx.send(PlayerCommand::GetDspLatency(tx)).await
+            .map_err(|_| PlayerServiceError::BackgroundThreadDied)?;
+
+        rx.await.map_err(|_| PlayerServiceError::BackgroundThreadDied)
+    }
+
+    pub fn stats(&self) -> PlayerStats {
+        PlayerStats {
+            clipped_samples: self.stats.clipped_samples(),
+        }
+    }
+
+    pub fn reset_stats(&self) {
+        self.stats.reset();
+    }
+
+    /// Start or stop emitting `PlayerEvent::Spectrum` and `PlayerEvent::Levels` for the audio
+    /// being played.
+    pub fn set_analysis(&self, enabled: bool) {
+        self.tap.set_enabled(enabled);
+    }
 }
 
 #[derive(Debug, Snafu)]
@@ -89,4 +336,16 @@ pub enum PlayerServiceError {
     NoDefaultDevice,
     #[snafu(display("Background thread died"))]
     BackgroundThreadDied,
+    #[snafu(display("Failed to list exclusive devices"))]
+    ExclusiveDevices {
+        source: OutputError,
+    },
+    #[snafu(display("Failed to import EQ preset: {source}"))]
+    ImportEqPreset {
+        source: EqParseError,
+    },
+    #[snafu(display("Failed to load impulse response: {source}"))]
+    LoadImpulseResponse {
+        source: ConvolutionError,
+    },
 }
@@ -5,24 +5,26 @@ use tidalrs::TidalClient;
 use tokio::sync::{broadcast, Mutex};
 use tracing::{instrument, trace};
 
-use crate::{audio::{queue::{Queue, QueueError}, track::{Track, TrackError}}, Event};
+use crate::{audio::{queue::{Queue, QueueError}, settings::QualitySettings, track::{Track, TrackError}}, Event};
 
 pub use crate::audio::queue::{QueueEvent, QueueEventDiscriminants};
 
 pub struct QueueService {
     tidal_client: Arc<TidalClient>,
     queue: Arc<Mutex<Queue>>,
+    quality: Arc<Mutex<QualitySettings>>,
 }
 
 impl QueueService {
-    #[instrument(skip(tidal_client))]
-    pub fn init(tidal_client: Arc<TidalClient>, event_emitter: broadcast::Sender<Event>) -> (Self, Arc<Mutex<Queue>>) {
+    #[instrument(skip(tidal_client, quality))]
+    pub fn init(tidal_client: Arc<TidalClient>, quality: Arc<Mutex<QualitySettings>>, event_emitter: broadcast::Sender<Event>) -> (Self, Arc<Mutex<Queue>>) {
         trace!("Initialising QueueService");
         let queue = Arc::new(Mutex::new(Queue::new(event_emitter)));
         (
             Self { 
                 tidal_client,
                 queue: queue.clone(),
+                quality,
             },
             queue.clone(),
         )
@@ -31,7 +33,8 @@ impl QueueService {
     #[instrument(skip(self))]
     pub async fn queue_track(&self, id: u64) -> Result<(), QueueServiceError> {
         trace!("Queueing track #{id}");
-        let track = Track::fetch(&self.tidal_client, id).await.context(FetchTrackSnafu { id })?;
+        let quality = self.quality.lock().await.streaming;
+        let track = Track::fetch(&self.tidal_client, id, quality).await.context(FetchTrackSnafu { id })?;
         self.queue.lock().await.add(track).context(AddTrackSnafu { id })?;
 
         Ok(())
@@ -41,9 +44,21 @@ impl QueueService {
     pub async fn queue_album(&self, id: u64) -> Result<(), QueueServiceError> {
         trace!("Queueing album #{id}");
         let album = self.tidal_client.album_tracks(id, None, None).await.context(FetchAlbumTracksSnafu { id })?;
-        for track in album.items {
-            let track = Track::fetch_from_track(&self.tidal_client, &track).await
-                .context(FetchTrackSnafu { id: track.id })?;
+        let quality = self.quality.lock().await.streaming;
+
+        // Each track's stream is probed as it's fetched, so fetch them all at once and queue them in order
+        let fetches: Vec<_> = album.items.into_iter()
+            .map(|track| {
+                let tidal_client = self.tidal_client.clone();
+                tokio::spawn(async move {
+                    Track::fetch_from_track(&tidal_client, &track, quality).await
+                        .context(FetchTrackSnafu { id: track.id })
+                })
+            })
+            .collect();
+
+        for fetch in fetches {
+            let track = fetch.await.context(FetchTaskSnafu { id })??;
             self.queue.lock().await.add(track).context(AddTrackSnafu { id })?;
         }
 
@@ -63,6 +78,11 @@ pub enum QueueServiceError {
         id: u64,
         source: tidalrs::Error,
     },
+    #[snafu(display("Failed to fetch a track of album: {id}"))]
+    FetchTask {
+        id: u64,
+        source: tokio::task::JoinError,
+    },
     #[snafu(display("Failed to add #{id} to queue"))]
     AddTrack {
         id: u64,
@@ -1,23 +1,56 @@
-use std::sync::Arc;
+use std::{future::Future, sync::Arc, time::Duration};
 
-use snafu::{ResultExt, Snafu};
+use snafu::{Report, ResultExt, Snafu};
 use tidalrs::{TidalApiError, TidalClient};
+use tokio::sync::Mutex;
 
 pub use tidalrs::{Track, MediaMetadata, AudioQuality};
-use tracing::instrument;
+pub use crate::audio::{settings::QualitySettings, waveform::{WaveformBucket, WaveformError}};
+use tracing::{info, instrument, warn};
+
+use crate::{audio::{track::{Track as PlayableTrack, TrackError}, waveform::Waveform}, utils::persistence::{PersistanceError, Persistence}};
 
 pub struct TrackService {
     tidal_client: Arc<TidalClient>,
+    persistence: Arc<Persistence>,
+    quality: Arc<Mutex<QualitySettings>>,
+    /// Held while a waveform is being decoded, so only one is decoded at a time alongside playback.
+    waveform_lock: Arc<Mutex<()>>,
+}
+
+/// An overview of a track's waveform, for drawing a seek bar.
+#[derive(Debug, Clone)]
+pub struct TrackWaveform {
+    pub buckets: Vec<WaveformBucket>,
+    /// How long the track is silent for before it starts.
+    pub leading_silence: Duration,
+    /// How long the track is silent for after it ends.
+    pub trailing_silence: Duration,
 }
 
 impl TrackService {
-    #[instrument(skip(tidal_client))]
-    pub fn new(tidal_client: Arc<TidalClient>) -> Self {
+    #[instrument(skip(tidal_client, persistence, quality))]
+    pub fn new(tidal_client: Arc<TidalClient>, persistence: Arc<Persistence>, quality: Arc<Mutex<QualitySettings>>) -> Self {
         Self {
-            tidal_client
+            tidal_client,
+            persistence,
+            quality,
+            waveform_lock: Arc::new(Mutex::new(())),
         }
     }
 
+    pub async fn quality(&self) -> QualitySettings {
+        *self.quality.lock().await
+    }
+
+    /// Set the quality tracks are requested in. Tracks that have already been queued keep the
+    /// quality they were fetched in.
+    #[instrument(skip(self), err)]
+    pub async fn set_quality(&self, quality: QualitySettings) -> Result<(), TrackServiceError> {
+        *self.quality.lock().await = quality;
+        self.persistence.store(&quality).context(PersistenceSnafu)
+    }
+
     /// Get the lyrics for a track
     #[instrument(skip(self), err)]
     pub async fn lyrics(&self, id: u64) -> Result<Option<String>, TrackServiceError> {
@@ -35,6 +68,59 @@ impl TrackService {
         let lyrics = resp.context(LyricsSnafu { id })?.lyrics;
         Ok(Some(lyrics))
     }
+
+    /// Get the waveform of a track, with its peaks split into `buckets`. Waveforms are cached by
+    /// track and quality, and otherwise decoded by streaming the whole track.
+    ///
+    /// Decoding can take a while, so the returned future doesn't borrow the service.
+    pub fn waveform(&self, id: u64, buckets: usize) -> impl Future<Output = Result<TrackWaveform, TrackServiceError>> + use<> {
+        waveform(self.tidal_client.clone(), self.persistence.clone(), self.quality.clone(), self.waveform_lock.clone(), id, buckets)
+    }
+}
+
+#[instrument(skip(tidal_client, persistence, quality, lock), err)]
+async fn waveform(
+    tidal_client: Arc<TidalClient>,
+    persistence: Arc<Persistence>,
+    quality: Arc<Mutex<QualitySettings>>,
+    lock: Arc<Mutex<()>>,
+    id: u64,
+    buckets: usize,
+) -> Result<TrackWaveform, TrackServiceError> {
+    // Cached by the quality the track is served in. That's usually the one asked for, so look
+    // there before asking TIDAL which it is
+    let requested = quality.lock().await.streaming;
+    let waveform = match persistence.load_keyed::<Waveform>(&waveform_key(id, requested)) {
+        Ok(waveform) => waveform,
+        Err(_) => {
+            let track = PlayableTrack::fetch(&tidal_client, id, requested).await.context(FetchSnafu { id })?;
+            let key = waveform_key(id, track.quality);
+            let _decoding = lock.lock().await;
+
+            // Another request may have decoded it while this one waited
+            match persistence.load_keyed::<Waveform>(&key) {
+                Ok(waveform) => waveform,
+                Err(_) => {
+                    info!("Decoding waveform for track #{id}");
+                    let waveform = Waveform::decode(track).await.context(WaveformSnafu { id })?;
+                    if let Err(e) = persistence.store_keyed(&key, &waveform) {
+                        warn!("Failed to cache waveform: {}", Report::from_error(e));
+                    }
+                    waveform
+                }
+            }
+        }
+    };
+
+    Ok(TrackWaveform {
+        buckets: waveform.buckets(buckets),
+        leading_silence: waveform.leading_silence(),
+        trailing_silence: waveform.trailing_silence(),
+    })
+}
+
+fn waveform_key(id: u64, quality: AudioQuality) -> String {
+    format!("{id}-{quality:?}")
 }
 
 #[derive(Debug, Snafu)]
@@ -44,4 +130,18 @@ pub enum  TrackServiceError {
         source: tidalrs::Error,
         id: u64,
     },
+    #[snafu(display("Could not fetch track #{id}"))]
+    Fetch {
+        source: TrackError,
+        id: u64,
+    },
+    #[snafu(display("Could not get the waveform of track #{id}: {source}"))]
+    Waveform {
+        source: WaveformError,
+        id: u64,
+    },
+    #[snafu(display("Could not save the quality settings"))]
+    Persistence {
+        source: PersistanceError,
+    },
 }
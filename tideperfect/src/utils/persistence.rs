@@ -42,6 +42,11 @@ impl Persistence {
         self.data_dir.join(format!("{}{}", T::identifier(), FILE_EXTENSION))
     }
 
+    #[instrument]
+    fn get_keyed_path<T: PersistenceContext>(&self, key: &str) -> PathBuf {
+        self.data_dir.join(T::identifier()).join(format!("{key}{FILE_EXTENSION}"))
+    }
+
     /// Stores the data. Overwrites existing data.
     #[instrument(err)]
     pub fn store<T: PersistenceContext>(&self, data: &T) -> Result<(), PersistanceError> {
@@ -70,6 +75,39 @@ impl Persistence {
 
         serde_json::from_reader(reader).context(DeserializeSnafu { data_type: type_name::<T>(), path: path.to_path_buf() })
     }
+
+    /// Stores one of many values of a type, identified by `key`. Overwrites existing data with the
+    /// same key.
+    #[instrument(skip(data), err)]
+    pub fn store_keyed<T: PersistenceContext>(&self, key: &str, data: &T) -> Result<(), PersistanceError> {
+        let path = self.get_keyed_path::<T>(key);
+
+        trace!("Writing data to {}", path.display());
+
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).context(WriteSnafu { path: dir.to_path_buf() })?;
+        }
+        let writer = std::fs::File::create(&path).context(WriteSnafu { path: path.to_path_buf() })?;
+
+        serde_json::to_writer(writer, data).context(SerializeSnafu { data_type: type_name::<T>() })
+    }
+
+    /// Load a value stored with `store_keyed`. A missing value isn't logged as an error, as callers
+    /// use this as a cache.
+    #[instrument]
+    pub fn load_keyed<T: PersistenceContext>(&self, key: &str) -> Result<T, PersistanceError> {
+        let path = self.get_keyed_path::<T>(key);
+
+        trace!("Loading data from {}", path.display());
+
+        if !path.exists() {
+            return Err(PersistanceError::FileDoesNotExist { path: path.to_path_buf() })
+        }
+
+        let reader = std::fs::File::open(&path).context(ReadSnafu { path: path.to_path_buf() })?;
+
+        serde_json::from_reader(reader).context(DeserializeSnafu { data_type: type_name::<T>(), path: path.to_path_buf() })
+    }
 }
 
 #[derive(Debug, Snafu)]